@@ -1,3 +1,4 @@
+import axios from 'axios'
 import { Dictionary } from "vue-router/types/router";
 
 export function formatDate (date: Date) {
@@ -12,6 +13,9 @@ export const useJsonHeader = {
 
 export class Item {
   public inDb: boolean = false
+  // Set when saving failed because the name does not belong to any employee
+  public unknownName: string | null = null
+  public suggestions: string[] = []
   public modified: Dictionary<boolean> = {
     'name': false,
     'date': false,
@@ -51,4 +55,36 @@ export class Report {
   was_pdf_generated: boolean = false
 
   constructor(public id: number, public title: string, public start_date: string, public end_date: string) {}
-}
\ No newline at end of file
+}
+
+/**
+ * Generate the PDF of a report in the background and show it once it is done.
+ *
+ * The window is opened right away, as browsers block windows that are not opened in response
+ * to a click.
+ */
+export function generatePdf (id: number, title: string): Promise<void> {
+  let pdfWindow = window.open('', '_blank')
+
+  let wait = (job: any): Promise<void> => {
+    if (job.status === 'done') {
+      let link = axios.defaults.baseURL + '/pdf_jobs/' + job.id + '/pdf/' + encodeURIComponent(title) + '.pdf'
+      if (pdfWindow) {
+        pdfWindow.location.href = link
+      }
+      return Promise.resolve()
+    }
+    if (job.status === 'failed') {
+      if (pdfWindow) {
+        pdfWindow.close()
+      }
+      return Promise.reject(new Error(job.log))
+    }
+    return new Promise(resolve => setTimeout(resolve, 1000))
+      .then(() => axios.get('pdf_jobs/' + job.id))
+      .then((response: any) => wait(response.data))
+  }
+
+  return axios.post('reports/' + id + '/pdf_jobs', '', useJsonHeader)
+    .then((response: any) => wait(response.data))
+}
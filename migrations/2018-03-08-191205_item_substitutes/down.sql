@@ -0,0 +1,48 @@
+DROP VIEW items_view;
+
+CREATE TABLE items_old (
+	id integer PRIMARY KEY AUTOINCREMENT NOT NULL,
+	report_id integer NOT NULL REFERENCES reports(id),
+	employee_id integer NOT NULL REFERENCES employees(id),
+
+
+	start_datetime text NOT NULL CHECK (
+		date(start_datetime) >= '2017-08-01' AND
+		time(start_datetime) >= '11:00'
+	),
+	end_datetime text NOT NULL CHECK (
+		date(start_datetime) = date(end_datetime) AND
+		time(start_datetime) <= time(end_datetime, '- 15minutes') AND
+		time(end_datetime) <= '16:00'
+	),
+
+	remark varchar,
+	UNIQUE (employee_id, start_datetime),
+	UNIQUE (employee_id, end_datetime)
+);
+
+INSERT INTO items_old (id, report_id, employee_id, start_datetime, end_datetime, remark)
+SELECT id, report_id, employee_id, start_datetime, end_datetime, remark FROM items;
+
+DROP TABLE items;
+ALTER TABLE items_old RENAME TO items;
+
+CREATE VIEW items_view AS
+SELECT
+	items.id AS id,
+	employee_id,
+	report_id,
+	employees.name AS name,
+	name_sort,
+	date(start_datetime) AS day,
+	type_of_week,
+	time(start_datetime) AS start,
+	time(end_datetime) AS end,
+	remark
+FROM items
+JOIN employees
+JOIN weeks
+ON
+	items.employee_id = employees.id AND
+	cast(strftime('%Y', start_datetime) AS integer) = weeks.year AND
+	cast(strftime('%W', start_datetime) AS integer) = weeks.week_of_year
@@ -0,0 +1,12 @@
+CREATE TABLE absences (
+	id integer PRIMARY KEY AUTOINCREMENT NOT NULL,
+	employee_id integer NOT NULL REFERENCES employees(id),
+	start_date text NOT NULL,
+	end_date text NOT NULL CHECK (start_date <= end_date),
+	reason varchar NOT NULL CHECK (
+		reason IN ('sick', 'vacation', 'training', 'other')
+	),
+	remark varchar NOT NULL DEFAULT ''
+);
+
+ALTER TABLE reports ADD COLUMN show_absences boolean NOT NULL DEFAULT false;
@@ -0,0 +1,41 @@
+DROP TABLE hourly_rates;
+
+DROP VIEW items_view;
+
+CREATE TABLE employees_old (
+	id integer PRIMARY KEY AUTOINCREMENT NOT NULL,
+	name varchar NOT NULL UNIQUE,
+	name_sort varchar NOT NULL UNIQUE
+);
+
+INSERT INTO employees_old (id, name, name_sort)
+SELECT id, name, name_sort FROM employees;
+
+DROP TABLE employees;
+ALTER TABLE employees_old RENAME TO employees;
+
+CREATE VIEW items_view AS
+SELECT
+	items.id AS id,
+	items.employee_id AS employee_id,
+	report_id,
+	employees.name AS name,
+	employees.name_sort AS name_sort,
+	date(start_datetime) AS day,
+	type_of_week,
+	time(start_datetime) AS start,
+	time(end_datetime) AS end,
+	remark,
+	substitutes_employee_id,
+	substitutes.name AS substitutes_name
+FROM items
+JOIN employees
+ON
+	items.employee_id = employees.id
+JOIN weeks
+ON
+	cast(strftime('%Y', start_datetime) AS integer) = weeks.year AND
+	cast(strftime('%W', start_datetime) AS integer) = weeks.week_of_year
+LEFT JOIN employees AS substitutes
+ON
+	items.substitutes_employee_id = substitutes.id;
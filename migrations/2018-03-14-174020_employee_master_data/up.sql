@@ -0,0 +1,15 @@
+ALTER TABLE employees ADD COLUMN contract_type varchar CHECK (
+	contract_type IN ('minijob', 'uebungsleiter', 'part_time')
+);
+ALTER TABLE employees ADD COLUMN employment_start text;
+ALTER TABLE employees ADD COLUMN employment_end text CHECK (
+	employment_start IS NULL OR employment_start <= employment_end
+);
+
+CREATE TABLE hourly_rates (
+	id integer PRIMARY KEY AUTOINCREMENT NOT NULL,
+	employee_id integer NOT NULL REFERENCES employees(id),
+	effective_from text NOT NULL,
+	rate_cents integer NOT NULL CHECK (rate_cents >= 0),
+	UNIQUE (employee_id, effective_from)
+);
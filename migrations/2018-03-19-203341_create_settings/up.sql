@@ -0,0 +1,4 @@
+CREATE TABLE settings (
+	key varchar NOT NULL PRIMARY KEY,
+	value varchar NOT NULL
+)
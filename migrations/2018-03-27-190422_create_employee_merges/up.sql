@@ -0,0 +1,10 @@
+CREATE TABLE employee_merges (
+	id integer PRIMARY KEY AUTOINCREMENT NOT NULL,
+	target_id integer NOT NULL REFERENCES employees(id),
+	merged_at text NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+	-- JSON document with everything needed to undo the merge
+	undo_data text NOT NULL,
+
+	undone boolean NOT NULL DEFAULT false
+)
@@ -0,0 +1 @@
+ALTER TABLE employees ADD COLUMN active boolean NOT NULL DEFAULT true
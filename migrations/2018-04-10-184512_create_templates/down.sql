@@ -0,0 +1,19 @@
+DROP TABLE templates;
+
+CREATE TABLE reports_old (
+	id integer PRIMARY KEY AUTOINCREMENT NOT NULL,
+	title varchar NOT NULL UNIQUE, --CHECK (length(title) > 7),
+	start_date text NOT NULL UNIQUE,
+	end_date text NOT NULL UNIQUE DEFAULT CURRENT_DATE CHECK (
+		start_date <= end_date AND
+		end_date <= strftime('%s', 'now', '-1 week')
+	),
+	was_pdf_generated boolean NOT NULL DEFAULT false,
+	show_absences boolean NOT NULL DEFAULT false
+);
+
+INSERT INTO reports_old (id, title, start_date, end_date, was_pdf_generated, show_absences)
+SELECT id, title, start_date, end_date, was_pdf_generated, show_absences FROM reports;
+
+DROP TABLE reports;
+ALTER TABLE reports_old RENAME TO reports;
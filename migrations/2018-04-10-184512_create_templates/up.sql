@@ -0,0 +1,7 @@
+CREATE TABLE templates (
+	name varchar NOT NULL PRIMARY KEY,
+	content text NOT NULL,
+	updated_at text NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+ALTER TABLE reports ADD COLUMN template varchar;
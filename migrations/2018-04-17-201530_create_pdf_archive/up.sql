@@ -0,0 +1,43 @@
+-- Count changes to a report, so archived PDFs can be matched to the state they were made from.
+ALTER TABLE reports ADD COLUMN revision integer NOT NULL DEFAULT 0;
+
+CREATE TRIGGER reports_revision_update
+AFTER UPDATE OF title, start_date, end_date, show_absences, template ON reports
+WHEN OLD.title IS NOT NEW.title
+	OR OLD.start_date IS NOT NEW.start_date
+	OR OLD.end_date IS NOT NEW.end_date
+	OR OLD.show_absences IS NOT NEW.show_absences
+	OR OLD.template IS NOT NEW.template
+BEGIN
+	UPDATE reports SET revision = revision + 1 WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER items_revision_insert AFTER INSERT ON items
+BEGIN
+	UPDATE reports SET revision = revision + 1 WHERE id = NEW.report_id;
+END;
+
+CREATE TRIGGER items_revision_update AFTER UPDATE ON items
+WHEN OLD.report_id IS NOT NEW.report_id
+	OR OLD.employee_id IS NOT NEW.employee_id
+	OR OLD.start_datetime IS NOT NEW.start_datetime
+	OR OLD.end_datetime IS NOT NEW.end_datetime
+	OR OLD.remark IS NOT NEW.remark
+	OR OLD.substitutes_employee_id IS NOT NEW.substitutes_employee_id
+BEGIN
+	UPDATE reports SET revision = revision + 1 WHERE id IN (OLD.report_id, NEW.report_id);
+END;
+
+CREATE TRIGGER items_revision_delete AFTER DELETE ON items
+BEGIN
+	UPDATE reports SET revision = revision + 1 WHERE id = OLD.report_id;
+END;
+
+CREATE TABLE pdf_archive (
+	id integer PRIMARY KEY AUTOINCREMENT NOT NULL,
+	report_id integer NOT NULL REFERENCES reports(id),
+	revision integer NOT NULL,
+	created_at text NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	sha256 text NOT NULL,
+	snapshot text NOT NULL
+);
@@ -0,0 +1,2 @@
+DROP INDEX pdf_jobs_pending;
+DROP TABLE pdf_jobs;
@@ -0,0 +1,15 @@
+CREATE TABLE pdf_jobs (
+	id integer PRIMARY KEY AUTOINCREMENT NOT NULL,
+	report_id integer NOT NULL REFERENCES reports(id),
+	revision integer NOT NULL,
+	status text NOT NULL DEFAULT 'queued'
+		CHECK (status IN ('queued', 'running', 'failed', 'done')),
+	log text NOT NULL DEFAULT '',
+	archive_id integer REFERENCES pdf_archive(id),
+	created_at text NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	finished_at text
+);
+
+-- Requests for a report that is already being generated are answered with the existing job.
+CREATE UNIQUE INDEX pdf_jobs_pending ON pdf_jobs(report_id, revision)
+WHERE status IN ('queued', 'running');
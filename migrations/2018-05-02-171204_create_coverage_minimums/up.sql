@@ -0,0 +1,8 @@
+-- Minimum number of staff on duty, by day of the week (0 is Monday) and type of week (0 is A).
+-- Combinations without an entry use the `coverage_minimum` setting.
+CREATE TABLE coverage_minimums (
+	weekday integer NOT NULL CHECK (weekday BETWEEN 0 AND 6),
+	type_of_week integer NOT NULL CHECK (type_of_week BETWEEN 0 AND 3),
+	minimum integer NOT NULL CHECK (minimum >= 0),
+	PRIMARY KEY (weekday, type_of_week)
+);
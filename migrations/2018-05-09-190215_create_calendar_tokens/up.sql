@@ -0,0 +1,5 @@
+-- Secret part of the URL of each employee's calendar feed, so nobody can guess someone else's.
+CREATE TABLE calendar_tokens (
+	employee_id integer PRIMARY KEY NOT NULL REFERENCES employees(id),
+	token text NOT NULL UNIQUE
+);
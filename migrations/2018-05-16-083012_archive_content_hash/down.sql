@@ -0,0 +1,14 @@
+CREATE TABLE pdf_archive_old (
+	id integer PRIMARY KEY AUTOINCREMENT NOT NULL,
+	report_id integer NOT NULL REFERENCES reports(id),
+	revision integer NOT NULL,
+	created_at text NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	sha256 text NOT NULL,
+	snapshot text NOT NULL
+);
+
+INSERT INTO pdf_archive_old (id, report_id, revision, created_at, sha256, snapshot)
+SELECT id, report_id, revision, created_at, sha256, snapshot FROM pdf_archive;
+
+DROP TABLE pdf_archive;
+ALTER TABLE pdf_archive_old RENAME TO pdf_archive;
@@ -0,0 +1,3 @@
+-- Hash of everything a PDF depends on, including hourly rates, absences and the template, which do
+-- not count as changes to the report's revision.
+ALTER TABLE pdf_archive ADD COLUMN content_sha256 text;
@@ -0,0 +1,270 @@
+use chrono::NaiveDate;
+use diesel::prelude::*;
+use diesel::{self, SqliteConnection};
+
+use employees;
+use errors::*;
+use schema::absences;
+
+use DATE_FORMAT;
+
+/// Reasons for an absence, as stored in the `reason` column.
+pub const REASONS: [&str; 4] = ["sick", "vacation", "training", "other"];
+
+/// Human-readable names for the entries of `REASONS`, as they appear on the report.
+pub const REASON_NAME: [&str; 4] = ["Krankheit", "Urlaub", "Fortbildung", "Sonstiges"];
+
+/// A period of time during which an employee was planned but could not work.
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct Absence {
+    #[serde(default)]
+    pub id: i32,
+    pub employee_id: i32,
+
+    /// First day of the absence.
+    pub start_date: String,
+
+    /// Last day of the absence, i.e. the absence includes this day.
+    pub end_date: String,
+
+    /// One of `REASONS`.
+    pub reason: String,
+
+    #[serde(default)]
+    pub remark: String,
+}
+
+impl Absence {
+    pub fn start(&self) -> Result<NaiveDate> {
+        NaiveDate::parse_from_str(&self.start_date, DATE_FORMAT)
+            .chain_err(|| format!("Invalid start date: {}", self.start_date))
+    }
+
+    pub fn end(&self) -> Result<NaiveDate> {
+        NaiveDate::parse_from_str(&self.end_date, DATE_FORMAT)
+            .chain_err(|| format!("Invalid end date: {}", self.end_date))
+    }
+
+    /// The name of the reason for this absence as it should appear on the report.
+    pub fn reason_name(&self) -> &'static str {
+        REASONS
+            .iter()
+            .position(|&reason| reason == self.reason)
+            .map_or("", |i| REASON_NAME[i])
+    }
+
+    /// Make sure the absence makes sense before writing it to the database.
+    fn check(&self, conn: &SqliteConnection) -> Result<()> {
+        employees::get_by_id(conn, self.employee_id)?;
+        if !REASONS.contains(&self.reason.as_str()) {
+            bail!(
+                "Invalid reason for absence: {} (expected one of {:?})",
+                self.reason,
+                REASONS
+            );
+        }
+        if self.start()? > self.end()? {
+            bail!(
+                "Absence ends ({}) before it starts ({})",
+                self.end_date,
+                self.start_date
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Get all absences from the database.
+pub fn get_all(conn: &SqliteConnection) -> Result<Vec<Absence>> {
+    absences::table
+        .order(absences::start_date.desc())
+        .load::<Absence>(conn)
+        .chain_err(|| "Failed to read table absences")
+}
+
+/// Get all absences of one employee.
+pub fn get_for_employee(conn: &SqliteConnection, employee_id: i32) -> Result<Vec<Absence>> {
+    assert!(employee_id >= 0);
+
+    absences::table
+        .filter(absences::employee_id.eq(employee_id))
+        .order(absences::start_date.desc())
+        .load::<Absence>(conn)
+        .chain_err(|| format!("Failed to get absences of employee #{}", employee_id))
+}
+
+/// Get the absences of an employee overlapping the period from `start` to `end` (inclusive).
+pub fn get_between(
+    conn: &SqliteConnection,
+    employee_id: i32,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Result<Vec<Absence>> {
+    assert!(employee_id >= 0);
+
+    absences::table
+        .filter(absences::employee_id.eq(employee_id))
+        .filter(absences::start_date.le(format!("{}", end.format(DATE_FORMAT))))
+        .filter(absences::end_date.ge(format!("{}", start.format(DATE_FORMAT))))
+        .order(absences::start_date.asc())
+        .load::<Absence>(conn)
+        .chain_err(|| format!("Failed to get absences of employee #{}", employee_id))
+}
+
+/// The ids of all employees with absences overlapping the period from `start` to `end`.
+pub fn employees_between(
+    conn: &SqliteConnection,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Result<Vec<i32>> {
+    absences::table
+        .select(absences::employee_id)
+        .distinct()
+        .filter(absences::start_date.le(format!("{}", end.format(DATE_FORMAT))))
+        .filter(absences::end_date.ge(format!("{}", start.format(DATE_FORMAT))))
+        .load(conn)
+        .chain_err(|| format!("Failed to get absences between {} and {}", start, end))
+}
+
+/// Find the absence of an employee covering a given day, if any.
+pub fn on_day(
+    conn: &SqliteConnection,
+    employee_id: i32,
+    day: NaiveDate,
+) -> Result<Option<Absence>> {
+    get_between(conn, employee_id, day, day).map(|absences| absences.into_iter().next())
+}
+
+/// Insert a new absence into the database, returning its id.
+pub fn insert(conn: &SqliteConnection, absence: &Absence) -> Result<i32> {
+    absence.check(conn)?;
+
+    diesel::insert_into(absences::table)
+        .values((
+            absences::employee_id.eq(absence.employee_id),
+            absences::start_date.eq(&absence.start_date),
+            absences::end_date.eq(&absence.end_date),
+            absences::reason.eq(&absence.reason),
+            absences::remark.eq(&absence.remark),
+        ))
+        .execute(conn)
+        .chain_err(|| format!("Failed to insert absence: {:?}", absence))?;
+
+    absences::table
+        .select(absences::id)
+        .order(absences::id.desc())
+        .first::<i32>(conn)
+        .chain_err(|| "Failed to get absence that was just inserted")
+}
+
+/// Replace the absence with the given id.
+pub fn update(conn: &SqliteConnection, id: i32, absence: &Absence) -> Result<()> {
+    assert!(id >= 0);
+    absence.check(conn)?;
+
+    diesel::update(absences::table.filter(absences::id.eq(id)))
+        .set((
+            absences::employee_id.eq(absence.employee_id),
+            absences::start_date.eq(&absence.start_date),
+            absences::end_date.eq(&absence.end_date),
+            absences::reason.eq(&absence.reason),
+            absences::remark.eq(&absence.remark),
+        ))
+        .execute(conn)
+        .map(|_| ())
+        .chain_err(|| format!("Failed to update absence #{}: {:?}", id, absence))
+}
+
+/// Remove an absence from the database.
+pub fn delete(conn: &SqliteConnection, id: i32) -> Result<()> {
+    diesel::delete(absences::table.filter(absences::id.eq(id)))
+        .execute(conn)
+        .map(|_| ())
+        .chain_err(|| format!("Failed to delete absence #{}", id))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn absence(employee_id: i32, start_date: &str, end_date: &str) -> Absence {
+        Absence {
+            id: 0,
+            employee_id,
+            start_date: start_date.to_string(),
+            end_date: end_date.to_string(),
+            reason: "sick".to_string(),
+            remark: String::new(),
+        }
+    }
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd(2018, 3, day)
+    }
+
+    #[test]
+    fn test_insert() {
+        let conn = ::db::test_connection();
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+
+        let id = insert(&conn, &absence(anna, "2018-03-05", "2018-03-09")).unwrap();
+        assert!(insert(&conn, &absence(anna + 1, "2018-03-05", "2018-03-09")).is_err());
+        assert!(insert(&conn, &absence(anna, "2018-03-09", "2018-03-05")).is_err());
+        let mut invalid = absence(anna, "2018-03-05", "2018-03-09");
+        invalid.reason = "holiday".to_string();
+        assert!(insert(&conn, &invalid).is_err());
+
+        assert!(update(&conn, id, &absence(anna + 1, "2018-03-05", "2018-03-09")).is_err());
+        update(&conn, id, &absence(anna, "2018-03-05", "2018-03-06")).unwrap();
+        let absences = get_all(&conn).unwrap();
+        assert_eq!(absences.len(), 1);
+        assert_eq!(absences[0].end_date, "2018-03-06");
+    }
+
+    #[test]
+    fn test_get_between() {
+        let conn = ::db::test_connection();
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+        let jan = employees::insert(&conn, "Jan Berg").unwrap();
+        insert(&conn, &absence(anna, "2018-03-05", "2018-03-09")).unwrap();
+        insert(&conn, &absence(anna, "2018-03-19", "2018-03-19")).unwrap();
+        insert(&conn, &absence(jan, "2018-03-01", "2018-03-31")).unwrap();
+
+        let starts = |start, end| -> Vec<String> {
+            get_between(&conn, anna, day(start), day(end))
+                .unwrap()
+                .into_iter()
+                .map(|absence| absence.start_date)
+                .collect()
+        };
+        assert_eq!(starts(1, 31), vec!["2018-03-05", "2018-03-19"]);
+        // Absences overlapping either end of the period count, including the first and last day.
+        assert_eq!(starts(1, 5), vec!["2018-03-05"]);
+        assert_eq!(starts(9, 12), vec!["2018-03-05"]);
+        assert_eq!(starts(6, 7), vec!["2018-03-05"]);
+        assert_eq!(starts(19, 25), vec!["2018-03-19"]);
+        assert!(starts(10, 18).is_empty());
+        assert!(starts(1, 4).is_empty());
+
+        let only_jan = employees_between(&conn, day(12), day(16)).unwrap();
+        assert_eq!(only_jan, vec![jan]);
+        let mut both = employees_between(&conn, day(19), day(19)).unwrap();
+        both.sort();
+        assert_eq!(both, vec![anna, jan]);
+    }
+
+    #[test]
+    fn test_on_day() {
+        let conn = ::db::test_connection();
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+        insert(&conn, &absence(anna, "2018-03-05", "2018-03-09")).unwrap();
+
+        assert!(on_day(&conn, anna, day(4)).unwrap().is_none());
+        assert_eq!(
+            on_day(&conn, anna, day(5)).unwrap().unwrap().reason_name(),
+            "Krankheit"
+        );
+        assert!(on_day(&conn, anna, day(9)).unwrap().is_some());
+        assert!(on_day(&conn, anna, day(10)).unwrap().is_none());
+    }
+}
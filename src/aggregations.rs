@@ -0,0 +1,348 @@
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, NaiveDate, Weekday};
+use diesel::SqliteConnection;
+use handlebars::Handlebars;
+use serde::Serialize;
+
+use errors::*;
+use items::{self, InvoiceItem};
+use reports::{self, REPORT_DATE_FORMAT};
+use weeks::{TYPE_OF_WEEK_NAME, WEEKDAY_NAME};
+use TIME_FORMAT;
+
+/// A way of summarizing the items of a report, other than the per-employee layout of the PDF.
+///
+/// Each aggregation comes with a Handlebars template showing it as a web page.
+pub trait Aggregation: Serialize + Sized {
+    /// The HTML template, which may use the `style` partial.
+    const TEMPLATE: &'static str;
+
+    fn compile(title: &str, items: &[InvoiceItem]) -> Self;
+
+    /// Aggregate the items of a report.
+    fn generate(conn: &SqliteConnection, report_id: i32) -> Result<Self> {
+        let report = reports::get(conn, report_id)?;
+        let items = items::get(conn, report_id)?;
+        Ok(Self::compile(&report.title, &items))
+    }
+
+    fn render_html(&self) -> Result<String> {
+        let mut handlebars = Handlebars::new();
+        handlebars.set_strict_mode(true);
+        handlebars
+            .register_partial("style", include_str!("aggregation_style.hbs"))
+            .chain_err(|| "Invalid style partial")?;
+        handlebars
+            .register_template_string("html", Self::TEMPLATE)
+            .chain_err(|| "Invalid HTML template")?;
+        handlebars
+            .render("html", self)
+            .chain_err(|| "Failed to render HTML report")
+    }
+}
+
+/// Format a number of minutes as hours and minutes, e.g. `2:05`.
+fn format_minutes(minutes: i64) -> String {
+    format!("{}:{:02}", minutes / 60, minutes % 60)
+}
+
+fn minutes(item: &InvoiceItem) -> i64 {
+    item.end.signed_duration_since(item.start).num_minutes()
+}
+
+fn format_date(day: NaiveDate) -> String {
+    format!("{}", day.format(REPORT_DATE_FORMAT))
+}
+
+/// How long one employee worked in some period.
+#[derive(Debug, Serialize)]
+pub struct EmployeeTotal {
+    pub employee_id: i32,
+    pub name: String,
+    pub minutes: i64,
+    /// `minutes` as hours and minutes.
+    pub total: String,
+}
+
+/// Sum up the time of each employee, sorted by name.
+fn employee_totals(items: &[&InvoiceItem]) -> Vec<EmployeeTotal> {
+    let mut by_employee: BTreeMap<i32, (&InvoiceItem, i64)> = BTreeMap::new();
+    for item in items {
+        by_employee.entry(item.employee_id).or_insert((*item, 0)).1 += minutes(item);
+    }
+
+    let mut totals: Vec<_> = by_employee.into_iter().map(|(_, total)| total).collect();
+    totals.sort_by(|a, b| ::names::compare(&a.0.name_sort, &b.0.name_sort));
+    totals
+        .into_iter()
+        .map(|(item, minutes)| EmployeeTotal {
+            employee_id: item.employee_id,
+            name: item.name.clone(),
+            minutes,
+            total: format_minutes(minutes),
+        })
+        .collect()
+}
+
+/// One employee's time on a specific day.
+#[derive(Debug, Serialize)]
+pub struct Shift {
+    pub name: String,
+    pub start: String,
+    pub end: String,
+    pub duration: String,
+    pub substitutes: String,
+    pub remark: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Day {
+    pub date: String,
+    pub weekday: String,
+    pub type_of_week: String,
+    /// Everyone on duty that day, by starting time.
+    pub shifts: Vec<Shift>,
+    pub total: String,
+}
+
+/// Who was on duty on each day.
+#[derive(Debug, Serialize)]
+pub struct ByDay {
+    pub title: String,
+    pub days: Vec<Day>,
+    pub total: String,
+}
+
+impl Aggregation for ByDay {
+    const TEMPLATE: &'static str = include_str!("by_day.html.hbs");
+
+    fn compile(title: &str, items: &[InvoiceItem]) -> Self {
+        let mut by_day: BTreeMap<NaiveDate, Vec<&InvoiceItem>> = BTreeMap::new();
+        for item in items {
+            by_day.entry(item.day).or_insert_with(Vec::new).push(item);
+        }
+
+        let days = by_day
+            .into_iter()
+            .map(|(day, mut items)| {
+                items.sort_by(|a, b| {
+                    a.start
+                        .cmp(&b.start)
+                        .then_with(|| ::names::compare(&a.name_sort, &b.name_sort))
+                });
+                Day {
+                    date: format_date(day),
+                    weekday: WEEKDAY_NAME[day.weekday().num_days_from_monday() as usize]
+                        .to_string(),
+                    type_of_week: TYPE_OF_WEEK_NAME[items[0].type_of_week as usize].to_string(),
+                    total: format_minutes(items.iter().map(|item| minutes(item)).sum()),
+                    shifts: items
+                        .into_iter()
+                        .map(|item| Shift {
+                            name: item.name.clone(),
+                            start: format!("{}", item.start.format(TIME_FORMAT)),
+                            end: format!("{}", item.end.format(TIME_FORMAT)),
+                            duration: format_minutes(minutes(item)),
+                            substitutes: item.substitutes_name.clone().unwrap_or_default(),
+                            remark: item.remark.clone(),
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        ByDay {
+            title: title.to_string(),
+            days,
+            total: format_minutes(items.iter().map(minutes).sum()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Week {
+    pub year: i32,
+    /// ISO week number.
+    pub week: u32,
+    pub type_of_week: String,
+    /// Monday of the week.
+    pub from: String,
+    /// Friday of the week.
+    pub to: String,
+    pub employees: Vec<EmployeeTotal>,
+    pub total: String,
+}
+
+/// How long everyone worked in each week.
+#[derive(Debug, Serialize)]
+pub struct ByWeek {
+    pub title: String,
+    pub weeks: Vec<Week>,
+    pub total: String,
+}
+
+impl Aggregation for ByWeek {
+    const TEMPLATE: &'static str = include_str!("by_week.html.hbs");
+
+    fn compile(title: &str, items: &[InvoiceItem]) -> Self {
+        let mut by_week: BTreeMap<(i32, u32), Vec<&InvoiceItem>> = BTreeMap::new();
+        for item in items {
+            let week = item.day.iso_week();
+            by_week
+                .entry((week.year(), week.week()))
+                .or_insert_with(Vec::new)
+                .push(item);
+        }
+
+        let weeks = by_week
+            .into_iter()
+            .map(|((year, week), items)| Week {
+                year,
+                week,
+                type_of_week: TYPE_OF_WEEK_NAME[items[0].type_of_week as usize].to_string(),
+                from: format_date(NaiveDate::from_isoywd(year, week, Weekday::Mon)),
+                to: format_date(NaiveDate::from_isoywd(year, week, Weekday::Fri)),
+                total: format_minutes(items.iter().map(|item| minutes(item)).sum()),
+                employees: employee_totals(&items),
+            })
+            .collect();
+
+        ByWeek {
+            title: title.to_string(),
+            weeks,
+            total: format_minutes(items.iter().map(minutes).sum()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct WeekType {
+    pub type_of_week: String,
+    /// Number of weeks of this type with at least one item.
+    pub weeks: usize,
+    /// Number of days of this type with at least one item.
+    pub days: usize,
+    pub employees: Vec<EmployeeTotal>,
+    pub minutes: i64,
+    pub total: String,
+}
+
+/// How long everyone worked in each type of week, to check the coverage of the A–D rota.
+#[derive(Debug, Serialize)]
+pub struct ByWeekType {
+    pub title: String,
+    pub week_types: Vec<WeekType>,
+    pub total: String,
+}
+
+impl Aggregation for ByWeekType {
+    const TEMPLATE: &'static str = include_str!("by_week_type.html.hbs");
+
+    fn compile(title: &str, items: &[InvoiceItem]) -> Self {
+        let week_types = TYPE_OF_WEEK_NAME
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let items: Vec<_> = items
+                    .iter()
+                    .filter(|item| item.type_of_week as usize == i)
+                    .collect();
+
+                let mut days: Vec<_> = items.iter().map(|item| item.day).collect();
+                days.sort();
+                days.dedup();
+                let mut weeks: Vec<_> = days
+                    .iter()
+                    .map(|day| (day.iso_week().year(), day.iso_week().week()))
+                    .collect();
+                weeks.dedup();
+
+                let minutes = items.iter().map(|item| minutes(item)).sum();
+                WeekType {
+                    type_of_week: name.to_string(),
+                    weeks: weeks.len(),
+                    days: days.len(),
+                    employees: employee_totals(&items),
+                    minutes,
+                    total: format_minutes(minutes),
+                }
+            })
+            .collect();
+
+        ByWeekType {
+            title: title.to_string(),
+            week_types,
+            total: format_minutes(items.iter().map(minutes).sum()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_data::invoice_items as items;
+
+    #[test]
+    fn test_format_minutes() {
+        assert_eq!(format_minutes(0), "0:00");
+        assert_eq!(format_minutes(65), "1:05");
+        assert_eq!(format_minutes(600), "10:00");
+    }
+
+    #[test]
+    fn test_by_day() {
+        let by_day = ByDay::compile("März", &items());
+        assert_eq!(by_day.days.len(), 3);
+        assert_eq!(by_day.total, "10:00");
+
+        let day = &by_day.days[0];
+        assert_eq!(day.weekday, "Dienstag");
+        assert_eq!(day.type_of_week, "A");
+        assert_eq!(day.total, "5:00");
+        assert_eq!(day.shifts[0].name, "Anna Schmidt");
+        assert_eq!(day.shifts[0].start, "12:00");
+        assert_eq!(day.shifts[1].name, "Jan Berg");
+        assert_eq!(day.shifts[1].duration, "2:30");
+    }
+
+    #[test]
+    fn test_by_week() {
+        let by_week = ByWeek::compile("März", &items());
+        assert_eq!(by_week.weeks.len(), 2);
+
+        let week = &by_week.weeks[0];
+        assert_eq!((week.year, week.week), (2018, 10));
+        assert_eq!(week.type_of_week, "A");
+        assert_eq!(week.from, format_date(NaiveDate::from_ymd(2018, 3, 5)));
+        assert_eq!(week.to, format_date(NaiveDate::from_ymd(2018, 3, 9)));
+        assert_eq!(week.total, "6:30");
+        assert_eq!(week.employees[0].name, "Anna Schmidt");
+        assert_eq!(week.employees[0].total, "4:00");
+        assert_eq!(week.employees[1].total, "2:30");
+    }
+
+    #[test]
+    fn test_by_week_type() {
+        let by_type = ByWeekType::compile("März", &items());
+        assert_eq!(by_type.week_types.len(), TYPE_OF_WEEK_NAME.len());
+
+        let a = &by_type.week_types[0];
+        assert_eq!((a.weeks, a.days, a.minutes), (1, 2, 390));
+        let b = &by_type.week_types[1];
+        assert_eq!((b.weeks, b.days, b.minutes), (1, 1, 210));
+        assert_eq!(b.employees.len(), 1);
+        assert!(by_type.week_types[2].employees.is_empty());
+        assert_eq!(by_type.total, "10:00");
+    }
+
+    #[test]
+    fn test_render_html() {
+        let html = ByDay::compile("März", &items()).render_html().unwrap();
+        assert!(html.contains("Dienstag"));
+        let html = ByWeek::compile("März", &items()).render_html().unwrap();
+        assert!(html.contains("KW 10"));
+        let html = ByWeekType::compile("März", &items()).render_html().unwrap();
+        assert!(html.contains("Anna Schmidt"));
+    }
+}
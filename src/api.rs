@@ -6,16 +6,38 @@
 use std::collections::HashMap;
 
 use chrono::NaiveDate;
-use rocket::response::NamedFile;
+use rocket::http::ContentType;
+use rocket::response::content::{Content, Html};
+use rocket::{Data, State};
 use rocket_contrib::Json;
+use serde_json::Value;
 
-use DATE_FORMAT;
+use {DATE_FORMAT, MAX_TIME, MIN_TIME};
+use absences::{self, Absence};
+use aggregations::{Aggregation, ByDay, ByWeek, ByWeekType};
+use archive::{self, ArchiveDiff, ArchivedPdf};
+use batch;
+use calendar::{self, CalendarLink};
+use compliance::{self, ComplianceReport};
+use coverage::{self, CoverageReport, Minimum};
 use db;
+use download::{Download, TempFile};
+use dump::{self, ImportSummary};
 use errors::*;
-use employees::{self, Employee};
-use items::{self, InvoiceItem, NewRow};
+use employees::{self, Employee, NewEmployee};
+use export::CsvOptions;
+use items::{self, InvoiceItem, NewRow, UpdatedItem};
+use jobs::{self, JobQueue, PdfJob};
+use merges::{self, MergeRecord, MergeRequest, MergeResult};
 use holidays;
-use reports::{self, Report};
+use import::{self, ImportReport};
+use rates::{self, HourlyRate};
+use reports::{self, NewReport, Report, ReportSummary};
+use settings::{self, Setting};
+use snapshots::{self, Snapshot};
+use statistics::{self, Filter};
+use substitutions::{self, EmployeeSubstitutions};
+use templates::{self, Template, TemplateInfo};
 
 #[derive(Serialize)]
 pub struct Globals {
@@ -26,8 +48,8 @@ pub struct Globals {
 impl Globals {
     pub fn new() -> Globals {
         Globals {
-            mintime: "12:00",
-            maxtime: "16:00",
+            mintime: MIN_TIME,
+            maxtime: MAX_TIME,
         }
     }
 }
@@ -53,13 +75,23 @@ fn set_item(
     report_id: i32,
     id: i32,
     item: Json<NewRow>,
-) -> Result<Json<InvoiceItem>> {
+) -> Result<Json<UpdatedItem>> {
     items::update(&conn, report_id, id, &item.into_inner()).map(Json)
 }
 
 #[get("/employees", format = "application/json")]
 fn get_employees(conn: db::DbConn) -> Result<Json<Vec<Employee>>> {
-    employees::get(&conn).map(Json)
+    employees::get(&conn, false).map(Json)
+}
+
+#[get("/employees/all", format = "application/json")]
+fn get_all_employees(conn: db::DbConn) -> Result<Json<Vec<Employee>>> {
+    employees::get(&conn, true).map(Json)
+}
+
+#[get("/employees/<id>", format = "application/json")]
+fn get_employee(conn: db::DbConn, id: i32) -> Result<Json<Employee>> {
+    employees::get_by_id(&conn, id).map(Json)
 }
 
 #[put("/employees/<id>", format = "application/json", data = "<employee>")]
@@ -71,15 +103,96 @@ fn update_employee(conn: db::DbConn, id: i32, employee: Json<Employee>) -> Resul
 }
 
 #[post("/employees", format = "application/json", data = "<employee>")]
-fn add_employee(conn: db::DbConn, employee: Json<Employee>) -> Result<Json<i32>> {
-    employees::insert(&conn, employee.into_inner().name).map(Json)
+fn add_employee(conn: db::DbConn, employee: Json<NewEmployee>) -> Result<Json<i32>> {
+    employees::create(&conn, &employee.into_inner()).map(Json)
 }
 
 #[delete("/employees/<id>")]
-fn delete_employee(conn: db::DbConn, id: i32) -> Result<Json<()>> {
+fn delete_employee(conn: db::DbConn, id: i32) -> Result<Json<bool>> {
     employees::delete(&conn, id).map(Json)
 }
 
+#[post("/employees/<id>/archive", format = "application/json")]
+fn archive_employee(conn: db::DbConn, id: i32) -> Result<Json<()>> {
+    employees::archive(&conn, id).map(Json)
+}
+
+#[post("/employees/<id>/restore", format = "application/json")]
+fn restore_employee(conn: db::DbConn, id: i32) -> Result<Json<()>> {
+    employees::restore(&conn, id).map(Json)
+}
+
+#[post("/employees/<id>/merge", format = "application/json", data = "<request>")]
+fn merge_employees(
+    conn: db::DbConn,
+    id: i32,
+    request: Json<MergeRequest>,
+) -> Result<Json<MergeResult>> {
+    merges::merge(&conn, id, &request.into_inner()).map(Json)
+}
+
+#[get("/merges", format = "application/json")]
+fn get_merges(conn: db::DbConn) -> Result<Json<Vec<MergeRecord>>> {
+    merges::get_all(&conn).map(Json)
+}
+
+#[post("/merges/<id>/undo")]
+fn undo_merge(conn: db::DbConn, id: i32) -> Result<Json<()>> {
+    merges::undo(&conn, id).map(Json)
+}
+
+/// The link to the calendar feed of an employee, to be passed on to them.
+#[get("/employees/<id>/calendar", format = "application/json")]
+fn get_calendar_link(conn: db::DbConn, id: i32) -> Result<Json<CalendarLink>> {
+    calendar::link(&conn, id).map(Json)
+}
+
+/// Revoke the calendar link of an employee and create a new one.
+#[post("/employees/<id>/calendar/reset", format = "application/json")]
+fn reset_calendar_link(conn: db::DbConn, id: i32) -> Result<Json<CalendarLink>> {
+    calendar::reset(&conn, id).map(Json)
+}
+
+#[get("/employees/<id>/rates", format = "application/json")]
+fn get_rates(conn: db::DbConn, id: i32) -> Result<Json<Vec<HourlyRate>>> {
+    rates::get_for_employee(&conn, id).map(Json)
+}
+
+#[post("/employees/<id>/rates", format = "application/json", data = "<rate>")]
+fn add_rate(conn: db::DbConn, id: i32, rate: Json<HourlyRate>) -> Result<Json<i32>> {
+    rates::insert(&conn, id, &rate.into_inner()).map(Json)
+}
+
+#[delete("/rates/<id>")]
+fn delete_rate(conn: db::DbConn, id: i32) -> Result<Json<()>> {
+    rates::delete(&conn, id).map(Json)
+}
+
+#[get("/absences", format = "application/json")]
+fn get_absences(conn: db::DbConn) -> Result<Json<Vec<Absence>>> {
+    absences::get_all(&conn).map(Json)
+}
+
+#[get("/employees/<id>/absences", format = "application/json")]
+fn get_employee_absences(conn: db::DbConn, id: i32) -> Result<Json<Vec<Absence>>> {
+    absences::get_for_employee(&conn, id).map(Json)
+}
+
+#[post("/absences", format = "application/json", data = "<absence>")]
+fn add_absence(conn: db::DbConn, absence: Json<Absence>) -> Result<Json<i32>> {
+    absences::insert(&conn, &absence.into_inner()).map(Json)
+}
+
+#[put("/absences/<id>", format = "application/json", data = "<absence>")]
+fn update_absence(conn: db::DbConn, id: i32, absence: Json<Absence>) -> Result<Json<()>> {
+    absences::update(&conn, id, &absence.into_inner()).map(Json)
+}
+
+#[delete("/absences/<id>")]
+fn delete_absence(conn: db::DbConn, id: i32) -> Result<Json<()>> {
+    absences::delete(&conn, id).map(Json)
+}
+
 #[get("/holidays", format = "application/json")]
 fn get_holidays(conn: db::DbConn) -> Json<HashMap<String, String>> {
     Json(holidays::get(&conn))
@@ -108,14 +221,322 @@ fn put_report(conn: db::DbConn, id: i32, report: Json<Report>) -> Result<()> {
 }
 
 #[post("/reports", format = "application/json", data = "<report>")]
-fn add_report(conn: db::DbConn, report: Json<Report>) -> Result<Json<i32>> {
+fn add_report(conn: db::DbConn, report: Json<NewReport>) -> Result<Json<i32>> {
     reports::add(&conn, &report.into_inner()).map(Json)
 }
 
-#[get("/reports/<id>/pdf/<_filename>")]
-fn generate_pdf_report(conn: db::DbConn, id: i32, _filename: String) -> Result<NamedFile> {
-    let pdf_file = ::generate_pdf::generate(&conn, id)?;
-    NamedFile::open(&pdf_file).chain_err(|| format!("Failed to open file {:?}", pdf_file))
+/// Start generating the PDF of a report. Poll the returned job until it is done or failed.
+#[post("/reports/<id>/pdf_jobs", format = "application/json")]
+fn add_pdf_job(conn: db::DbConn, queue: State<JobQueue>, id: i32) -> Result<Json<PdfJob>> {
+    jobs::enqueue(&conn, &queue, id).map(Json)
+}
+
+#[get("/pdf_jobs/<id>", format = "application/json")]
+fn get_pdf_job(conn: db::DbConn, id: i32) -> Result<Json<PdfJob>> {
+    jobs::get(&conn, id).map(Json)
+}
+
+#[get("/pdf_jobs/<id>/pdf/<filename>")]
+fn get_pdf_job_pdf(conn: db::DbConn, id: i32, filename: String) -> Result<Download> {
+    let data = jobs::pdf(&conn, id)?;
+    Ok(Download::new(filename, ContentType::PDF, data))
+}
+
+/// List all PDFs that were generated for a report, most recent first.
+#[get("/reports/<id>/archive", format = "application/json")]
+fn get_archive(conn: db::DbConn, id: i32) -> Result<Json<Vec<ArchivedPdf>>> {
+    archive::get_for_report(&conn, id).map(Json)
+}
+
+#[get("/archive/<id>/pdf/<filename>")]
+fn get_archived_pdf(conn: db::DbConn, id: i32, filename: String) -> Result<Download> {
+    let data = archive::read(&conn, id)?;
+    Ok(Download::new(filename, ContentType::PDF, data))
+}
+
+/// The data an archived PDF was generated from.
+#[get("/archive/<id>/snapshot", format = "application/json")]
+fn get_archive_snapshot(conn: db::DbConn, id: i32) -> Result<Json<Value>> {
+    archive::snapshot(&conn, id).map(Json)
+}
+
+#[get("/archive/<from>/diff/<to>", format = "application/json")]
+fn get_archive_diff(conn: db::DbConn, from: i32, to: i32) -> Result<Json<ArchiveDiff>> {
+    archive::diff(&conn, from, to).map(Json)
+}
+
+/// Render the LaTeX source of a report with the given template, without compiling it.
+#[get("/reports/<id>/preview/<template>")]
+fn preview_report(conn: db::DbConn, id: i32, template: String) -> Result<String> {
+    let report = reports::PerEmployeeReport::generate(&conn, id)?;
+    templates::render(&conn, Some(&template), &report)
+}
+
+/// Show a report as a web page. Unlike the PDF, this does not mark the report as generated.
+#[get("/reports/<id>/html")]
+fn get_report_html(conn: db::DbConn, id: i32) -> Result<Html<String>> {
+    let report = reports::PerEmployeeReport::generate(&conn, id)?;
+    ::html::render(&report).map(Html)
+}
+
+/// Who was on duty on each day of a report.
+#[get("/reports/<id>/by_day", format = "application/json")]
+fn get_by_day(conn: db::DbConn, id: i32) -> Result<Json<ByDay>> {
+    ByDay::generate(&conn, id).map(Json)
+}
+
+#[get("/reports/<id>/by_day/html")]
+fn get_by_day_html(conn: db::DbConn, id: i32) -> Result<Html<String>> {
+    ByDay::generate(&conn, id)?.render_html().map(Html)
+}
+
+/// How long everyone worked in each week of a report.
+#[get("/reports/<id>/by_week", format = "application/json")]
+fn get_by_week(conn: db::DbConn, id: i32) -> Result<Json<ByWeek>> {
+    ByWeek::generate(&conn, id).map(Json)
+}
+
+#[get("/reports/<id>/by_week/html")]
+fn get_by_week_html(conn: db::DbConn, id: i32) -> Result<Html<String>> {
+    ByWeek::generate(&conn, id)?.render_html().map(Html)
+}
+
+/// How long everyone worked in each type of week in a report.
+#[get("/reports/<id>/by_week_type", format = "application/json")]
+fn get_by_week_type(conn: db::DbConn, id: i32) -> Result<Json<ByWeekType>> {
+    ByWeekType::generate(&conn, id).map(Json)
+}
+
+#[get("/reports/<id>/by_week_type/html")]
+fn get_by_week_type_html(conn: db::DbConn, id: i32) -> Result<Html<String>> {
+    ByWeekType::generate(&conn, id)?.render_html().map(Html)
+}
+
+/// Export the items of a report as CSV, e.g. `/reports/3/export/csv?encoding=windows-1252`.
+///
+/// See `export::CsvOptions` for the parameters overriding the settings.
+#[get("/reports/<id>/export/csv?<options>")]
+fn export_csv(conn: db::DbConn, id: i32, options: CsvOptions) -> Result<Download> {
+    let report = ::generate_pdf::RawReportData::from_id(&conn, id)?;
+    let data = ::export::csv(&conn, &report, &options)?;
+    Ok(Download::new(
+        format!("{}.csv", report.sanitized_path()),
+        ContentType::new("text", "csv"),
+        data,
+    ))
+}
+
+/// Export the items of a report as CSV, using the settings.
+#[get("/reports/<id>/export/csv")]
+fn export_csv_with_settings(conn: db::DbConn, id: i32) -> Result<Download> {
+    export_csv(conn, id, CsvOptions::default())
+}
+
+#[get("/reports/<id>/export/ods")]
+fn export_ods(conn: db::DbConn, id: i32) -> Result<Download> {
+    let report = ::generate_pdf::RawReportData::from_id(&conn, id)?;
+    let data = ::ods::ods(&conn, &report)?;
+    Ok(Download::new(
+        format!("{}.ods", report.sanitized_path()),
+        ContentType::new("application", "vnd.oasis.opendocument.spreadsheet"),
+        data,
+    ))
+}
+
+/// Download the PDF and CSV files of all reports in a period, e.g. for the funding body.
+#[get("/export/range/<from>/<to>")]
+fn export_range(
+    conn: db::DbConn,
+    queue: State<JobQueue>,
+    from: String,
+    to: String,
+) -> Result<Download<TempFile>> {
+    let from = NaiveDate::parse_from_str(&from, DATE_FORMAT).chain_err(|| "Invalid date format")?;
+    let to = NaiveDate::parse_from_str(&to, DATE_FORMAT).chain_err(|| "Invalid date format")?;
+    export_zip(&conn, &queue, from, to)
+}
+
+/// Download the PDF and CSV files of all reports in the school year starting in `year`.
+#[get("/export/school_year/<year>")]
+fn export_school_year(
+    conn: db::DbConn,
+    queue: State<JobQueue>,
+    year: i32,
+) -> Result<Download<TempFile>> {
+    let (from, to) = batch::school_year(year);
+    export_zip(&conn, &queue, from, to)
+}
+
+/// Export a period into a temporary file, which is streamed to the client.
+///
+/// PDFs that are missing from the archive are queued instead of generated while the client waits,
+/// and the export is refused until they are done.
+fn export_zip(
+    conn: &db::DbConn,
+    queue: &JobQueue,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Download<TempFile>> {
+    let missing = batch::missing_pdfs(conn, from, to)?;
+    if !missing.is_empty() {
+        for report in &missing {
+            jobs::enqueue(conn, queue, report.id)?;
+        }
+        let titles: Vec<_> = missing.iter().map(|report| report.title.as_str()).collect();
+        bail!(
+            "The PDFs of {} are being generated, please try again once they are done",
+            titles.join(", ")
+        );
+    }
+
+    Ok(Download::from_body(
+        batch::filename(from, to),
+        ContentType::new("application", "zip"),
+        batch::export(conn, from, to, TempFile::create("export")?)?,
+    ))
+}
+
+/// Download all tables as one JSON document, e.g. as a backup.
+#[get("/admin/dump")]
+fn get_dump(conn: db::DbConn) -> Result<Download> {
+    let dump = dump::export(&conn)?;
+    Ok(Download::new(
+        dump::filename(&dump),
+        ContentType::JSON,
+        ::serde_json::to_vec_pretty(&dump)?,
+    ))
+}
+
+/// Replace the whole database with an uploaded dump.
+#[post("/admin/dump", data = "<data>")]
+fn import_dump(conn: db::DbConn, data: Data) -> Result<Json<ImportSummary>> {
+    dump::import(&conn, &dump::read(data.open())?, false).map(Json)
+}
+
+/// Check whether an uploaded dump could be imported, without changing anything.
+#[post("/admin/dump/check", data = "<data>")]
+fn check_dump(conn: db::DbConn, data: Data) -> Result<Json<ImportSummary>> {
+    dump::import(&conn, &dump::read(data.open())?, true).map(Json)
+}
+
+/// Import items from an uploaded CSV file in the layout of `export_csv`.
+#[post("/import/csv", data = "<data>")]
+fn import_csv(conn: db::DbConn, data: Data) -> Result<Json<ImportReport>> {
+    import::csv(&conn, &import::read(data.open())?, false).map(Json)
+}
+
+/// Show what importing an uploaded CSV file would do, without changing anything.
+#[post("/import/csv/check", data = "<data>")]
+fn check_csv(conn: db::DbConn, data: Data) -> Result<Json<ImportReport>> {
+    import::csv(&conn, &import::read(data.open())?, true).map(Json)
+}
+
+#[get("/admin/snapshots", format = "application/json")]
+fn get_snapshots(conn: db::DbConn) -> Result<Json<Vec<Snapshot>>> {
+    snapshots::list(&conn).map(Json)
+}
+
+#[post("/admin/snapshots", format = "application/json")]
+fn take_snapshot(conn: db::DbConn) -> Result<Json<Snapshot>> {
+    snapshots::take(&conn, "manual").map(Json)
+}
+
+#[get("/reports/<id>/summary", format = "application/json")]
+fn get_report_summary(conn: db::DbConn, id: i32) -> Result<Json<ReportSummary>> {
+    let report = reports::PerEmployeeReport::generate(&conn, id)?;
+    Ok(Json(report.summary(id)))
+}
+
+#[get("/reports/<id>/substitutions", format = "application/json")]
+fn get_substitutions(conn: db::DbConn, id: i32) -> Result<Json<Vec<EmployeeSubstitutions>>> {
+    substitutions::summary(&conn, id).map(Json)
+}
+
+/// Statistics over all items, e.g. `/statistics/months?from=2017-08-01&employee=3`.
+///
+/// See `statistics::get` for the available kinds of statistics.
+#[get("/statistics/<kind>?<filter>", format = "application/json")]
+fn get_statistics(conn: db::DbConn, kind: String, filter: Filter) -> Result<Json<Value>> {
+    statistics::get(&conn, &kind, &filter).map(Json)
+}
+
+/// Statistics over all items, without any filter.
+///
+/// Ranked below `get_statistics`, which only matches with a query, and above the static files.
+#[get("/statistics/<kind>", format = "application/json", rank = 1)]
+fn get_unfiltered_statistics(conn: db::DbConn, kind: String) -> Result<Json<Value>> {
+    statistics::get(&conn, &kind, &Filter::default()).map(Json)
+}
+
+/// School days between `from` and `to` on which not enough staff are on duty.
+#[get("/coverage/<from>/<to>", format = "application/json")]
+fn get_coverage(conn: db::DbConn, from: String, to: String) -> Result<Json<CoverageReport>> {
+    let from = NaiveDate::parse_from_str(&from, DATE_FORMAT).chain_err(|| "Invalid date format")?;
+    let to = NaiveDate::parse_from_str(&to, DATE_FORMAT).chain_err(|| "Invalid date format")?;
+    coverage::check(&conn, from, to).map(Json)
+}
+
+#[get("/coverage/minimums", format = "application/json")]
+fn get_coverage_minimums(conn: db::DbConn) -> Result<Json<Vec<Minimum>>> {
+    coverage::get_minimums(&conn).map(Json)
+}
+
+#[put("/coverage/minimums", format = "application/json", data = "<minimum>")]
+fn put_coverage_minimum(conn: db::DbConn, minimum: Json<Minimum>) -> Result<()> {
+    coverage::set_minimum(&conn, &minimum)
+}
+
+#[delete("/coverage/minimums/<weekday>/<type_of_week>")]
+fn delete_coverage_minimum(conn: db::DbConn, weekday: i32, type_of_week: i32) -> Result<()> {
+    coverage::reset_minimum(&conn, weekday, type_of_week)
+}
+
+#[get("/compliance/<year>", format = "application/json")]
+fn get_compliance(conn: db::DbConn, year: i32) -> Result<Json<ComplianceReport>> {
+    compliance::check(&conn, year).map(Json)
+}
+
+#[get("/templates", format = "application/json")]
+fn get_templates(conn: db::DbConn) -> Result<Json<Vec<TemplateInfo>>> {
+    templates::list(&conn).map(Json)
+}
+
+#[get("/templates/<name>", format = "application/json")]
+fn get_template(conn: db::DbConn, name: String) -> Result<Json<Template>> {
+    templates::get(&conn, &name).map(Json)
+}
+
+#[put("/templates/<name>", format = "application/json", data = "<template>")]
+fn put_template(conn: db::DbConn, name: String, template: Json<Template>) -> Result<Json<()>> {
+    let template = template.into_inner();
+    if template.name != name {
+        bail!(
+            "Template name {} does not match URL {}",
+            template.name,
+            name
+        );
+    }
+    templates::save(&conn, &template).map(Json)
+}
+
+#[delete("/templates/<name>")]
+fn delete_template(conn: db::DbConn, name: String) -> Result<Json<()>> {
+    templates::delete(&conn, &name).map(Json)
+}
+
+#[get("/settings", format = "application/json")]
+fn get_settings(conn: db::DbConn) -> Result<Json<Vec<Setting>>> {
+    settings::get_all(&conn).map(Json)
+}
+
+#[put("/settings/<key>", format = "application/json", data = "<value>")]
+fn put_setting(conn: db::DbConn, key: String, value: Json<String>) -> Result<Json<()>> {
+    settings::set(&conn, &key, &value.into_inner()).map(Json)
+}
+
+#[delete("/settings/<key>")]
+fn delete_setting(conn: db::DbConn, key: String) -> Result<Json<()>> {
+    settings::reset(&conn, &key).map(Json)
 }
 
 #[get("/next_schoolday/<day>", format = "application/json")]
@@ -143,15 +564,103 @@ pub fn routes() -> Vec<::rocket::Route> {
         put_report,
         get_globals,
         get_employees,
+        get_all_employees,
+        get_employee,
         add_employee,
         update_employee,
         delete_employee,
+        archive_employee,
+        restore_employee,
+        merge_employees,
+        get_merges,
+        undo_merge,
+        get_calendar_link,
+        reset_calendar_link,
+        get_rates,
+        add_rate,
+        delete_rate,
+        get_absences,
+        get_employee_absences,
+        add_absence,
+        update_absence,
+        delete_absence,
         get_items,
         get_holidays,
         add_report,
         set_item,
-        generate_pdf_report,
+        add_pdf_job,
+        get_pdf_job,
+        get_pdf_job_pdf,
+        get_archive,
+        get_archived_pdf,
+        get_archive_snapshot,
+        get_archive_diff,
+        get_report_summary,
+        get_report_html,
+        get_by_day,
+        get_by_day_html,
+        get_by_week,
+        get_by_week_html,
+        get_by_week_type,
+        get_by_week_type_html,
+        export_csv,
+        export_csv_with_settings,
+        export_ods,
+        import_csv,
+        check_csv,
+        export_range,
+        export_school_year,
+        get_dump,
+        import_dump,
+        check_dump,
+        get_snapshots,
+        take_snapshot,
+        preview_report,
+        get_substitutions,
+        get_statistics,
+        get_unfiltered_statistics,
+        get_compliance,
+        get_coverage,
+        get_coverage_minimums,
+        put_coverage_minimum,
+        delete_coverage_minimum,
+        get_templates,
+        get_template,
+        put_template,
+        delete_template,
+        get_settings,
+        put_setting,
+        delete_setting,
         get_next_schoolday,
         get_previous_schoolday,
     ]
 }
+
+/// School and general holidays, for subscribing to in a calendar app.
+#[get("/holidays/<_filename>", rank = 0)]
+fn get_holiday_calendar(conn: db::DbConn, _filename: String) -> Result<Content<String>> {
+    let feed = calendar::holiday_feed(&conn)?;
+    Ok(Content(ContentType::new("text", "calendar"), feed))
+}
+
+/// The shifts of an employee, for subscribing to in a calendar app.
+///
+/// The file name is ignored, but some apps only accept URLs ending in `.ics`. Ranked below the
+/// holiday feed, which has the same shape, and above the static files in `main.rs`.
+#[get("/<token>/<_filename>", rank = 1)]
+fn get_employee_calendar(
+    conn: db::DbConn,
+    token: String,
+    _filename: String,
+) -> Result<Content<String>> {
+    let feed = calendar::employee_feed(&conn, &token)?;
+    Ok(Content(ContentType::new("text", "calendar"), feed))
+}
+
+/// The calendar feeds, which calendar apps fetch without logging in.
+///
+/// They are mounted outside of the API, so that a reverse proxy can make them public while the
+/// API, which hands out the links, stays internal.
+pub fn calendar_routes() -> Vec<::rocket::Route> {
+    routes![get_holiday_calendar, get_employee_calendar]
+}
@@ -0,0 +1,402 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use diesel::prelude::*;
+use diesel::{self, SqliteConnection};
+use serde_json::{self, Value};
+use sha2::{Digest, Sha256};
+
+use errors::*;
+use generate_pdf::ReportSnapshot;
+use schema::pdf_archive;
+use settings;
+
+/// Setting for the directory where generated files are kept.
+pub const DATA_DIR: &str = "data_dir";
+const DEFAULT_DATA_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/data");
+
+/// Fields of the report that are not worth mentioning in a diff.
+const IGNORED_REPORT_FIELDS: [&str; 3] = ["id", "revision", "was_pdf_generated"];
+
+/// A PDF that was generated at some point, without the snapshot of the data it was made from.
+#[derive(Debug, Serialize, Queryable)]
+pub struct ArchivedPdf {
+    pub id: i32,
+    pub report_id: i32,
+    pub revision: i32,
+    pub created_at: String,
+    pub sha256: String,
+
+    /// `ReportSnapshot::content_hash` of the data the PDF was made from. Missing for PDFs
+    /// archived before this was recorded.
+    pub content_sha256: Option<String>,
+}
+
+/// A value that differs between two versions.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+/// An item that exists in both versions, but with different values.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ItemChange {
+    pub item_id: i64,
+    pub changes: Vec<FieldChange>,
+}
+
+/// What changed in the input data between two archived PDFs.
+#[derive(Debug, Serialize)]
+pub struct ArchiveDiff {
+    pub from: ArchivedPdf,
+    pub to: ArchivedPdf,
+
+    /// Changes to the title, period etc. of the report itself.
+    pub report: Vec<FieldChange>,
+
+    pub added_items: Vec<Value>,
+    pub removed_items: Vec<Value>,
+    pub changed_items: Vec<ItemChange>,
+}
+
+/// The directory containing the archive, exports and backups.
+pub fn data_dir(conn: &SqliteConnection) -> Result<PathBuf> {
+    let dir: String = settings::get(conn, DATA_DIR, DEFAULT_DATA_DIR.to_string())?;
+    Ok(PathBuf::from(dir))
+}
+
+/// Files in the archive are named after their hash, so they can never be overwritten by a
+/// different PDF.
+fn file_path(conn: &SqliteConnection, sha256: &str) -> Result<PathBuf> {
+    Ok(data_dir(conn)?
+        .join("archive")
+        .join(format!("{}.pdf", sha256)))
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Add a freshly generated PDF to the archive, together with the data it was made from.
+pub fn store(
+    conn: &SqliteConnection,
+    snapshot: &ReportSnapshot,
+    pdf: &[u8],
+) -> Result<ArchivedPdf> {
+    let report = &snapshot.data;
+    let sha256 = sha256_hex(pdf);
+    let path = file_path(conn, &sha256)?;
+
+    if !path.exists() {
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir).chain_err(|| format!("Failed to create {:?}", dir))?;
+
+        // Write to a temporary file first, so a crash cannot leave a truncated PDF behind.
+        let partial = path.with_extension("part");
+        File::create(&partial)
+            .and_then(|mut file| file.write_all(pdf))
+            .chain_err(|| format!("Failed to write {:?}", partial))?;
+        fs::rename(&partial, &path).chain_err(|| format!("Failed to move PDF to {:?}", path))?;
+    }
+
+    diesel::insert_into(pdf_archive::table)
+        .values((
+            pdf_archive::report_id.eq(report.metadata.id),
+            pdf_archive::revision.eq(report.metadata.revision),
+            pdf_archive::sha256.eq(&sha256),
+            pdf_archive::snapshot.eq(serde_json::to_string(snapshot)?),
+            pdf_archive::content_sha256.eq(snapshot.content_hash()?),
+        ))
+        .execute(conn)
+        .chain_err(|| format!("Failed to archive PDF of report #{}", report.metadata.id))?;
+
+    info!(
+        "Archived PDF of report #{} (revision {}) as {}",
+        report.metadata.id, report.metadata.revision, sha256
+    );
+
+    pdf_archive::table
+        .select(pdf_archive::id)
+        .filter(pdf_archive::report_id.eq(report.metadata.id))
+        .order(pdf_archive::id.desc())
+        .first(conn)
+        .chain_err(|| "Failed to get PDF that was just archived")
+        .and_then(|id| get(conn, id))
+}
+
+/// List all PDFs generated for a report, most recent first.
+pub fn get_for_report(conn: &SqliteConnection, report_id: i32) -> Result<Vec<ArchivedPdf>> {
+    pdf_archive::table
+        .select((
+            pdf_archive::id,
+            pdf_archive::report_id,
+            pdf_archive::revision,
+            pdf_archive::created_at,
+            pdf_archive::sha256,
+            pdf_archive::content_sha256,
+        ))
+        .filter(pdf_archive::report_id.eq(report_id))
+        .order(pdf_archive::id.desc())
+        .load(conn)
+        .chain_err(|| format!("Failed to read archive of report #{}", report_id))
+}
+
+pub fn get(conn: &SqliteConnection, id: i32) -> Result<ArchivedPdf> {
+    pdf_archive::table
+        .select((
+            pdf_archive::id,
+            pdf_archive::report_id,
+            pdf_archive::revision,
+            pdf_archive::created_at,
+            pdf_archive::sha256,
+            pdf_archive::content_sha256,
+        ))
+        .filter(pdf_archive::id.eq(id))
+        .first(conn)
+        .chain_err(|| format!("Failed to get archived PDF #{}", id))
+}
+
+/// The data an archived PDF was generated from.
+pub fn snapshot(conn: &SqliteConnection, id: i32) -> Result<Value> {
+    let snapshot = pdf_archive::table
+        .select(pdf_archive::snapshot)
+        .filter(pdf_archive::id.eq(id))
+        .first::<String>(conn)
+        .chain_err(|| format!("Failed to get archived PDF #{}", id))?;
+    Ok(serde_json::from_str(&snapshot)?)
+}
+
+/// Read an archived PDF, making sure it has not been changed since it was generated.
+pub fn read(conn: &SqliteConnection, id: i32) -> Result<Vec<u8>> {
+    let archived = get(conn, id)?;
+    let path = file_path(conn, &archived.sha256)?;
+
+    let mut data = vec![];
+    File::open(&path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .chain_err(|| format!("Failed to read {:?}", path))?;
+
+    if sha256_hex(&data) != archived.sha256 {
+        bail!("Archived PDF #{} has been modified: {:?}", id, path);
+    }
+    Ok(data)
+}
+
+/// Compare the fields of two JSON objects.
+fn diff_fields(old: &Value, new: &Value, ignored: &[&str]) -> Vec<FieldChange> {
+    let empty = serde_json::Map::new();
+    let old = old.as_object().unwrap_or(&empty);
+    let new = new.as_object().unwrap_or(&empty);
+
+    let mut fields: Vec<_> = old.keys().chain(new.keys()).collect();
+    fields.sort();
+    fields.dedup();
+
+    fields
+        .into_iter()
+        .filter(|field| !ignored.contains(&field.as_str()))
+        .filter_map(|field| {
+            let old = old.get(field).cloned().unwrap_or(Value::Null);
+            let new = new.get(field).cloned().unwrap_or(Value::Null);
+            if old == new {
+                None
+            } else {
+                Some(FieldChange {
+                    field: field.clone(),
+                    old,
+                    new,
+                })
+            }
+        })
+        .collect()
+}
+
+/// The items of a snapshot by id.
+fn items_by_id(snapshot: &Value) -> BTreeMap<i64, &Value> {
+    snapshot["items"]
+        .as_array()
+        .map(|items| {
+            items
+                .iter()
+                .filter_map(|item| item["id"].as_i64().map(|id| (id, item)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Compare two snapshots.
+fn diff_snapshots(
+    old: &Value,
+    new: &Value,
+) -> (Vec<FieldChange>, Vec<Value>, Vec<Value>, Vec<ItemChange>) {
+    let report = diff_fields(&old["metadata"], &new["metadata"], &IGNORED_REPORT_FIELDS);
+
+    let old_items = items_by_id(old);
+    let new_items = items_by_id(new);
+
+    let added = new_items
+        .iter()
+        .filter(|&(id, _)| !old_items.contains_key(id))
+        .map(|(_, &item)| item.clone())
+        .collect();
+    let removed = old_items
+        .iter()
+        .filter(|&(id, _)| !new_items.contains_key(id))
+        .map(|(_, &item)| item.clone())
+        .collect();
+    let changed = old_items
+        .iter()
+        .filter_map(|(&id, &old_item)| {
+            let new_item = new_items.get(&id)?;
+            let changes = diff_fields(old_item, new_item, &[]);
+            if changes.is_empty() {
+                None
+            } else {
+                Some(ItemChange {
+                    item_id: id,
+                    changes,
+                })
+            }
+        })
+        .collect();
+
+    (report, added, removed, changed)
+}
+
+/// The archived PDF of a report that was made from the current data, if there is one.
+pub fn current(conn: &SqliteConnection, report_id: i32) -> Result<Option<ArchivedPdf>> {
+    let content_sha256 = ReportSnapshot::from_id(conn, report_id)?.content_hash()?;
+    Ok(get_for_report(conn, report_id)?
+        .into_iter()
+        .find(|archived| archived.content_sha256.as_ref() == Some(&content_sha256)))
+}
+
+/// Show what changed in the input data between two archived PDFs.
+pub fn diff(conn: &SqliteConnection, from: i32, to: i32) -> Result<ArchiveDiff> {
+    let (report, added_items, removed_items, changed_items) =
+        diff_snapshots(&snapshot(conn, from)?, &snapshot(conn, to)?);
+
+    Ok(ArchiveDiff {
+        from: get(conn, from)?,
+        to: get(conn, to)?,
+        report,
+        added_items,
+        removed_items,
+        changed_items,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    use rates::{self, HourlyRate};
+    use templates::{self, Template};
+    use test_data;
+
+    #[test]
+    fn test_current() {
+        let dir = TempDir::new("archive").unwrap();
+        let conn = ::db::test_connection();
+        settings::set(&conn, DATA_DIR, dir.path().to_str().unwrap()).unwrap();
+
+        let anna = ::employees::insert(&conn, "Anna Schmidt").unwrap();
+        let report_id = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        test_data::item(&conn, report_id, anna, "2018-03-05", (12, 14), None);
+        assert!(current(&conn, report_id).unwrap().is_none());
+
+        let report = ReportSnapshot::from_id(&conn, report_id).unwrap();
+        let archived = store(&conn, &report, b"%PDF-1.4").unwrap();
+        assert_eq!(current(&conn, report_id).unwrap().unwrap().id, archived.id);
+        assert_eq!(read(&conn, archived.id).unwrap(), b"%PDF-1.4");
+
+        // Neither of these changes the revision of the report.
+        let rate = HourlyRate {
+            id: 0,
+            employee_id: anna,
+            effective_from: "2018-01-01".to_string(),
+            rate_cents: 1200,
+        };
+        rates::insert(&conn, anna, &rate).unwrap();
+        assert!(current(&conn, report_id).unwrap().is_none());
+        let report = ReportSnapshot::from_id(&conn, report_id).unwrap();
+        let archived = store(&conn, &report, b"%PDF-1.4 rate").unwrap();
+        assert_eq!(current(&conn, report_id).unwrap().unwrap().id, archived.id);
+
+        let template = Template {
+            name: templates::BUILTIN.to_string(),
+            content: "{{title}}: {{amount}}".to_string(),
+        };
+        templates::save(&conn, &template).unwrap();
+        assert!(current(&conn, report_id).unwrap().is_none());
+
+        let saved = snapshot(&conn, archived.id).unwrap();
+        assert_eq!(saved["report"]["amount"], json!("24,00 €"));
+        assert_eq!(saved["template"]["name"], json!(templates::BUILTIN));
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_diff_snapshots() {
+        let old = json!({
+            "metadata": {"id": 1, "title": "März", "revision": 3, "was_pdf_generated": false},
+            "items": [
+                {"id": 1, "name": "Anna Schmidt", "remark": ""},
+                {"id": 2, "name": "Jan Berg", "remark": ""},
+            ],
+        });
+        let new = json!({
+            "metadata": {"id": 1, "title": "März 2018", "revision": 5, "was_pdf_generated": true},
+            "items": [
+                {"id": 1, "name": "Anna Schmidt", "remark": "Hausaufgaben"},
+                {"id": 3, "name": "Jan Berg", "remark": ""},
+            ],
+        });
+
+        let (report, added, removed, changed) = diff_snapshots(&old, &new);
+        assert_eq!(
+            report,
+            vec![FieldChange {
+                field: "title".to_string(),
+                old: json!("März"),
+                new: json!("März 2018"),
+            }]
+        );
+        assert_eq!(added, vec![new["items"][1].clone()]);
+        assert_eq!(removed, vec![old["items"][1].clone()]);
+        assert_eq!(
+            changed,
+            vec![ItemChange {
+                item_id: 1,
+                changes: vec![FieldChange {
+                    field: "remark".to_string(),
+                    old: json!(""),
+                    new: json!("Hausaufgaben"),
+                }],
+            }]
+        );
+
+        let (report, added, removed, changed) = diff_snapshots(&new, &new);
+        assert!(report.is_empty() && added.is_empty() && removed.is_empty());
+        assert!(changed.is_empty());
+    }
+}
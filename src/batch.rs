@@ -0,0 +1,222 @@
+use std::io::{Seek, Write};
+
+use chrono::{Datelike, NaiveDate};
+use diesel::SqliteConnection;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use archive;
+use errors::*;
+use export;
+use generate_pdf::RawReportData;
+use ods;
+use reports::{self, Report};
+use DATE_FORMAT;
+
+/// The period of the school year starting in the summer of `year`.
+///
+/// Reports are handed in at the end of every month, so the school year is taken to run from the
+/// beginning of August to the end of July, which also covers the summer holidays.
+pub fn school_year(year: i32) -> (NaiveDate, NaiveDate) {
+    (
+        NaiveDate::from_ymd(year, 8, 1),
+        NaiveDate::from_ymd(year + 1, 7, 31),
+    )
+}
+
+/// The school year a day belongs to, as the year it started in.
+pub fn school_year_of(day: NaiveDate) -> i32 {
+    if day.month() >= 8 {
+        day.year()
+    } else {
+        day.year() - 1
+    }
+}
+
+/// Name of the ZIP file for a period, e.g. `Abrechnungen 2017-18.zip` for a whole school year.
+pub fn filename(from: NaiveDate, to: NaiveDate) -> String {
+    if (from, to) == school_year(from.year()) {
+        format!("Abrechnungen {}-{:02}.zip", from.year(), to.year() % 100)
+    } else {
+        format!(
+            "Abrechnungen {} bis {}.zip",
+            from.format(DATE_FORMAT),
+            to.format(DATE_FORMAT)
+        )
+    }
+}
+
+/// All reports overlapping the given period, in chronological order.
+fn reports_between(conn: &SqliteConnection, from: NaiveDate, to: NaiveDate) -> Result<Vec<Report>> {
+    let from = from.format(DATE_FORMAT).to_string();
+    let to = to.format(DATE_FORMAT).to_string();
+
+    let mut reports: Vec<_> = reports::get_all(conn)?
+        .into_iter()
+        .filter(|report| report.start_date <= to && report.end_date >= from)
+        .collect();
+    reports.sort_by(|a, b| (&a.start_date, a.id).cmp(&(&b.start_date, b.id)));
+    Ok(reports)
+}
+
+/// Reports in the given period without an archived PDF of their current data. Their PDFs have to
+/// be generated before the period can be exported.
+pub fn missing_pdfs(
+    conn: &SqliteConnection,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<Report>> {
+    let mut missing = vec![];
+    for report in reports_between(conn, from, to)? {
+        if archive::current(conn, report.id)?.is_none() {
+            missing.push(report);
+        }
+    }
+    Ok(missing)
+}
+
+/// The archived PDF of a report, which has to be up to date.
+fn pdf(conn: &SqliteConnection, report: &Report) -> Result<Vec<u8>> {
+    match archive::current(conn, report.id)? {
+        Some(archived) => archive::read(conn, archived.id),
+        None => bail!("The PDF of {} has to be generated first", report.title),
+    }
+}
+
+fn add_file<W: Write + Seek>(zip: &mut ZipWriter<W>, name: &str, data: &[u8]) -> Result<()> {
+    zip.start_file(name, FileOptions::default())
+        .and_then(|()| Ok(zip.write_all(data)?))
+        .chain_err(|| format!("Failed to add {} to ZIP archive", name))
+}
+
+/// Write a ZIP archive with the PDF and CSV file of every report in the given period, plus a
+/// spreadsheet summarizing all of them, to `target`.
+///
+/// PDFs are taken from the archive, so they have to be generated first, see `missing_pdfs`. Each
+/// file is added as soon as it is ready, so only one of them is held in memory at a time. File
+/// names start with the first day of the report, so they are sorted chronologically and reports
+/// with the same title do not overwrite each other.
+pub fn export<W: Write + Seek>(
+    conn: &SqliteConnection,
+    from: NaiveDate,
+    to: NaiveDate,
+    target: W,
+) -> Result<W> {
+    if from > to {
+        bail!("The period ends before it starts: {} to {}", from, to);
+    }
+    let reports = reports_between(conn, from, to)?;
+    if reports.is_empty() {
+        bail!("There are no reports between {} and {}", from, to);
+    }
+
+    let mut zip = ZipWriter::new(target);
+    let mut all_data = vec![];
+    for report in reports {
+        let data = RawReportData::from_id(conn, report.id)?;
+        let name = format!("{} {}", report.start_date, data.sanitized_path());
+
+        let pdf = pdf(conn, &report).chain_err(|| format!("Failed to export {}", report.title))?;
+        add_file(&mut zip, &format!("{}.pdf", name), &pdf)?;
+        let csv = export::csv(conn, &data, &export::CsvOptions::default())?;
+        add_file(&mut zip, &format!("{}.csv", name), &csv)?;
+        all_data.push(data);
+    }
+    add_file(
+        &mut zip,
+        "Übersicht.ods",
+        &ods::year_summary(conn, &all_data)?,
+    )?;
+
+    info!(
+        "Exported {} reports between {} and {}",
+        all_data.len(),
+        from,
+        to
+    );
+    zip.finish().chain_err(|| "Failed to create ZIP archive")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Cursor, Read};
+    use tempdir::TempDir;
+    use zip::ZipArchive;
+
+    use generate_pdf::ReportSnapshot;
+    use settings;
+    use test_data;
+
+    #[test]
+    fn test_school_year_of() {
+        assert_eq!(school_year_of(NaiveDate::from_ymd(2018, 7, 31)), 2017);
+        assert_eq!(school_year_of(NaiveDate::from_ymd(2018, 8, 1)), 2018);
+        assert_eq!(school_year_of(NaiveDate::from_ymd(2018, 12, 31)), 2018);
+        let (from, to) = school_year(2017);
+        assert_eq!((school_year_of(from), school_year_of(to)), (2017, 2017));
+    }
+
+    #[test]
+    fn test_filename() {
+        let (from, to) = school_year(2017);
+        assert_eq!(from, NaiveDate::from_ymd(2017, 8, 1));
+        assert_eq!(to, NaiveDate::from_ymd(2018, 7, 31));
+        assert_eq!(filename(from, to), "Abrechnungen 2017-18.zip");
+
+        let (from, to) = school_year(2099);
+        assert_eq!(filename(from, to), "Abrechnungen 2099-00.zip");
+
+        assert_eq!(
+            filename(
+                NaiveDate::from_ymd(2018, 1, 1),
+                NaiveDate::from_ymd(2018, 3, 31)
+            ),
+            "Abrechnungen 2018-01-01 bis 2018-03-31.zip"
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let dir = TempDir::new("batch").unwrap();
+        let conn = ::db::test_connection();
+        settings::set(&conn, archive::DATA_DIR, dir.path().to_str().unwrap()).unwrap();
+
+        let anna = ::employees::insert(&conn, "Anna Schmidt").unwrap();
+        let report_id = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        test_data::item(&conn, report_id, anna, "2018-03-05", (12, 14), None);
+        let (from, to) = school_year(2017);
+
+        // PDFs are not generated during the export.
+        let missing = missing_pdfs(&conn, from, to).unwrap();
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].id, report_id);
+        assert!(export(&conn, from, to, Cursor::new(vec![])).is_err());
+
+        let snapshot = ReportSnapshot::from_id(&conn, report_id).unwrap();
+        archive::store(&conn, &snapshot, b"%PDF-1.4").unwrap();
+        assert!(missing_pdfs(&conn, from, to).unwrap().is_empty());
+
+        let data = export(&conn, from, to, Cursor::new(vec![])).unwrap();
+        let mut zip = ZipArchive::new(data).unwrap();
+        let names: Vec<_> = (0..zip.len())
+            .map(|i| zip.by_index(i).unwrap().name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "2018-03-01 2018-03-01 bis 2018-03-31.pdf",
+                "2018-03-01 2018-03-01 bis 2018-03-31.csv",
+                "Übersicht.ods",
+            ]
+        );
+
+        let mut pdf = vec![];
+        zip.by_index(0).unwrap().read_to_end(&mut pdf).unwrap();
+        assert_eq!(pdf, b"%PDF-1.4");
+
+        assert!(export(&conn, to, from, Cursor::new(vec![])).is_err());
+        let (from, to) = school_year(2018);
+        assert!(export(&conn, from, to, Cursor::new(vec![])).is_err());
+    }
+}
@@ -0,0 +1,359 @@
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Utc, Weekday};
+use diesel::prelude::*;
+use diesel::{self, SqliteConnection};
+use rand::{OsRng, Rng};
+
+use employees;
+use errors::*;
+use items::InvoiceItem;
+use schema::{calendar_tokens, holidays};
+use weeks::TYPE_OF_WEEK_NAME;
+use DATE_FORMAT;
+
+/// Identifies this program as the author of the calendars.
+const PRODUCT_ID: &str = "-//time-report//Schichten//DE";
+
+/// Longer lines are folded, as required by RFC 5545. The limit is in bytes, not characters.
+const MAX_LINE_LENGTH: usize = 75;
+
+/// Where the feeds are served, separately from the API.
+pub const MOUNT_POINT: &str = "/calendar/";
+
+/// The secret that gives access to the calendar of an employee.
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "calendar_tokens"]
+pub struct CalendarToken {
+    pub employee_id: i32,
+    pub token: String,
+}
+
+/// Where an employee can subscribe to their calendar.
+#[derive(Debug, Serialize)]
+pub struct CalendarLink {
+    pub employee_id: i32,
+    pub token: String,
+    /// The URL of the feed, relative to the server rather than the API.
+    pub path: String,
+}
+
+impl CalendarLink {
+    fn new(employee_id: i32, token: String) -> Self {
+        CalendarLink {
+            employee_id,
+            path: format!("{}{}/schichten.ics", MOUNT_POINT, token),
+            token,
+        }
+    }
+}
+
+/// 256 random bits from the operating system, as a hexadecimal string.
+fn new_token() -> Result<String> {
+    let mut bytes = [0u8; 32];
+    OsRng::new()
+        .chain_err(|| "Failed to access random number generator")?
+        .fill_bytes(&mut bytes);
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn set_token(conn: &SqliteConnection, employee_id: i32) -> Result<String> {
+    let token = CalendarToken {
+        employee_id,
+        token: new_token()?,
+    };
+    diesel::replace_into(calendar_tokens::table)
+        .values(&token)
+        .execute(conn)
+        .chain_err(|| format!("Failed to set calendar token of employee #{}", employee_id))?;
+    Ok(token.token)
+}
+
+/// The calendar link of an employee. A token is created the first time.
+pub fn link(conn: &SqliteConnection, employee_id: i32) -> Result<CalendarLink> {
+    employees::get_by_id(conn, employee_id)?;
+    let token = calendar_tokens::table
+        .find(employee_id)
+        .select(calendar_tokens::token)
+        .first::<String>(conn)
+        .optional()
+        .chain_err(|| format!("Failed to get calendar token of employee #{}", employee_id))?;
+
+    let token = match token {
+        Some(token) => token,
+        None => set_token(conn, employee_id)?,
+    };
+    Ok(CalendarLink::new(employee_id, token))
+}
+
+/// Give an employee a new token, so that the old link stops working.
+pub fn reset(conn: &SqliteConnection, employee_id: i32) -> Result<CalendarLink> {
+    employees::get_by_id(conn, employee_id)?;
+    let token = set_token(conn, employee_id)?;
+    Ok(CalendarLink::new(employee_id, token))
+}
+
+/// Escape a text value, see section 3.3.11 of RFC 5545.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | ';' | ',' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Split a line into lines of at most `MAX_LINE_LENGTH` bytes. The continuation lines start with
+/// a space. Characters are never split.
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE_LENGTH {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded
+}
+
+/// An iCalendar document being written.
+struct Calendar {
+    lines: Vec<String>,
+    timestamp: String,
+}
+
+impl Calendar {
+    fn new(name: &str) -> Self {
+        Calendar {
+            lines: vec![
+                "BEGIN:VCALENDAR".to_string(),
+                "VERSION:2.0".to_string(),
+                format!("PRODID:{}", PRODUCT_ID),
+                "CALSCALE:GREGORIAN".to_string(),
+                format!("X-WR-CALNAME:{}", escape(name)),
+            ],
+            timestamp: Utc::now().format("%Y%m%dT%H%M%SZ").to_string(),
+        }
+    }
+
+    /// Add an event. `start` and `end` are the values of `DTSTART` and `DTEND` including any
+    /// parameters, see `date_time` and `date`.
+    fn event(&mut self, uid: &str, start: &str, end: &str, summary: &str, description: &str) {
+        self.lines.push("BEGIN:VEVENT".to_string());
+        self.lines.push(format!("UID:{}", uid));
+        self.lines.push(format!("DTSTAMP:{}", self.timestamp));
+        self.lines.push(format!("DTSTART{}", start));
+        self.lines.push(format!("DTEND{}", end));
+        self.lines.push(format!("SUMMARY:{}", escape(summary)));
+        if !description.is_empty() {
+            self.lines
+                .push(format!("DESCRIPTION:{}", escape(description)));
+        }
+        self.lines.push("END:VEVENT".to_string());
+    }
+
+    fn finish(mut self) -> String {
+        self.lines.push("END:VCALENDAR".to_string());
+        let mut calendar = String::new();
+        for line in &self.lines {
+            calendar.push_str(&fold(line));
+            calendar.push_str("\r\n");
+        }
+        calendar
+    }
+}
+
+/// A local time, without a time zone, so it is shown as it is wherever the calendar is opened.
+fn date_time(date_time: NaiveDateTime) -> String {
+    date_time.format(":%Y%m%dT%H%M%S").to_string()
+}
+
+/// A day, for events lasting all day.
+fn date(date: NaiveDate) -> String {
+    date.format(";VALUE=DATE:%Y%m%d").to_string()
+}
+
+fn item_event(calendar: &mut Calendar, item: &InvoiceItem) {
+    let summary = format!(
+        "Betreuung ({}-Woche)",
+        TYPE_OF_WEEK_NAME[item.type_of_week as usize]
+    );
+    let mut description = vec![];
+    if let Some(ref name) = item.substitutes_name {
+        description.push(format!("Vertretung für {}", name));
+    }
+    if !item.remark.is_empty() {
+        description.push(item.remark.clone());
+    }
+
+    calendar.event(
+        &format!("item-{}@time-report", item.id),
+        &date_time(item.day.and_time(item.start)),
+        &date_time(item.day.and_time(item.end)),
+        &summary,
+        &description.join("\n"),
+    );
+}
+
+/// All items of the employee the token belongs to, as an iCalendar document.
+pub fn employee_feed(conn: &SqliteConnection, token: &str) -> Result<String> {
+    use schema::items_view;
+
+    let employee_id = calendar_tokens::table
+        .filter(calendar_tokens::token.eq(token))
+        .select(calendar_tokens::employee_id)
+        .first::<i32>(conn)
+        .optional()
+        .chain_err(|| "Failed to look up calendar token")?
+        .ok_or("Unknown calendar")?;
+    let employee = employees::get_by_id(conn, employee_id)?;
+
+    let items = items_view::table
+        .filter(items_view::employee_id.eq(employee_id))
+        .order((items_view::day.asc(), items_view::start.asc()))
+        .load::<InvoiceItem>(conn)
+        .chain_err(|| format!("Failed to get items of employee #{}", employee_id))?;
+
+    let mut calendar = Calendar::new(&format!("Schichten {}", employee.name));
+    for item in &items {
+        item_event(&mut calendar, item);
+    }
+    Ok(calendar.finish())
+}
+
+/// Combine consecutive holidays with the same title into one event, e.g. for the holidays. As
+/// the holidays do not always include the weekends, they are ignored when looking for gaps.
+///
+/// Returns the first and last day and the title of each event.
+fn holiday_periods(holidays: &[(NaiveDate, String)]) -> Vec<(NaiveDate, NaiveDate, String)> {
+    let is_weekend =
+        |day: NaiveDate| day.weekday() == Weekday::Sat || day.weekday() == Weekday::Sun;
+
+    let mut periods: Vec<(NaiveDate, NaiveDate, String)> = vec![];
+    for &(day, ref title) in holidays {
+        let continues_last = periods.last().map_or(false, |last| {
+            let mut next = last.1.succ();
+            while next < day && is_weekend(next) {
+                next = next.succ();
+            }
+            next == day && last.2 == *title
+        });
+        if continues_last {
+            periods.last_mut().unwrap().1 = day;
+        } else {
+            periods.push((day, day, title.clone()));
+        }
+    }
+    periods
+}
+
+/// All school and general holidays, as an iCalendar document.
+pub fn holiday_feed(conn: &SqliteConnection) -> Result<String> {
+    let holidays = holidays::table
+        .select((holidays::date, holidays::title))
+        .order(holidays::date)
+        .load::<(String, String)>(conn)
+        .chain_err(|| "Failed to read holidays")?
+        .into_iter()
+        .map(|(day, title)| {
+            NaiveDate::parse_from_str(&day, DATE_FORMAT)
+                .map(|day| (day, title))
+                .chain_err(|| format!("Invalid date in holidays table: {}", day))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut calendar = Calendar::new("Ferien und Feiertage");
+    for (first, last, title) in holiday_periods(&holidays) {
+        let title = if title.is_empty() {
+            "Schulfrei".to_string()
+        } else {
+            title
+        };
+        calendar.event(
+            &format!("holiday-{}@time-report", first.format(DATE_FORMAT)),
+            &date(first),
+            // The end of an event lasting all day is the day after it.
+            &date(last.succ()),
+            &title,
+            "",
+        );
+    }
+    Ok(calendar.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("Ausflug"), "Ausflug");
+        assert_eq!(escape("Zoo, Eis; C:\\Bilder"), "Zoo\\, Eis\\; C:\\\\Bilder");
+        assert_eq!(escape("Zeile 1\r\nZeile 2"), "Zeile 1\\nZeile 2");
+    }
+
+    #[test]
+    fn test_fold() {
+        assert_eq!(fold("SUMMARY:Betreuung"), "SUMMARY:Betreuung");
+
+        let line = format!("DESCRIPTION:{}", "ä".repeat(40));
+        let folded = fold(&line);
+        let lines: Vec<_> = folded.split("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        // 12 bytes for the name, 31 umlauts of 2 bytes each fit into the first line.
+        assert_eq!(lines[0].len(), 74);
+        assert_eq!(lines[1], format!(" {}", "ä".repeat(9)));
+        assert_eq!(folded.replace("\r\n ", ""), line);
+    }
+
+    #[test]
+    fn test_calendar() {
+        let mut calendar = Calendar::new("Schichten Anna Schmidt");
+        calendar.event(
+            "item-1@time-report",
+            &date_time(NaiveDate::from_ymd(2018, 3, 5).and_hms(12, 0, 0)),
+            &date_time(NaiveDate::from_ymd(2018, 3, 5).and_hms(14, 30, 0)),
+            "Betreuung (A-Woche)",
+            "Zoo, Eis",
+        );
+        let calendar = calendar.finish();
+
+        assert!(calendar.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(calendar.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+        assert!(calendar.contains("\r\nDTSTART:20180305T120000\r\nDTEND:20180305T143000\r\n"));
+        assert!(calendar.contains("\r\nDESCRIPTION:Zoo\\, Eis\r\n"));
+    }
+
+    #[test]
+    fn test_holiday_periods() {
+        let day = |month, day| NaiveDate::from_ymd(2018, month, day);
+        let easter = "Osterferien".to_string();
+        let holidays = vec![
+            // Friday to Tuesday, without the weekend
+            (day(3, 23), easter.clone()),
+            (day(3, 26), easter.clone()),
+            (day(3, 27), easter.clone()),
+            (day(3, 29), easter.clone()),
+            (day(3, 30), "Karfreitag".to_string()),
+            (day(4, 2), "Ostermontag".to_string()),
+        ];
+        assert_eq!(
+            holiday_periods(&holidays),
+            vec![
+                (day(3, 23), day(3, 27), easter.clone()),
+                (day(3, 29), day(3, 29), easter.clone()),
+                (day(3, 30), day(3, 30), "Karfreitag".to_string()),
+                (day(4, 2), day(4, 2), "Ostermontag".to_string()),
+            ]
+        );
+        assert_eq!(date(day(3, 23)), ";VALUE=DATE:20180323");
+    }
+}
@@ -0,0 +1,176 @@
+use std::fs::File;
+
+use chrono::NaiveDate;
+
+use batch;
+use db;
+use dump;
+use errors::*;
+use generate_pdf;
+use import;
+use snapshots;
+use DATE_FORMAT;
+
+const USAGE: &str = "Usage:
+  time-report                                      Start the server
+  time-report export <from> <to> <file.zip>        Export all reports in a period
+  time-report export --school-year <year> <file.zip>
+  time-report dump <file.json>                     Save all tables as JSON
+  time-report load [--dry-run] <file.json>         Replace all tables with a saved dump
+  time-report import [--dry-run] <file.csv>        Import items in the layout of the CSV export
+  time-report snapshot [<reason>]                  Copy the database, e.g. before migrations
+  time-report snapshots                            List all snapshots, newest first
+  time-report restore <snapshot>                   Replace the database with a snapshot";
+
+fn parse_date(date: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, DATE_FORMAT)
+        .chain_err(|| format!("Invalid date, expected YYYY-MM-DD: {}", date))
+}
+
+fn export(args: &[String]) -> Result<()> {
+    if args.len() != 3 {
+        bail!(USAGE);
+    }
+    let (from, to) = if args[0] == "--school-year" {
+        let year = args[1]
+            .parse()
+            .chain_err(|| format!("Invalid year: {}", args[1]))?;
+        batch::school_year(year)
+    } else {
+        (parse_date(&args[0])?, parse_date(&args[1])?)
+    };
+    let path = &args[2];
+
+    // There is no worker to queue them for, so missing PDFs are generated right here.
+    let conn = db::connect();
+    for report in batch::missing_pdfs(&conn, from, to)? {
+        println!("Generating PDF of {}", report.title);
+        generate_pdf::generate(&conn, report.id)?;
+    }
+
+    let file = File::create(path).chain_err(|| format!("Failed to create {}", path))?;
+    batch::export(&conn, from, to, file).chain_err(|| format!("Failed to write {}", path))?;
+    println!("Wrote {}", path);
+    Ok(())
+}
+
+fn save_dump(args: &[String]) -> Result<()> {
+    if args.len() != 1 {
+        bail!(USAGE);
+    }
+    let path = &args[0];
+
+    let dump = dump::export(&db::connect())?;
+    let file = File::create(path).chain_err(|| format!("Failed to create {}", path))?;
+    ::serde_json::to_writer_pretty(file, &dump)
+        .chain_err(|| format!("Failed to write {}", path))?;
+    println!("Wrote {}", path);
+    Ok(())
+}
+
+fn load_dump(args: &[String]) -> Result<()> {
+    let (dry_run, path) = match args.len() {
+        1 => (false, &args[0]),
+        2 if args[0] == "--dry-run" => (true, &args[1]),
+        _ => bail!(USAGE),
+    };
+
+    let file = File::open(path).chain_err(|| format!("Failed to open {}", path))?;
+    let summary = dump::import(&db::connect(), &dump::read(file)?, dry_run)?;
+    for (table, rows) in &summary.rows {
+        println!("{:<20} {:>6} rows", table, rows);
+    }
+    if dry_run {
+        println!("Dry run, nothing was changed");
+    } else {
+        println!("Imported {}", path);
+    }
+    Ok(())
+}
+
+fn import_csv(args: &[String]) -> Result<()> {
+    let (dry_run, path) = match args.len() {
+        1 => (false, &args[0]),
+        2 if args[0] == "--dry-run" => (true, &args[1]),
+        _ => bail!(USAGE),
+    };
+
+    let file = File::open(path).chain_err(|| format!("Failed to open {}", path))?;
+    let report = import::csv(&db::connect(), &import::read(file)?, dry_run)?;
+    for row in &report.rows {
+        let day = row.day.map_or(String::new(), |day| day.to_string());
+        match row.error {
+            Some(ref error) => println!("{:>5} {} {}: {}", row.line, day, row.name, error),
+            None => println!("{:>5} {} {}: ok", row.line, day, row.name),
+        }
+        for warning in &row.warnings {
+            println!("      warning: {}", warning);
+        }
+    }
+    for name in &report.new_employees {
+        println!("New employee: {}", name);
+    }
+    for title in &report.new_reports {
+        println!("New report: {}", title);
+    }
+
+    if report.imported {
+        println!("Imported {} items", report.rows.len());
+    } else if dry_run {
+        println!("Dry run, nothing was changed");
+    } else {
+        bail!("Nothing was imported because of errors in some rows");
+    }
+    Ok(())
+}
+
+fn snapshot(args: &[String]) -> Result<()> {
+    let reason = match args.len() {
+        0 => "manual",
+        1 => args[0].as_str(),
+        _ => bail!(USAGE),
+    };
+    let snapshot = snapshots::take(&db::connect(), reason)?;
+    println!("Wrote {}", snapshot.name);
+    Ok(())
+}
+
+fn list_snapshots(args: &[String]) -> Result<()> {
+    if !args.is_empty() {
+        bail!(USAGE);
+    }
+    for snapshot in snapshots::list(&db::connect())? {
+        println!("{:<50} {:>12} bytes", snapshot.name, snapshot.size);
+    }
+    Ok(())
+}
+
+fn restore(args: &[String]) -> Result<()> {
+    if args.len() != 1 {
+        bail!(USAGE);
+    }
+    let path = snapshots::restore(&args[0])?;
+    println!("Restored {}", path.display());
+    Ok(())
+}
+
+/// Run the command given on the command line, if any.
+///
+/// Returns `false` if there is no command, in which case the server should be started.
+pub fn run(args: &[String]) -> Result<bool> {
+    match args.split_first() {
+        None => return Ok(false),
+        Some((command, args)) => match command.as_str() {
+            "export" => export(args)?,
+            "dump" => save_dump(args)?,
+            "load" => load_dump(args)?,
+            "import" => import_csv(args)?,
+            "snapshot" => snapshot(args)?,
+            "snapshots" => list_snapshots(args)?,
+            "restore" => restore(args)?,
+            "help" | "--help" | "-h" => println!("{}", USAGE),
+            _ => bail!("Unknown command: {}\n{}", command, USAGE),
+        },
+    }
+    Ok(true)
+}
@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, NaiveDate};
+use diesel::prelude::*;
+use diesel::SqliteConnection;
+
+use employees;
+use errors::*;
+use items::InvoiceItem;
+use money::{amount_cents, format_euro};
+use names;
+use rates;
+use settings;
+
+/// Setting for the monthly earnings limit of a Minijob, in cents.
+pub const MINIJOB_MONTHLY_LIMIT: &str = "minijob_monthly_limit_cents";
+/// Setting for the yearly earnings limit of a Minijob, in cents.
+pub const MINIJOB_YEARLY_LIMIT: &str = "minijob_yearly_limit_cents";
+/// Setting for the tax-free yearly amount of an Übungsleiter (Übungsleiterpauschale), in cents.
+pub const UEBUNGSLEITER_YEARLY_LIMIT: &str = "uebungsleiter_yearly_limit_cents";
+/// Setting for how many percent of a limit an employee has to reach to get a warning.
+pub const WARNING_THRESHOLD: &str = "limit_warning_percent";
+
+/// How much an employee worked and earned in a calendar year.
+#[derive(Debug, Serialize)]
+pub struct EmployeeEarnings {
+    pub employee_id: i32,
+    pub name: String,
+    pub contract_type: Option<String>,
+    pub minutes: i64,
+    pub amount_cents: i64,
+
+    /// Earnings in cents for each month, January first.
+    pub monthly_cents: Vec<i64>,
+
+    /// Number of items for which there was no hourly rate, and which are therefore missing from
+    /// the amounts.
+    pub items_without_rate: i32,
+}
+
+/// An employee getting close to or exceeding one of the earnings limits.
+#[derive(Debug, Serialize)]
+pub struct LimitWarning {
+    pub employee_id: i32,
+    pub name: String,
+
+    /// The period the limit applies to, either a year ("2018") or a month ("2018-03").
+    pub period: String,
+
+    pub amount_cents: i64,
+    pub limit_cents: i64,
+
+    /// Whether the limit was exceeded, as opposed to almost reached.
+    pub exceeded: bool,
+
+    /// Description of the problem, as it appears on the report.
+    pub message: String,
+}
+
+impl LimitWarning {
+    fn new(
+        employee: &EmployeeEarnings,
+        kind: &str,
+        period: String,
+        amount_cents: i64,
+        limit_cents: i64,
+    ) -> Self {
+        let exceeded = amount_cents > limit_cents;
+        let in_period = if period.len() == 4 { "im Jahr" } else { "im Monat" };
+        let status = if exceeded { "überschritten" } else { "fast erreicht" };
+        let message = format!(
+            "{} hat {} {} {} verdient und damit die Grenze von {} ({}) {}.",
+            employee.name,
+            in_period,
+            period,
+            format_euro(amount_cents),
+            format_euro(limit_cents),
+            kind,
+            status
+        );
+        LimitWarning {
+            employee_id: employee.employee_id,
+            name: employee.name.clone(),
+            period,
+            amount_cents,
+            limit_cents,
+            exceeded,
+            message,
+        }
+    }
+}
+
+/// Earnings of all employees in a calendar year and the limits they are getting close to.
+#[derive(Debug, Serialize)]
+pub struct ComplianceReport {
+    pub year: i32,
+    pub employees: Vec<EmployeeEarnings>,
+    pub warnings: Vec<LimitWarning>,
+}
+
+/// The configured limits in cents.
+struct Limits {
+    minijob_monthly: i64,
+    minijob_yearly: i64,
+    uebungsleiter_yearly: i64,
+    threshold_percent: i64,
+}
+
+impl Limits {
+    fn load(conn: &SqliteConnection) -> Result<Self> {
+        Ok(Limits {
+            minijob_monthly: settings::get(conn, MINIJOB_MONTHLY_LIMIT, 450_00)?,
+            minijob_yearly: settings::get(conn, MINIJOB_YEARLY_LIMIT, 5_400_00)?,
+            uebungsleiter_yearly: settings::get(conn, UEBUNGSLEITER_YEARLY_LIMIT, 2_400_00)?,
+            threshold_percent: settings::get(conn, WARNING_THRESHOLD, 90)?,
+        })
+    }
+
+    /// Is `amount` high enough to warn about it?
+    fn is_close(&self, amount: i64, limit: i64) -> bool {
+        amount * 100 >= limit * self.threshold_percent
+    }
+
+    /// Check the earnings of one employee against the limits of their contract type.
+    fn check(&self, year: i32, employee: &EmployeeEarnings) -> Vec<LimitWarning> {
+        let mut warnings = vec![];
+
+        match employee.contract_type.as_ref().map(String::as_str) {
+            Some("minijob") => {
+                for (month, &amount) in employee.monthly_cents.iter().enumerate() {
+                    if self.is_close(amount, self.minijob_monthly) {
+                        warnings.push(LimitWarning::new(
+                            employee,
+                            "Minijob",
+                            format!("{}-{:02}", year, month + 1),
+                            amount,
+                            self.minijob_monthly,
+                        ));
+                    }
+                }
+                if self.is_close(employee.amount_cents, self.minijob_yearly) {
+                    warnings.push(LimitWarning::new(
+                        employee,
+                        "Minijob",
+                        format!("{}", year),
+                        employee.amount_cents,
+                        self.minijob_yearly,
+                    ));
+                }
+            }
+            Some("uebungsleiter") => {
+                if self.is_close(employee.amount_cents, self.uebungsleiter_yearly) {
+                    warnings.push(LimitWarning::new(
+                        employee,
+                        "Übungsleiterpauschale",
+                        format!("{}", year),
+                        employee.amount_cents,
+                        self.uebungsleiter_yearly,
+                    ));
+                }
+            }
+            _ => {}
+        }
+
+        warnings
+    }
+}
+
+/// Sum up the earnings of every employee who worked in the given calendar year.
+fn earnings(conn: &SqliteConnection, year: i32) -> Result<Vec<EmployeeEarnings>> {
+    use schema::items_view;
+
+    let (first, last) = match (
+        NaiveDate::from_ymd_opt(year, 1, 1),
+        NaiveDate::from_ymd_opt(year, 12, 31),
+    ) {
+        (Some(first), Some(last)) => (first, last),
+        _ => bail!("Invalid year: {}", year),
+    };
+    let items = items_view::table
+        .filter(items_view::day.ge(first))
+        .filter(items_view::day.le(last))
+        .order(items_view::name_sort.asc())
+        .load::<InvoiceItem>(conn)
+        .chain_err(|| format!("Failed to query items in {}", year))?;
+
+    let mut result: BTreeMap<i32, EmployeeEarnings> = BTreeMap::new();
+    let mut all_rates = BTreeMap::new();
+    let mut sort_keys = BTreeMap::new();
+
+    for item in items {
+        if !result.contains_key(&item.employee_id) {
+            let employee = employees::get_by_id(conn, item.employee_id)?;
+            all_rates.insert(employee.id, rates::get_for_employee(conn, employee.id)?);
+            sort_keys.insert(employee.id, employee.name_sort);
+            result.insert(
+                employee.id,
+                EmployeeEarnings {
+                    employee_id: employee.id,
+                    name: employee.name,
+                    contract_type: employee.contract_type,
+                    minutes: 0,
+                    amount_cents: 0,
+                    monthly_cents: vec![0; 12],
+                    items_without_rate: 0,
+                },
+            );
+        }
+
+        let earnings = result.get_mut(&item.employee_id).unwrap();
+        let minutes = item.end.signed_duration_since(item.start).num_minutes();
+        earnings.minutes += minutes;
+
+        match rates::rate_on(&all_rates[&item.employee_id], item.day) {
+            Some(rate) => {
+                let amount = amount_cents(rate.rate_cents, minutes);
+                earnings.amount_cents += amount;
+                earnings.monthly_cents[item.day.month0() as usize] += amount;
+            }
+            None => earnings.items_without_rate += 1,
+        }
+    }
+
+    let mut result: Vec<_> = result.into_iter().map(|(_, earnings)| earnings).collect();
+    result.sort_by(|a, b| names::compare(&sort_keys[&a.employee_id], &sort_keys[&b.employee_id]));
+    Ok(result)
+}
+
+/// Check the earnings of all employees in a calendar year against the configured limits.
+pub fn check(conn: &SqliteConnection, year: i32) -> Result<ComplianceReport> {
+    let limits = Limits::load(conn)?;
+    let employees = earnings(conn, year)?;
+
+    let warnings = employees
+        .iter()
+        .flat_map(|employee| limits.check(year, employee))
+        .collect();
+
+    Ok(ComplianceReport {
+        year,
+        employees,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            minijob_monthly: 450_00,
+            minijob_yearly: 5_400_00,
+            uebungsleiter_yearly: 2_400_00,
+            threshold_percent: 90,
+        }
+    }
+
+    fn earnings(contract_type: Option<&str>, monthly_cents: Vec<i64>) -> EmployeeEarnings {
+        EmployeeEarnings {
+            employee_id: 1,
+            name: "Anna Schmidt".to_string(),
+            contract_type: contract_type.map(str::to_string),
+            minutes: 0,
+            amount_cents: monthly_cents.iter().sum(),
+            monthly_cents,
+            items_without_rate: 0,
+        }
+    }
+
+    #[test]
+    fn test_is_close() {
+        let limits = limits();
+        assert!(!limits.is_close(404_99, 450_00));
+        assert!(limits.is_close(405_00, 450_00));
+        assert!(limits.is_close(450_00, 450_00));
+        assert!(limits.is_close(500_00, 450_00));
+        assert!(!limits.is_close(0, 450_00));
+
+        let strict = Limits {
+            threshold_percent: 100,
+            ..limits
+        };
+        assert!(!strict.is_close(449_99, 450_00));
+        assert!(strict.is_close(450_00, 450_00));
+    }
+
+    #[test]
+    fn test_minijob() {
+        let mut monthly = vec![300_00; 12];
+        monthly[2] = 420_00;
+        monthly[5] = 460_00;
+        let warnings = limits().check(2018, &earnings(Some("minijob"), monthly));
+
+        let periods: Vec<_> = warnings.iter().map(|w| w.period.as_str()).collect();
+        assert_eq!(periods, vec!["2018-03", "2018-06"]);
+        assert!(!warnings[0].exceeded);
+        assert!(warnings[1].exceeded);
+        assert_eq!(warnings[1].limit_cents, 450_00);
+        assert!(warnings[1].message.contains("im Monat 2018-06"));
+
+        let warnings = limits().check(2018, &earnings(Some("minijob"), vec![440_00; 12]));
+        assert_eq!(warnings.len(), 13);
+        let yearly = warnings.last().unwrap();
+        assert_eq!(yearly.period, "2018");
+        assert_eq!(yearly.amount_cents, 5_280_00);
+        assert!(!yearly.exceeded);
+    }
+
+    #[test]
+    fn test_uebungsleiter() {
+        let mut monthly = vec![0; 12];
+        monthly[0] = 2_200_00;
+        let warnings = limits().check(2018, &earnings(Some("uebungsleiter"), monthly));
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].period, "2018");
+        assert_eq!(warnings[0].limit_cents, 2_400_00);
+
+        // There is no monthly limit, and other contracts have no limits at all.
+        let limits = limits();
+        let high = vec![1_000_00; 12];
+        assert!(limits.check(2018, &earnings(None, high.clone())).is_empty());
+        let other = earnings(Some("other"), high);
+        assert!(limits.check(2018, &other).is_empty());
+    }
+
+    #[test]
+    fn test_invalid_year() {
+        let conn = ::db::test_connection();
+        assert!(check(&conn, 2018).unwrap().employees.is_empty());
+        assert!(check(&conn, i32::max_value()).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use std::cmp;
+use std::collections::HashMap;
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveTime};
+use diesel::prelude::*;
+use diesel::{self, SqliteConnection};
+
+use errors::*;
+use holidays;
+use items::InvoiceItem;
+use schema::coverage_minimums;
+use settings;
+use weeks::{self, TYPE_OF_WEEK_NAME, WEEKDAY_NAME};
+use {MAX_TIME, MIN_TIME, TIME_FORMAT};
+
+/// Setting for the number of staff required when there is no entry in `coverage_minimums`.
+pub const DEFAULT_MINIMUM: &str = "coverage_minimum";
+
+/// Length of the time slots the supervision window is divided into.
+const SLOT_MINUTES: i64 = 30;
+
+/// How many employees have to be on duty on a certain day of the week in a certain type of week.
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "coverage_minimums"]
+pub struct Minimum {
+    /// Day of the week, starting with 0 for Monday.
+    pub weekday: i32,
+    /// Type of week, starting with 0 for A.
+    pub type_of_week: i32,
+    pub minimum: i32,
+}
+
+/// All minimums that differ from the default.
+pub fn get_minimums(conn: &SqliteConnection) -> Result<Vec<Minimum>> {
+    coverage_minimums::table
+        .order((coverage_minimums::weekday, coverage_minimums::type_of_week))
+        .load(conn)
+        .chain_err(|| "Failed to read table coverage_minimums")
+}
+
+pub fn set_minimum(conn: &SqliteConnection, minimum: &Minimum) -> Result<()> {
+    if minimum.weekday < 0 || minimum.weekday as usize >= WEEKDAY_NAME.len() {
+        bail!("Invalid day of the week: {}", minimum.weekday);
+    }
+    if minimum.type_of_week < 0 || minimum.type_of_week as usize >= TYPE_OF_WEEK_NAME.len() {
+        bail!("Invalid type of week: {}", minimum.type_of_week);
+    }
+    if minimum.minimum < 0 {
+        bail!("The minimum cannot be negative: {}", minimum.minimum);
+    }
+
+    diesel::replace_into(coverage_minimums::table)
+        .values(minimum)
+        .execute(conn)
+        .map(|_| ())
+        .chain_err(|| format!("Failed to set coverage minimum {:?}", minimum))
+}
+
+/// Use the default minimum for a day of the week and type of week again.
+pub fn reset_minimum(conn: &SqliteConnection, weekday: i32, type_of_week: i32) -> Result<()> {
+    let minimum = coverage_minimums::table
+        .filter(coverage_minimums::weekday.eq(weekday))
+        .filter(coverage_minimums::type_of_week.eq(type_of_week));
+    diesel::delete(minimum)
+        .execute(conn)
+        .map(|_| ())
+        .chain_err(|| "Failed to reset coverage minimum")
+}
+
+/// The configured minimums, for looking them up quickly.
+struct Minimums {
+    minimums: HashMap<(i32, i32), i32>,
+    default: i32,
+}
+
+impl Minimums {
+    fn load(conn: &SqliteConnection) -> Result<Self> {
+        let minimums = get_minimums(conn)?
+            .into_iter()
+            .map(|m| ((m.weekday, m.type_of_week), m.minimum))
+            .collect();
+        Ok(Minimums {
+            minimums,
+            default: settings::get(conn, DEFAULT_MINIMUM, 1)?,
+        })
+    }
+
+    fn get(&self, weekday: i32, type_of_week: i32) -> i32 {
+        self.minimums
+            .get(&(weekday, type_of_week))
+            .cloned()
+            .unwrap_or(self.default)
+    }
+}
+
+/// A time during which fewer employees than required are on duty.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Gap {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Number of employees on duty for the whole time.
+    pub staff: usize,
+}
+
+/// A school day with at least one gap.
+#[derive(Debug, Serialize)]
+pub struct DayCoverage {
+    pub date: NaiveDate,
+    pub weekday: String,
+    pub type_of_week: String,
+    pub minimum: i32,
+    /// Number of employees on duty at any time of the day.
+    pub staff: usize,
+    pub gaps: Vec<Gap>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CoverageReport {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    /// Number of school days checked.
+    pub school_days: usize,
+    /// The days without enough staff.
+    pub days: Vec<DayCoverage>,
+}
+
+/// A shift of an employee: their id and when they started and stopped.
+type Shift = (i32, NaiveTime, NaiveTime);
+
+fn staff_count<'a, I: Iterator<Item = &'a Shift>>(shifts: I) -> usize {
+    let mut employees: Vec<_> = shifts.map(|shift| shift.0).collect();
+    employees.sort();
+    employees.dedup();
+    employees.len()
+}
+
+/// Find the parts of the window in which fewer than `minimum` employees are on duty.
+///
+/// The window is checked in slots of `SLOT_MINUTES`, an employee only counts for a slot if they
+/// are there for all of it. Adjacent slots with the same number of staff are merged.
+fn gaps(shifts: &[Shift], minimum: i32, window: (NaiveTime, NaiveTime)) -> Vec<Gap> {
+    let mut gaps: Vec<Gap> = vec![];
+    let mut start = window.0;
+
+    while start < window.1 {
+        let end = cmp::min(start + Duration::minutes(SLOT_MINUTES), window.1);
+        let staff = staff_count(
+            shifts
+                .iter()
+                .filter(|shift| shift.1 <= start && shift.2 >= end),
+        );
+
+        if (staff as i32) < minimum {
+            let extends_last = gaps
+                .last()
+                .map_or(false, |gap| gap.end == start && gap.staff == staff);
+            if extends_last {
+                gaps.last_mut().unwrap().end = end;
+            } else {
+                gaps.push(Gap { start, end, staff });
+            }
+        }
+        start = end;
+    }
+
+    gaps
+}
+
+/// Check every school day between `from` and `to` (inclusive) for times without enough staff.
+///
+/// All items count, including those entered in advance for days that have not happened yet.
+pub fn check(conn: &SqliteConnection, from: NaiveDate, to: NaiveDate) -> Result<CoverageReport> {
+    use schema::items_view;
+
+    if from > to {
+        bail!("The period ends before it starts: {} to {}", from, to);
+    }
+
+    let parse_time = |time| NaiveTime::parse_from_str(time, TIME_FORMAT).unwrap();
+    let window = (parse_time(MIN_TIME), parse_time(MAX_TIME));
+    let minimums = Minimums::load(conn)?;
+
+    let mut shifts: HashMap<NaiveDate, Vec<Shift>> = HashMap::new();
+    for item in items_view::table
+        .filter(items_view::day.ge(from))
+        .filter(items_view::day.le(to))
+        .load::<InvoiceItem>(conn)
+        .chain_err(|| "Failed to query items for coverage")?
+    {
+        shifts.entry(item.day).or_insert_with(Vec::new).push((
+            item.employee_id,
+            item.start,
+            item.end,
+        ));
+    }
+
+    let mut school_days = 0;
+    let mut days = vec![];
+    let mut day = holidays::next_schoolday(from.pred());
+    while day <= to {
+        school_days += 1;
+
+        let type_of_week = weeks::find_type_of_week(day)
+            .ok_or_else(|| format!("The type of week of {} is not known yet", day))?;
+        let weekday = day.weekday().num_days_from_monday() as i32;
+        let minimum = minimums.get(weekday, type_of_week);
+        let day_shifts = shifts.get(&day).map(Vec::as_slice).unwrap_or(&[]);
+
+        let gaps = gaps(day_shifts, minimum, window);
+        if !gaps.is_empty() {
+            days.push(DayCoverage {
+                date: day,
+                weekday: WEEKDAY_NAME[weekday as usize].to_string(),
+                type_of_week: TYPE_OF_WEEK_NAME[type_of_week as usize].to_string(),
+                minimum,
+                staff: staff_count(day_shifts.iter()),
+                gaps,
+            });
+        }
+
+        day = holidays::next_schoolday(day);
+    }
+
+    Ok(CoverageReport {
+        from,
+        to,
+        school_days,
+        days,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms(hour, minute, 0)
+    }
+
+    fn window() -> (NaiveTime, NaiveTime) {
+        (time(12, 0), time(16, 0))
+    }
+
+    #[test]
+    fn test_no_staff() {
+        assert_eq!(
+            gaps(&[], 1, window()),
+            vec![Gap {
+                start: time(12, 0),
+                end: time(16, 0),
+                staff: 0,
+            }]
+        );
+        assert!(gaps(&[], 0, window()).is_empty());
+    }
+
+    #[test]
+    fn test_gaps() {
+        let shifts = [
+            (1, time(12, 0), time(14, 0)),
+            (2, time(13, 0), time(16, 0)),
+            // The same employee twice does not count as two.
+            (2, time(13, 0), time(14, 0)),
+            // Only part of a slot does not count.
+            (3, time(15, 0), time(15, 45)),
+        ];
+        assert!(gaps(&shifts, 1, window()).is_empty());
+        assert_eq!(
+            gaps(&shifts, 2, window()),
+            vec![
+                Gap {
+                    start: time(12, 0),
+                    end: time(13, 0),
+                    staff: 1,
+                },
+                Gap {
+                    start: time(14, 0),
+                    end: time(15, 0),
+                    staff: 1,
+                },
+                Gap {
+                    start: time(15, 30),
+                    end: time(16, 0),
+                    staff: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_staff_count() {
+        let shifts = [
+            (1, time(12, 0), time(14, 0)),
+            (1, time(15, 0), time(16, 0)),
+            (2, time(13, 0), time(16, 0)),
+        ];
+        assert_eq!(staff_count(shifts.iter()), 2);
+    }
+}
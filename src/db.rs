@@ -1,6 +1,7 @@
 // Taken from https://rocket.rs/guide/state/#databases.
 
 use std::ops::Deref;
+use std::path::Path;
 
 use diesel::{Connection, SqliteConnection};
 use r2d2_diesel::ConnectionManager;
@@ -36,7 +37,7 @@ impl Deref for DbConn {
     }
 }
 
-type Pool = ::r2d2::Pool<ConnectionManager<SqliteConnection>>;
+pub type Pool = ::r2d2::Pool<ConnectionManager<SqliteConnection>>;
 
 pub fn init_pool() -> Pool {
     let manager = ConnectionManager::<SqliteConnection>::new(&*DATABASE_URL);
@@ -47,3 +48,44 @@ pub fn connect() -> SqliteConnection {
     SqliteConnection::establish(DATABASE_URL)
         .expect(&format!("Error connecting to {}", DATABASE_URL))
 }
+
+/// Location of the database file.
+pub fn path() -> &'static Path {
+    Path::new(DATABASE_URL)
+}
+
+/// A new, empty database in memory with all migrations applied.
+///
+/// Unlike `connect`, every call returns a separate database, so tests can run in parallel without
+/// seeing each other's data.
+#[cfg(test)]
+pub fn test_connection() -> SqliteConnection {
+    test_database(":memory:")
+}
+
+/// Create a database at `url` with all migrations applied, for tests which need a file, e.g. to
+/// take snapshots.
+#[cfg(test)]
+pub fn test_database(url: &str) -> SqliteConnection {
+    use diesel::connection::SimpleConnection;
+    use std::fs;
+    use std::io::Read;
+
+    let conn = SqliteConnection::establish(url).expect("Error creating test database");
+
+    let mut migrations: Vec<_> = fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+        .expect("Error listing migrations")
+        .map(|entry| entry.expect("Error listing migrations").path())
+        .filter(|path| path.is_dir())
+        .collect();
+    migrations.sort();
+    for migration in migrations {
+        let mut sql = String::new();
+        fs::File::open(migration.join("up.sql"))
+            .and_then(|mut file| file.read_to_string(&mut sql))
+            .expect("Error reading migration");
+        conn.batch_execute(&sql)
+            .unwrap_or_else(|e| panic!("Error running {}: {}", migration.display(), e));
+    }
+    conn
+}
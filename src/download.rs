@@ -0,0 +1,152 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use tempdir::TempDir;
+
+use errors::*;
+
+/// A file the browser should save under the given name instead of displaying it.
+pub struct Download<B = Cursor<Vec<u8>>> {
+    pub filename: String,
+    pub content_type: ContentType,
+    pub body: B,
+}
+
+impl Download {
+    pub fn new(filename: String, content_type: ContentType, data: Vec<u8>) -> Self {
+        Download::from_body(filename, content_type, Cursor::new(data))
+    }
+}
+
+impl<B: Read + Seek> Download<B> {
+    /// A download that is streamed from `body`, e.g. a `TempFile`, instead of held in memory.
+    pub fn from_body(filename: String, content_type: ContentType, body: B) -> Self {
+        Download {
+            filename,
+            content_type,
+            body,
+        }
+    }
+}
+
+/// A file in a temporary directory of its own, which is removed as soon as the file is dropped,
+/// e.g. after it has been sent.
+pub struct TempFile {
+    // Fields are dropped in order, so the file is closed before the directory is removed.
+    file: File,
+    _dir: TempDir,
+}
+
+impl TempFile {
+    pub fn create(name: &str) -> Result<Self> {
+        let dir = TempDir::new(name).chain_err(|| "Failed to create temporary directory")?;
+        let path = dir.path().join(name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .chain_err(|| format!("Failed to create {:?}", path))?;
+        Ok(TempFile { file, _dir: dir })
+    }
+}
+
+impl Read for TempFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for TempFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for TempFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+/// Value of the `Content-Disposition` header for downloading a file.
+///
+/// Browsers that do not understand the RFC 5987 `filename*` parameter get a plain ASCII version
+/// of the name, with everything else replaced by underscores.
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '...'~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+impl<'r, B: Read + Seek + 'r> Responder<'r> for Download<B> {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(self.content_type)
+            .raw_header("Content-Disposition", content_disposition(&self.filename))
+            .sized_body(self.body)
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("report.csv"),
+            "attachment; filename=\"report.csv\"; filename*=UTF-8''report.csv"
+        );
+        assert_eq!(
+            content_disposition("März 2018.ods"),
+            "attachment; filename=\"M_rz 2018.ods\"; filename*=UTF-8''M%C3%A4rz%202018.ods"
+        );
+        assert_eq!(
+            content_disposition("a\"b.csv"),
+            "attachment; filename=\"a_b.csv\"; filename*=UTF-8''a%22b.csv"
+        );
+    }
+
+    #[test]
+    fn test_temp_file() {
+        let mut file = TempFile::create("download").unwrap();
+        let path = file._dir.path().to_path_buf();
+        file.write_all(b"PK").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut data = vec![];
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"PK");
+        assert!(path.exists());
+        drop(file);
+        assert!(!path.exists());
+    }
+}
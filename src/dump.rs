@@ -0,0 +1,573 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::io::Read;
+
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::result::Error::RollbackTransaction;
+use diesel::{self, SqliteConnection};
+use serde_json;
+
+use calendar::CalendarToken;
+use coverage::Minimum;
+use errors::*;
+use holidays;
+use reports::Report;
+use schema::{
+    absences, calendar_tokens, coverage_minimums, employee_merges, employees,
+    holidays as holiday_table, hourly_rates, items, pdf_archive, pdf_jobs, reports, settings,
+    templates, weeks as week_table,
+};
+use settings::Setting;
+use snapshots;
+use weeks;
+
+/// Version of the dump format. Increase it whenever a table changes, and make `import` convert
+/// older dumps.
+///
+/// - 1: initial format
+/// - 2: added `calendar_tokens`
+pub const VERSION: u32 = 2;
+
+/// Oldest version `import` still accepts.
+pub const MIN_VERSION: u32 = 1;
+
+/// Largest dump accepted for import, in bytes.
+pub const MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "employees"]
+pub struct EmployeeRow {
+    pub id: i32,
+    pub name: String,
+    pub name_sort: String,
+    pub contract_type: Option<String>,
+    pub employment_start: Option<String>,
+    pub employment_end: Option<String>,
+    pub active: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "hourly_rates"]
+pub struct HourlyRateRow {
+    pub id: i32,
+    pub employee_id: i32,
+    pub effective_from: String,
+    pub rate_cents: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "absences"]
+pub struct AbsenceRow {
+    pub id: i32,
+    pub employee_id: i32,
+    pub start_date: String,
+    pub end_date: String,
+    pub reason: String,
+    pub remark: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "items"]
+pub struct ItemRow {
+    pub id: i32,
+    pub report_id: i32,
+    pub employee_id: i32,
+    pub start_datetime: String,
+    pub end_datetime: String,
+    pub remark: Option<String>,
+    pub substitutes_employee_id: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "holiday_table"]
+pub struct HolidayRow {
+    pub date: String,
+    pub title: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "week_table"]
+pub struct WeekRow {
+    pub year: i32,
+    pub week_of_year: i32,
+    pub type_of_week: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "templates"]
+pub struct TemplateRow {
+    pub name: String,
+    pub content: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "employee_merges"]
+pub struct MergeRow {
+    pub id: i32,
+    pub target_id: i32,
+    pub merged_at: String,
+    pub undo_data: String,
+    pub undone: bool,
+}
+
+/// The contents of all tables, except for the PDF archive and job queue.
+///
+/// Archived PDFs are files in the data directory, which a dump cannot restore, so their entries
+/// are left alone by `import`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Dump {
+    pub version: u32,
+    pub created_at: String,
+    pub employees: Vec<EmployeeRow>,
+    pub hourly_rates: Vec<HourlyRateRow>,
+    pub absences: Vec<AbsenceRow>,
+    pub reports: Vec<Report>,
+    pub items: Vec<ItemRow>,
+    pub holidays: Vec<HolidayRow>,
+    pub weeks: Vec<WeekRow>,
+    pub settings: Vec<Setting>,
+    pub templates: Vec<TemplateRow>,
+    pub employee_merges: Vec<MergeRow>,
+    pub coverage_minimums: Vec<Minimum>,
+    /// Missing in version 1.
+    #[serde(default)]
+    pub calendar_tokens: Vec<CalendarToken>,
+}
+
+/// What an import did, or would have done in a dry run.
+#[derive(Debug, Serialize)]
+pub struct ImportSummary {
+    pub dry_run: bool,
+    /// Number of rows imported into each table.
+    pub rows: BTreeMap<&'static str, usize>,
+}
+
+/// Read all tables in one transaction, so the dump is consistent even while the server is
+/// writing to the database.
+pub fn export(conn: &SqliteConnection) -> Result<Dump> {
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        Ok(Dump {
+            version: VERSION,
+            created_at: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            employees: employees::table.order(employees::id).load(conn)?,
+            hourly_rates: hourly_rates::table.order(hourly_rates::id).load(conn)?,
+            absences: absences::table.order(absences::id).load(conn)?,
+            reports: reports::table.order(reports::id).load(conn)?,
+            items: items::table.order(items::id).load(conn)?,
+            holidays: holiday_table::table.order(holiday_table::date).load(conn)?,
+            weeks: week_table::table
+                .order((week_table::year, week_table::week_of_year))
+                .load(conn)?,
+            settings: settings::table.order(settings::key).load(conn)?,
+            templates: templates::table.order(templates::name).load(conn)?,
+            employee_merges: employee_merges::table
+                .order(employee_merges::id)
+                .load(conn)?,
+            coverage_minimums: coverage_minimums::table
+                .order((coverage_minimums::weekday, coverage_minimums::type_of_week))
+                .load(conn)?,
+            calendar_tokens: calendar_tokens::table
+                .order(calendar_tokens::employee_id)
+                .load(conn)?,
+        })
+    }).chain_err(|| "Failed to export database")
+}
+
+/// Name of the file to save a dump as, e.g. `time-report-2018-05-08.json`.
+pub fn filename(dump: &Dump) -> String {
+    let date = dump.created_at.split(' ').next().unwrap();
+    format!("time-report-{}.json", date)
+}
+
+/// Parse a dump, refusing anything larger than `MAX_SIZE`.
+pub fn read<R: Read>(reader: R) -> Result<Dump> {
+    let mut data = vec![];
+    reader
+        .take(MAX_SIZE + 1)
+        .read_to_end(&mut data)
+        .chain_err(|| "Failed to read dump")?;
+    if data.len() as u64 > MAX_SIZE {
+        bail!("The dump is larger than {} bytes", MAX_SIZE);
+    }
+    serde_json::from_slice(&data).chain_err(|| "Invalid dump")
+}
+
+/// Report every key that appears more than once.
+fn check_unique<K: Ord + Display>(table: &str, keys: Vec<K>, problems: &mut Vec<String>) {
+    let mut seen = BTreeSet::new();
+    for key in keys {
+        if seen.contains(&key) {
+            problems.push(format!("{}: duplicate key {}", table, key));
+        } else {
+            seen.insert(key);
+        }
+    }
+}
+
+/// Find references to rows that are not part of the dump, and duplicate keys.
+pub fn check(dump: &Dump) -> Vec<String> {
+    let mut problems = vec![];
+
+    if dump.version < MIN_VERSION || dump.version > VERSION {
+        problems.push(format!(
+            "Unsupported dump version {}, expected {} to {}",
+            dump.version, MIN_VERSION, VERSION
+        ));
+        return problems;
+    }
+
+    let employees = dump.employees.iter().map(|e| e.id).collect::<BTreeSet<_>>();
+    let reports = dump.reports.iter().map(|r| r.id).collect::<BTreeSet<_>>();
+    {
+        let mut check_employee = |table: &str, id: i32, employee_id: i32| {
+            if !employees.contains(&employee_id) {
+                problems.push(format!(
+                    "{} #{}: unknown employee #{}",
+                    table, id, employee_id
+                ));
+            }
+        };
+
+        for rate in &dump.hourly_rates {
+            check_employee("hourly_rates", rate.id, rate.employee_id);
+        }
+        for absence in &dump.absences {
+            check_employee("absences", absence.id, absence.employee_id);
+        }
+        for merge in &dump.employee_merges {
+            check_employee("employee_merges", merge.id, merge.target_id);
+        }
+        for token in &dump.calendar_tokens {
+            check_employee("calendar_tokens", token.employee_id, token.employee_id);
+        }
+        for item in &dump.items {
+            check_employee("items", item.id, item.employee_id);
+            if let Some(substitute) = item.substitutes_employee_id {
+                check_employee("items", item.id, substitute);
+            }
+        }
+    }
+
+    for item in &dump.items {
+        if !reports.contains(&item.report_id) {
+            problems.push(format!(
+                "items #{}: unknown report #{}",
+                item.id, item.report_id
+            ));
+        }
+        if item.substitutes_employee_id == Some(item.employee_id) {
+            problems.push(format!(
+                "items #{}: employee #{} cannot substitute themselves",
+                item.id, item.employee_id
+            ));
+        }
+    }
+
+    check_unique(
+        "employees",
+        dump.employees.iter().map(|e| e.id).collect(),
+        &mut problems,
+    );
+    check_unique(
+        "employees",
+        dump.employees.iter().map(|e| &e.name).collect(),
+        &mut problems,
+    );
+    check_unique(
+        "hourly_rates",
+        dump.hourly_rates.iter().map(|r| r.id).collect(),
+        &mut problems,
+    );
+    check_unique(
+        "absences",
+        dump.absences.iter().map(|a| a.id).collect(),
+        &mut problems,
+    );
+    check_unique(
+        "reports",
+        dump.reports.iter().map(|r| r.id).collect(),
+        &mut problems,
+    );
+    check_unique(
+        "items",
+        dump.items.iter().map(|i| i.id).collect(),
+        &mut problems,
+    );
+    check_unique(
+        "holidays",
+        dump.holidays.iter().map(|h| &h.date).collect(),
+        &mut problems,
+    );
+    check_unique(
+        "weeks",
+        dump.weeks
+            .iter()
+            .map(|w| format!("{}/{}", w.year, w.week_of_year))
+            .collect(),
+        &mut problems,
+    );
+    check_unique(
+        "settings",
+        dump.settings.iter().map(|s| &s.key).collect(),
+        &mut problems,
+    );
+    check_unique(
+        "templates",
+        dump.templates.iter().map(|t| &t.name).collect(),
+        &mut problems,
+    );
+    check_unique(
+        "employee_merges",
+        dump.employee_merges.iter().map(|m| m.id).collect(),
+        &mut problems,
+    );
+    check_unique(
+        "calendar_tokens",
+        dump.calendar_tokens.iter().map(|t| t.employee_id).collect(),
+        &mut problems,
+    );
+    check_unique(
+        "calendar_tokens",
+        dump.calendar_tokens.iter().map(|t| &t.token).collect(),
+        &mut problems,
+    );
+
+    problems
+}
+
+/// Replace the contents of all tables in the dump.
+///
+/// The archive of generated PDFs is not part of the dump. It is cleared as well, since report ids
+/// are reused by the dump and the old PDFs would be taken for the new reports.
+fn replace_all(conn: &SqliteConnection, dump: &Dump) -> Result<()> {
+    // Delete in reverse order of the references between the tables.
+    diesel::delete(pdf_jobs::table).execute(conn)?;
+    diesel::delete(pdf_archive::table).execute(conn)?;
+    diesel::delete(items::table).execute(conn)?;
+    diesel::delete(reports::table).execute(conn)?;
+    diesel::delete(employee_merges::table).execute(conn)?;
+    diesel::delete(calendar_tokens::table).execute(conn)?;
+    diesel::delete(absences::table).execute(conn)?;
+    diesel::delete(hourly_rates::table).execute(conn)?;
+    diesel::delete(employees::table).execute(conn)?;
+    diesel::delete(holiday_table::table).execute(conn)?;
+    diesel::delete(week_table::table).execute(conn)?;
+    diesel::delete(settings::table).execute(conn)?;
+    diesel::delete(templates::table).execute(conn)?;
+    diesel::delete(coverage_minimums::table).execute(conn)?;
+
+    diesel::insert_into(holiday_table::table)
+        .values(&dump.holidays)
+        .execute(conn)?;
+    diesel::insert_into(week_table::table)
+        .values(&dump.weeks)
+        .execute(conn)?;
+    diesel::insert_into(settings::table)
+        .values(&dump.settings)
+        .execute(conn)?;
+    diesel::insert_into(templates::table)
+        .values(&dump.templates)
+        .execute(conn)?;
+    diesel::insert_into(employees::table)
+        .values(&dump.employees)
+        .execute(conn)?;
+    diesel::insert_into(hourly_rates::table)
+        .values(&dump.hourly_rates)
+        .execute(conn)?;
+    diesel::insert_into(absences::table)
+        .values(&dump.absences)
+        .execute(conn)?;
+    diesel::insert_into(employee_merges::table)
+        .values(&dump.employee_merges)
+        .execute(conn)?;
+    diesel::insert_into(calendar_tokens::table)
+        .values(&dump.calendar_tokens)
+        .execute(conn)?;
+    diesel::insert_into(reports::table)
+        .values(&dump.reports)
+        .execute(conn)?;
+    diesel::insert_into(items::table)
+        .values(&dump.items)
+        .execute(conn)?;
+    diesel::insert_into(coverage_minimums::table)
+        .values(&dump.coverage_minimums)
+        .execute(conn)?;
+
+    // Inserting the items bumped the revisions of the reports.
+    for report in &dump.reports {
+        diesel::update(reports::table.find(report.id))
+            .set(reports::revision.eq(report.revision))
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Run `replace_all` in a transaction, which is rolled back with `dry_run`.
+fn replace(conn: &SqliteConnection, dump: &Dump, dry_run: bool) -> Result<()> {
+    let result = conn.transaction::<_, Error, _>(|| {
+        replace_all(conn, dump)?;
+        if dry_run {
+            Err(RollbackTransaction.into())
+        } else {
+            Ok(())
+        }
+    });
+    match result {
+        Ok(()) | Err(Error(ErrorKind::Diesel(RollbackTransaction), _)) => Ok(()),
+        Err(e) => Err(e).chain_err(|| "Failed to import dump"),
+    }
+}
+
+/// Replace the whole database with the contents of a dump.
+///
+/// Nothing is changed if the dump is inconsistent or any row is rejected by the database. With
+/// `dry_run`, all changes are rolled back at the end, so this only checks whether the import
+/// would succeed. Otherwise, a snapshot of the old database is taken once the dump has passed
+/// these checks. Archived PDFs are dropped, but stay in the snapshot.
+pub fn import(conn: &SqliteConnection, dump: &Dump, dry_run: bool) -> Result<ImportSummary> {
+    let problems = check(dump);
+    if !problems.is_empty() {
+        bail!("The dump is inconsistent: {}", problems.join("; "));
+    }
+
+    // Try the import first, so a dump the database rejects does not cost a snapshot.
+    replace(conn, dump, true)?;
+    if !dry_run {
+        snapshots::take(conn, "import")?;
+        replace(conn, dump, false)?;
+        holidays::reload(conn);
+        weeks::reload(conn);
+        info!("Imported dump created at {}", dump.created_at);
+    }
+
+    let mut rows = BTreeMap::new();
+    rows.insert("employees", dump.employees.len());
+    rows.insert("hourly_rates", dump.hourly_rates.len());
+    rows.insert("absences", dump.absences.len());
+    rows.insert("reports", dump.reports.len());
+    rows.insert("items", dump.items.len());
+    rows.insert("holidays", dump.holidays.len());
+    rows.insert("weeks", dump.weeks.len());
+    rows.insert("settings", dump.settings.len());
+    rows.insert("templates", dump.templates.len());
+    rows.insert("employee_merges", dump.employee_merges.len());
+    rows.insert("coverage_minimums", dump.coverage_minimums.len());
+    rows.insert("calendar_tokens", dump.calendar_tokens.len());
+    Ok(ImportSummary { dry_run, rows })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn dump() -> Dump {
+        ::serde_json::from_value(json!({
+            "version": VERSION,
+            "created_at": "2018-05-08 12:00:00",
+            "employees": [
+                {"id": 1, "name": "Anna Schmidt", "name_sort": "Schmidt, Anna",
+                 "contract_type": null, "employment_start": null, "employment_end": null,
+                 "active": true},
+                {"id": 2, "name": "Jan Berg", "name_sort": "Berg, Jan",
+                 "contract_type": "minijob", "employment_start": null, "employment_end": null,
+                 "active": true},
+            ],
+            "hourly_rates": [
+                {"id": 1, "employee_id": 1, "effective_from": "2017-08-01", "rate_cents": 1000},
+            ],
+            "absences": [],
+            "reports": [
+                {"id": 1, "title": "März 2018", "start_date": "2018-03-01",
+                 "end_date": "2018-03-31", "was_pdf_generated": false, "show_absences": false,
+                 "template": null, "revision": 4},
+            ],
+            "items": [
+                {"id": 1, "report_id": 1, "employee_id": 1,
+                 "start_datetime": "2018-03-05 12:00:00", "end_datetime": "2018-03-05 14:00:00",
+                 "remark": null, "substitutes_employee_id": 2},
+            ],
+            "holidays": [{"date": "2018-03-26", "title": "Osterferien"}],
+            "weeks": [{"year": 2018, "week_of_year": 10, "type_of_week": 0}],
+            "settings": [{"key": "csv_delimiter", "value": ";"}],
+            "templates": [],
+            "employee_merges": [],
+            "coverage_minimums": [{"weekday": 0, "type_of_week": 0, "minimum": 2}],
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_check_valid() {
+        assert_eq!(check(&dump()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_check_references() {
+        let mut dump = dump();
+        dump.items[0].report_id = 2;
+        dump.items[0].substitutes_employee_id = Some(3);
+        dump.hourly_rates[0].employee_id = 4;
+        assert_eq!(
+            check(&dump),
+            vec![
+                "hourly_rates #1: unknown employee #4",
+                "items #1: unknown employee #3",
+                "items #1: unknown report #2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_duplicates() {
+        let mut dump = dump();
+        dump.employees[1].id = 1;
+        dump.employees[1].name = "Anna Schmidt".to_string();
+        assert_eq!(
+            check(&dump),
+            vec![
+                "items #1: unknown employee #2",
+                "employees: duplicate key 1",
+                "employees: duplicate key Anna Schmidt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replace_all() {
+        let conn = ::db::test_connection();
+        let report_id = ::test_data::report(&conn, "2018-03-01", "2018-03-31");
+        diesel::insert_into(pdf_archive::table)
+            .values((
+                pdf_archive::report_id.eq(report_id),
+                pdf_archive::revision.eq(0),
+                pdf_archive::sha256.eq("0"),
+                pdf_archive::snapshot.eq("{}"),
+            ))
+            .execute(&conn)
+            .unwrap();
+
+        replace_all(&conn, &dump()).unwrap();
+        let reports = reports::table.load::<Report>(&conn).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].revision, 4);
+        assert_eq!(pdf_archive::table.count().get_result::<i64>(&conn), Ok(0));
+    }
+
+    #[test]
+    fn test_check_version() {
+        let mut dump = dump();
+        dump.version = VERSION + 1;
+        assert_eq!(check(&dump).len(), 1);
+        dump.version = MIN_VERSION - 1;
+        assert_eq!(check(&dump).len(), 1);
+
+        let mut json = serde_json::to_value(&dump).unwrap();
+        json["version"] = json!(1);
+        json.as_object_mut().unwrap().remove("calendar_tokens");
+        let dump: Dump = serde_json::from_value(json).unwrap();
+        assert!(dump.calendar_tokens.is_empty());
+        assert!(check(&dump).is_empty());
+    }
+}
@@ -1,7 +1,22 @@
-use diesel::{self, ExpressionMethods, QueryDsl, RunQueryDsl, SqliteConnection};
+use chrono::{Local, NaiveDate};
+use diesel::{
+    self, BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl,
+    SqliteConnection,
+};
 
 use errors::*;
+use names::{self, PersonName};
 use schema::employees;
+use settings;
+
+use DATE_FORMAT;
+
+/// Possible values of the `contract_type` column.
+pub const CONTRACT_TYPES: [&str; 3] = ["minijob", "uebungsleiter", "part_time"];
+
+/// Setting for the comma-separated list of name particles, like "von" or "van", that are ignored
+/// when sorting employees by last name.
+pub const NAME_PARTICLES: &str = "name_particles";
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Queryable)]
 pub struct Employee {
@@ -11,10 +26,78 @@ pub struct Employee {
     pub name: String,
 
     /// Value used for sorting employees. For people with a name of the form "<first names>
-    /// <last name>", this is "<last name>, <first names>".
+    /// <particles> <last name>", this is "<last name>, <first names> <particles>".
     /// For names with only one space-separated component, it is "<name>, ".
+    ///
+    /// This is always derived from the name by the server; whatever the client sends is ignored.
     #[serde(default)]
     pub name_sort: String,
+
+    /// One of `CONTRACT_TYPES`, if known.
+    #[serde(default)]
+    pub contract_type: Option<String>,
+
+    /// First day of employment, if known.
+    #[serde(default)]
+    pub employment_start: Option<String>,
+
+    /// Last day of employment, if the employee has left or is going to leave.
+    #[serde(default)]
+    pub employment_end: Option<String>,
+
+    /// Archived employees are not offered for new items, but still appear in old reports. This
+    /// can only be changed with `archive` and `restore`, not by `update`.
+    #[serde(default = "default_active")]
+    pub active: bool,
+}
+
+fn default_active() -> bool {
+    true
+}
+
+impl Employee {
+    /// Parse an optional date as stored in the `employees` table.
+    fn parse_date(date: &Option<String>) -> Result<Option<NaiveDate>> {
+        match *date {
+            Some(ref date) => NaiveDate::parse_from_str(date, DATE_FORMAT)
+                .map(Some)
+                .chain_err(|| format!("Invalid date: {}", date)),
+            None => Ok(None),
+        }
+    }
+
+    /// Was this person employed on the given day?
+    ///
+    /// If either end of the employment period is unknown, assume it is unbounded.
+    pub fn is_employed_on(&self, day: NaiveDate) -> Result<bool> {
+        let after_start =
+            Self::parse_date(&self.employment_start)?.map_or(true, |start| start <= day);
+        let before_end = Self::parse_date(&self.employment_end)?.map_or(true, |end| day <= end);
+        Ok(after_start && before_end)
+    }
+
+    /// Make sure the master data makes sense before writing it to the database.
+    fn check(&self) -> Result<()> {
+        if let Some(ref contract_type) = self.contract_type {
+            if !CONTRACT_TYPES.contains(&contract_type.as_str()) {
+                bail!(
+                    "Invalid contract type: {} (expected one of {:?})",
+                    contract_type,
+                    CONTRACT_TYPES
+                );
+            }
+        }
+
+        let start = Self::parse_date(&self.employment_start)?;
+        let end = Self::parse_date(&self.employment_end)?;
+        if let (Some(start), Some(end)) = (start, end) {
+            if start > end {
+                bail!("Employment ends ({}) before it starts ({})", end, start);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl PartialOrd for Employee {
@@ -25,39 +108,135 @@ impl PartialOrd for Employee {
 
 impl Ord for Employee {
     fn cmp(&self, other: &Employee) -> ::std::cmp::Ordering {
-        self.name_sort.cmp(&other.name_sort)
+        names::compare(&self.name_sort, &other.name_sort)
     }
 }
 
-/// Get all employees from the database.
-pub fn get(conn: &SqliteConnection) -> Result<Vec<Employee>> {
-    use schema::employees::dsl::*;
-    employees
-        .order(name_sort)
+/// Data needed to create a new employee.
+///
+/// Either give the full `name`, which is then split into first and last name automatically, or
+/// give `first_names` and `last_name` explicitly for names where that would not work.
+#[derive(Debug, Default, Deserialize)]
+pub struct NewEmployee {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub first_names: String,
+    #[serde(default)]
+    pub last_name: String,
+}
+
+/// Get the configured name particles, see `NAME_PARTICLES`.
+fn name_particles(conn: &SqliteConnection) -> Result<Vec<String>> {
+    let particles: String =
+        settings::get(conn, NAME_PARTICLES, names::DEFAULT_PARTICLES.join(","))?;
+    Ok(particles
+        .split(',')
+        .map(|particle| particle.trim().to_lowercase())
+        .filter(|particle| !particle.is_empty())
+        .collect())
+}
+
+/// Get all employees from the database, sorted by last name.
+///
+/// Archived employees, and employees whose employment has ended, are only included if
+/// `include_archived` is true.
+pub fn get(conn: &SqliteConnection, include_archived: bool) -> Result<Vec<Employee>> {
+    let mut query = employees::table.into_boxed();
+    if !include_archived {
+        let today = Local::today().format(DATE_FORMAT).to_string();
+        query = query.filter(employees::active.eq(true)).filter(
+            employees::employment_end
+                .is_null()
+                .or(employees::employment_end.ge(today)),
+        );
+    }
+
+    let mut result = query
         .load::<Employee>(conn)
-        .chain_err(|| "Failed to read table employees")
+        .chain_err(|| "Failed to read table employees")?;
+    result.sort();
+    Ok(result)
+}
+
+/// Get the employee with the given id.
+pub fn get_by_id(conn: &SqliteConnection, id: i32) -> Result<Employee> {
+    employees::table
+        .filter(employees::id.eq(id))
+        .first::<Employee>(conn)
+        .chain_err(|| format!("Failed to get employee #{}", id))
+}
+
+/// Get the id of the employee with exactly the given name, if there is one.
+pub fn find_by_name(conn: &SqliteConnection, name: &str) -> Result<Option<i32>> {
+    employees::table
+        .select(employees::id)
+        .filter(employees::name.eq(name.trim()))
+        .first::<i32>(conn)
+        .optional()
+        .chain_err(|| format!("Failed to look up employee {}", name))
+}
+
+/// Names of existing employees that are similar to `name`, most similar first.
+///
+/// Similarity is measured by the edit distance, ignoring case and umlauts. Names that differ in
+/// more than a third of their characters are not considered similar.
+pub fn suggest(conn: &SqliteConnection, name: &str) -> Result<Vec<String>> {
+    const MAX_SUGGESTIONS: usize = 3;
+
+    let key = names::collation_key(name.trim());
+    let max_distance = ::std::cmp::max(1, key.chars().count() / 3);
+
+    let mut candidates: Vec<_> = get(conn, false)?
+        .into_iter()
+        .map(|employee| {
+            let distance = names::edit_distance(&key, &names::collation_key(&employee.name));
+            (distance, employee.name)
+        })
+        .filter(|&(distance, _)| distance <= max_distance)
+        .collect();
+    candidates.sort();
+
+    Ok(candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, name)| name)
+        .collect())
 }
 
 /// Insert a new employee into the database.
 ///
 /// Create the `name_sort` column from the `name` by assuming that the last word of the full name
-/// is the last name. That is not true in general but should be enough for our purposes: sorting a
-/// list of employees by name in an invoice.
+/// is the last name, possibly preceded by particles like "von". That is not true in general but
+/// should be enough for our purposes: sorting a list of employees by name in an invoice. Use
+/// `create` for names where this does not work.
 pub fn insert<S: AsRef<str>>(conn: &SqliteConnection, name: S) -> Result<i32> {
-    let reversed_name = {
-        let words: Vec<_> = name.as_ref().split(' ').collect();
-        let len = words.len();
-        let mut tmp = words[len - 1].to_string();
-        tmp.push_str(", ");
-        for word in &words[..len - 1] {
-            tmp.push_str(word);
-        }
-        tmp
+    let name = PersonName::parse(name.as_ref(), &name_particles(conn)?);
+    insert_name(conn, &name)
+}
+
+/// Insert a new employee into the database, unless an employee of the same name already exists.
+///
+/// Return the id of the employee either way.
+pub fn create(conn: &SqliteConnection, employee: &NewEmployee) -> Result<i32> {
+    let particles = name_particles(conn)?;
+    let name = if employee.last_name.trim().is_empty() {
+        PersonName::parse(&employee.name, &particles)
+    } else {
+        PersonName::from_parts(&employee.first_names, &employee.last_name, &particles)
     };
+    insert_name(conn, &name)
+}
+
+fn insert_name(conn: &SqliteConnection, name: &PersonName) -> Result<i32> {
+    let full_name = name.full_name();
+    if full_name.is_empty() {
+        bail!("The name of an employee must not be empty");
+    }
 
     let values = (
-        employees::name.eq(name.as_ref()),
-        employees::name_sort.eq(reversed_name),
+        employees::name.eq(&full_name),
+        employees::name_sort.eq(name.sort_key()),
     );
 
     diesel::insert_or_ignore_into(employees::table)
@@ -67,19 +246,33 @@ pub fn insert<S: AsRef<str>>(conn: &SqliteConnection, name: S) -> Result<i32> {
 
     employees::table
         .select(employees::id)
-        .filter(employees::name.eq(name.as_ref()))
+        .filter(employees::name.eq(&full_name))
         .first::<i32>(conn)
         .chain_err(|| "Failed to get employee id")
 }
 
-/// Change the name of an employee.
+/// Change the name and master data of an employee.
 ///
-/// Update `name` and `name_sort` components of the record with the given `id`.
+/// Update all components of the record with the given `id`. If the name changed, `name_sort` is
+/// derived from the new name; otherwise the stored value is kept, as it may have been created from
+/// explicitly given first and last names.
 pub fn update(conn: &SqliteConnection, id: i32, employee: Employee) -> Result<i32> {
+    employee.check()?;
+
+    let old = get_by_id(conn, id)?;
+    let name_sort = if old.name == employee.name {
+        old.name_sort
+    } else {
+        PersonName::parse(&employee.name, &name_particles(conn)?).sort_key()
+    };
+
     diesel::update(employees::table.filter(employees::id.eq(id)))
         .set((
             employees::name.eq(&employee.name),
-            employees::name_sort.eq(&employee.name_sort),
+            employees::name_sort.eq(&name_sort),
+            employees::contract_type.eq(&employee.contract_type),
+            employees::employment_start.eq(&employee.employment_start),
+            employees::employment_end.eq(&employee.employment_end),
         ))
         .execute(conn)
         .chain_err(|| format!("Failed to update employee #{}: {:?}", id, employee))?;
@@ -87,11 +280,98 @@ pub fn update(conn: &SqliteConnection, id: i32, employee: Employee) -> Result<i3
     Ok(id)
 }
 
+/// Recompute `name_sort` for employees whose sort key was created by an earlier version.
+///
+/// That version ran the first names together ("Schmidt, AnnaMaria") and did not know about
+/// particles. Sort keys that differ from what it would have produced were set on purpose and are
+/// left alone.
+pub fn fix_legacy_sort_keys(conn: &SqliteConnection) -> Result<()> {
+    let legacy_sort_key = |name: &str| {
+        let words: Vec<_> = name.split(' ').collect();
+        let mut key = words[words.len() - 1].to_string();
+        key.push_str(", ");
+        key.push_str(&words[..words.len() - 1].concat());
+        key
+    };
+
+    let particles = name_particles(conn)?;
+    for employee in get(conn, true)? {
+        let sort_key = PersonName::parse(&employee.name, &particles).sort_key();
+        if employee.name_sort == legacy_sort_key(&employee.name) && employee.name_sort != sort_key {
+            info!("Sorting {} as {:?}", employee.name, sort_key);
+            diesel::update(employees::table.filter(employees::id.eq(employee.id)))
+                .set(employees::name_sort.eq(&sort_key))
+                .execute(conn)
+                .chain_err(|| format!("Failed to update sort key of {}", employee.name))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Set the `active` flag of an employee.
+fn set_active(conn: &SqliteConnection, id: i32, active: bool) -> Result<()> {
+    let updated = diesel::update(employees::table.filter(employees::id.eq(id)))
+        .set(employees::active.eq(active))
+        .execute(conn)
+        .chain_err(|| format!("Failed to update employee #{}", id))?;
+    if updated == 0 {
+        bail!("No employee #{}", id);
+    }
+    Ok(())
+}
+
+/// Hide an employee who left from the list of employees, without touching their items.
+pub fn archive(conn: &SqliteConnection, id: i32) -> Result<()> {
+    set_active(conn, id, false)
+}
+
+/// Undo `archive`.
+pub fn restore(conn: &SqliteConnection, id: i32) -> Result<()> {
+    set_active(conn, id, true)
+}
+
+/// Is anything in the database referring to the employee with the given id?
+fn is_referenced(conn: &SqliteConnection, id: i32) -> Result<bool> {
+    use diesel::dsl::{exists, select};
+    use schema::{absences, calendar_tokens, employee_merges, hourly_rates, items};
+
+    let has_items = items::table.filter(
+        items::employee_id
+            .eq(id)
+            .or(items::substitutes_employee_id.eq(id)),
+    );
+    let has_absences = absences::table.filter(absences::employee_id.eq(id));
+    let has_rates = hourly_rates::table.filter(hourly_rates::employee_id.eq(id));
+    // Undoing a merge needs the target, and the calendar link should keep working.
+    let has_merges = employee_merges::table.filter(employee_merges::target_id.eq(id));
+    let has_token = calendar_tokens::table.filter(calendar_tokens::employee_id.eq(id));
+
+    select(
+        exists(has_items)
+            .or(exists(has_absences))
+            .or(exists(has_rates))
+            .or(exists(has_merges))
+            .or(exists(has_token)),
+    )
+    .get_result(conn)
+    .chain_err(|| format!("Failed to check references to employee #{}", id))
+}
+
 /// Remove an employee from the database.
-pub fn delete(conn: &SqliteConnection, id: i32) -> Result<()> {
+///
+/// Employees who still have items or other data attached to them are archived instead, so that
+/// old reports stay intact. Return whether the employee was actually deleted.
+pub fn delete(conn: &SqliteConnection, id: i32) -> Result<bool> {
+    if is_referenced(conn, id)? {
+        info!("Archiving employee #{} instead of deleting them", id);
+        archive(conn, id)?;
+        return Ok(false);
+    }
+
     diesel::delete(employees::table.filter(employees::id.eq(id)))
         .execute(conn)
-        .map(|_| ())
+        .map(|_| true)
         .chain_err(|| format!("Failed to delete employee #{}", id))
 }
 
@@ -130,9 +410,81 @@ mod test {
             super::insert(&conn, name).unwrap();
         }
 
-        let mut employees = ::employees::get(&conn).unwrap();
+        let mut employees = ::employees::get(&conn, false).unwrap();
         employees.sort_unstable();
         let retrieved_names: Vec<_> = employees.into_iter().map(|x| x.name).collect();
         assert_eq!(retrieved_names, names);
     }
+
+    #[test]
+    fn test_archived() {
+        let conn = ::db::test_connection();
+        let anna = insert(&conn, "Anna Schmidt").unwrap();
+        let jan = insert(&conn, "Jan Berg").unwrap();
+
+        archive(&conn, jan).unwrap();
+        let names = |include_archived| -> Vec<String> {
+            get(&conn, include_archived)
+                .unwrap()
+                .into_iter()
+                .map(|employee| employee.name)
+                .collect()
+        };
+        assert_eq!(names(false), vec!["Anna Schmidt"]);
+        assert_eq!(names(true), vec!["Jan Berg", "Anna Schmidt"]);
+        assert!(!get_by_id(&conn, jan).unwrap().active);
+
+        restore(&conn, jan).unwrap();
+        assert_eq!(names(false), vec!["Jan Berg", "Anna Schmidt"]);
+        assert!(archive(&conn, anna + jan + 1).is_err());
+
+        // Employees who have left are treated as archived, those who are going to leave are not.
+        let set_end = |id, end: &str| {
+            diesel::update(employees::table.find(id))
+                .set(employees::employment_end.eq(end))
+                .execute(&conn)
+                .unwrap();
+        };
+        set_end(jan, "2000-07-31");
+        set_end(anna, "2999-07-31");
+        assert_eq!(names(false), vec!["Anna Schmidt"]);
+        assert_eq!(names(true), vec!["Jan Berg", "Anna Schmidt"]);
+    }
+
+    #[test]
+    fn test_delete() {
+        use schema::{calendar_tokens, employee_merges};
+        use test_data;
+
+        let conn = ::db::test_connection();
+        let report = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        let unused = insert(&conn, "Unused Employee").unwrap();
+        let working = insert(&conn, "Anna Schmidt").unwrap();
+        let absent = insert(&conn, "Jan Berg").unwrap();
+        let target = insert(&conn, "Merge Target").unwrap();
+        let subscribed = insert(&conn, "Zoe Adler").unwrap();
+        test_data::item(&conn, report, working, "2018-03-05", (12, 14), Some(absent));
+        diesel::insert_into(employee_merges::table)
+            .values((
+                employee_merges::target_id.eq(target),
+                employee_merges::undo_data.eq("{}"),
+            ))
+            .execute(&conn)
+            .unwrap();
+        diesel::insert_into(calendar_tokens::table)
+            .values((
+                calendar_tokens::employee_id.eq(subscribed),
+                calendar_tokens::token.eq("0123"),
+            ))
+            .execute(&conn)
+            .unwrap();
+
+        assert!(delete(&conn, unused).unwrap());
+        assert!(get_by_id(&conn, unused).is_err());
+
+        for &id in &[working, absent, target, subscribed] {
+            assert!(!delete(&conn, id).unwrap());
+            assert!(!get_by_id(&conn, id).unwrap().active);
+        }
+    }
 }
@@ -0,0 +1,235 @@
+use csv;
+use diesel::SqliteConnection;
+
+use errors::*;
+use generate_pdf::RawReportData;
+use settings;
+use weeks::TYPE_OF_WEEK_NAME;
+use TIME_FORMAT;
+
+/// Setting for the character separating the fields of exported CSV files.
+pub const CSV_DELIMITER: &str = "csv_delimiter";
+/// Setting for the encoding of exported CSV files: `utf-8`, `utf-8-bom` or `windows-1252`.
+///
+/// Excel only recognizes UTF-8 if the file starts with a byte order mark, and older versions only
+/// read Windows-1252 correctly.
+pub const CSV_ENCODING: &str = "csv_encoding";
+
+pub const ENCODINGS: [&str; 3] = ["utf-8", "utf-8-bom", "windows-1252"];
+
+/// Overrides the CSV settings for a single export, e.g. `?delimiter=%3B&encoding=windows-1252`.
+#[derive(Debug, Default, FromForm)]
+pub struct CsvOptions {
+    /// A single ASCII character, like `CSV_DELIMITER`.
+    pub delimiter: Option<String>,
+    /// One of `ENCODINGS`, like `CSV_ENCODING`.
+    pub encoding: Option<String>,
+}
+
+/// The characters of Windows-1252 between 0x80 and 0x9f, where it differs from ISO 8859-1.
+/// Unassigned code points are `None`.
+const WINDOWS_1252_HIGH: [Option<char>; 32] = [
+    Some('€'),
+    None,
+    Some('‚'),
+    Some('ƒ'),
+    Some('„'),
+    Some('…'),
+    Some('†'),
+    Some('‡'),
+    Some('ˆ'),
+    Some('‰'),
+    Some('Š'),
+    Some('‹'),
+    Some('Œ'),
+    None,
+    Some('Ž'),
+    None,
+    None,
+    Some('‘'),
+    Some('’'),
+    Some('“'),
+    Some('”'),
+    Some('•'),
+    Some('–'),
+    Some('—'),
+    Some('˜'),
+    Some('™'),
+    Some('š'),
+    Some('›'),
+    Some('œ'),
+    None,
+    Some('ž'),
+    Some('Ÿ'),
+];
+
+/// Encode a string in Windows-1252, replacing characters that do not exist there with '?'.
+fn encode_windows_1252(s: &str) -> Vec<u8> {
+    s.chars()
+        .map(|c| match c as u32 {
+            code @ 0x00...0x7f | code @ 0xa0...0xff => code as u8,
+            _ => WINDOWS_1252_HIGH
+                .iter()
+                .position(|&high| high == Some(c))
+                .map(|i| 0x80 + i as u8)
+                .unwrap_or(b'?'),
+        })
+        .collect()
+}
+
+/// Convert UTF-8 text to the given encoding.
+pub fn encode(text: &str, encoding: &str) -> Result<Vec<u8>> {
+    match encoding {
+        "utf-8" => Ok(text.as_bytes().to_vec()),
+        "utf-8-bom" => {
+            let mut result = "\u{feff}".as_bytes().to_vec();
+            result.extend_from_slice(text.as_bytes());
+            Ok(result)
+        }
+        "windows-1252" => Ok(encode_windows_1252(text)),
+        _ => bail!(
+            "Unknown encoding {}, expected one of {}",
+            encoding,
+            ENCODINGS.join(", ")
+        ),
+    }
+}
+
+/// Convert text in any of the `ENCODINGS` to UTF-8.
+///
+/// Anything that is not valid UTF-8 is assumed to be Windows-1252, since every byte sequence is
+/// valid there.
+pub fn decode(data: &[u8]) -> String {
+    match ::std::str::from_utf8(data) {
+        Ok(text) => text.trim_left_matches('\u{feff}').to_string(),
+        Err(_) => data
+            .iter()
+            .map(|&byte| match byte {
+                0x80...0x9f => WINDOWS_1252_HIGH[(byte - 0x80) as usize].unwrap_or('\u{fffd}'),
+                _ => byte as char,
+            })
+            .collect(),
+    }
+}
+
+/// Write all items of a report as CSV, with a header line.
+///
+/// Delimiter and encoding are taken from `options`, or from the settings where it leaves them out.
+pub fn csv(
+    conn: &SqliteConnection,
+    report: &RawReportData,
+    options: &CsvOptions,
+) -> Result<Vec<u8>> {
+    let delimiter: char = match options.delimiter {
+        Some(ref delimiter) => delimiter.parse().chain_err(|| {
+            format!(
+                "The CSV delimiter has to be a single character, not {:?}",
+                delimiter
+            )
+        })?,
+        None => settings::get(conn, CSV_DELIMITER, ',')?,
+    };
+    if !delimiter.is_ascii() {
+        bail!(
+            "The CSV delimiter has to be an ASCII character, not {:?}",
+            delimiter
+        );
+    }
+    let encoding: String = match options.encoding {
+        Some(ref encoding) => encoding.clone(),
+        None => settings::get(conn, CSV_ENCODING, "utf-8".to_string())?,
+    };
+
+    let mut writer = csv::Writer::from_memory().delimiter(delimiter as u8);
+    writer
+        .encode(("Name", "Datum", "Woche", "Beginn", "Ende", "Bemerkung"))
+        .chain_err(|| "Failed to write CSV header")?;
+
+    for item in &report.items {
+        let row = (
+            &item.name,
+            format!("{}", item.day.format("%d.%m.%y")),
+            &TYPE_OF_WEEK_NAME[item.type_of_week as usize],
+            format!("{}", item.start.format(TIME_FORMAT)),
+            format!("{}", item.end.format(TIME_FORMAT)),
+            &item.remark,
+        );
+        writer
+            .encode(&row)
+            .chain_err(|| format!("Failed to format CSV row: {:?}", row))?;
+    }
+
+    encode(writer.as_string(), &encoding)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use employees;
+    use test_data;
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode("Jörg", "utf-8").unwrap(), "Jörg".as_bytes());
+        assert_eq!(
+            encode("Jörg", "utf-8-bom").unwrap(),
+            b"\xef\xbb\xbfJ\xc3\xb6rg".to_vec()
+        );
+        assert!(encode("Jörg", "latin2").is_err());
+    }
+
+    #[test]
+    fn test_windows_1252() {
+        assert_eq!(
+            encode_windows_1252("Weiß, Jörg"),
+            b"Wei\xdf, J\xf6rg".to_vec()
+        );
+        assert_eq!(
+            encode_windows_1252("12,50 € – „gut“"),
+            b"12,50 \x80 \x96 \x84gut\x93".to_vec()
+        );
+        assert_eq!(encode_windows_1252("Łukasz"), b"?ukasz".to_vec());
+    }
+
+    #[test]
+    fn test_decode() {
+        for encoding in &ENCODINGS {
+            let text = "Weiß, Jörg: 12,50 €";
+            assert_eq!(decode(&encode(text, encoding).unwrap()), text);
+        }
+        assert_eq!(decode(b"\x81"), "\u{fffd}");
+    }
+
+    #[test]
+    fn test_csv_options() {
+        let conn = ::db::test_connection();
+        settings::set(&conn, CSV_DELIMITER, ";").unwrap();
+        let report_id = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        let jorg = employees::insert(&conn, "Jörg Özdemir").unwrap();
+        test_data::item(&conn, report_id, jorg, "2018-03-05", (12, 14), None);
+        let report = RawReportData::from_id(&conn, report_id).unwrap();
+
+        let data = csv(&conn, &report, &CsvOptions::default()).unwrap();
+        assert!(data.starts_with(b"Name;Datum;"));
+        assert!(data.ends_with("Jörg Özdemir;05.03.18;A;12:00;14:00;\n".as_bytes()));
+
+        let options = CsvOptions {
+            delimiter: Some(",".to_string()),
+            encoding: Some("windows-1252".to_string()),
+        };
+        let data = csv(&conn, &report, &options).unwrap();
+        assert!(data.starts_with(b"Name,Datum,"));
+        assert!(data.ends_with(b"J\xf6rg \xd6zdemir,05.03.18,A,12:00,14:00,\n"));
+
+        let options = CsvOptions {
+            delimiter: Some(";;".to_string()),
+            encoding: None,
+        };
+        assert!(csv(&conn, &report, &options).is_err());
+        let options = CsvOptions {
+            delimiter: None,
+            encoding: Some("latin2".to_string()),
+        };
+        assert!(csv(&conn, &report, &options).is_err());
+    }
+}
@@ -1,137 +1,219 @@
 use std::fs::{self, File};
-use std::io::Write;
-use std::ops::Add;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::process;
 
-use csv;
+use archive::{self, ArchivedPdf};
 use errors::*;
 use diesel::SqliteConnection;
+use serde_json;
 use tempdir::TempDir;
 
-use TIME_FORMAT;
 use items::{self, InvoiceItem};
-use reports::{self, Report};
-use weeks::TYPE_OF_WEEK_NAME;
+use reports::{self, PerEmployeeReport, Report};
+use settings;
+use templates::{self, Template};
 
+/// Setting choosing how PDFs are generated: `latex` runs XeLaTeX on the report's template,
+/// `native` renders the PDF in-process, which requires the `native-pdf` feature.
+pub const PDF_BACKEND: &str = "pdf_backend";
+
+#[cfg(feature = "native-pdf")]
+const DEFAULT_BACKEND: &str = "native";
+#[cfg(not(feature = "native-pdf"))]
+const DEFAULT_BACKEND: &str = "latex";
+
+/// A report with all its items, as stored in the database.
+#[derive(Serialize)]
 pub struct RawReportData {
-    metadata: Report,
-    items: Vec<InvoiceItem>,
+    pub metadata: Report,
+    pub items: Vec<InvoiceItem>,
 }
 
 impl RawReportData {
     /// Retrieve all data belonging to a report from the database.
-    fn from_id(conn: &SqliteConnection, id: i32) -> Result<RawReportData> {
+    pub fn from_id(conn: &SqliteConnection, id: i32) -> Result<RawReportData> {
         let metadata = reports::get(conn, id)?;
         let items = items::get(conn, id)?;
         Ok(RawReportData { metadata, items })
     }
 
-    fn sanitized_path(&self) -> String {
+    /// The title, made safe to use as a file name.
+    pub fn sanitized_path(&self) -> String {
         let slashes_replaced = self.metadata.title.replace('/', "_");
         slashes_replaced.replace('\0', "_")
     }
+}
 
-    fn write_csv(&self) -> Result<()> {
-        let mut path = Path::new(env!("CARGO_MANIFEST_DIR"))
-            .join("csv")
-            .join(&self.sanitized_path());
-        path.set_extension("csv");
-
-        let mut writer = csv::Writer::from_file(path).expect("Failed to create CSV writer");
-
-        for item in &self.items {
-            let row = (
-                &item.name,
-                format!("{}", item.day.format("%d.%m.%y")),
-                &TYPE_OF_WEEK_NAME[item.type_of_week as usize],
-                format!("{}", item.start.format(TIME_FORMAT)),
-                format!("{}", item.end.format(TIME_FORMAT)),
-                &item.remark,
-            );
-            writer
-                .encode(&row)
-                .chain_err(|| format!("Failed to format CSV row: {:?}", row))?;
-        }
+/// Everything a PDF is made from, stored with every archived PDF.
+///
+/// Besides the report as stored in the database, this contains the data passed to the template,
+/// so hourly rates and absences are recorded as well, and the template itself.
+#[derive(Serialize)]
+pub struct ReportSnapshot {
+    #[serde(flatten)]
+    pub data: RawReportData,
+    pub report: PerEmployeeReport,
+    pub template: Template,
+}
 
-        Ok(())
+impl ReportSnapshot {
+    pub fn from_id(conn: &SqliteConnection, id: i32) -> Result<ReportSnapshot> {
+        let data = RawReportData::from_id(conn, id)?;
+        let report = PerEmployeeReport::generate(conn, id)?;
+        let template =
+            templates::resolve(conn, data.metadata.template.as_ref().map(String::as_str))?;
+        Ok(ReportSnapshot {
+            data,
+            report,
+            template,
+        })
     }
 
-    fn generate_latex(&self) -> String {
-        use handlebars::Handlebars;
-
-        let mut handlebars = Handlebars::new();
-        handlebars
-            .register_template_string("latex", include_str!("template.tex.hbs"))
-            .expect("Failed to register template");
-
-        // TODO When updating to Handlebars 1, uncomment this:
-        //handlebars.set_strict_mode(true);
-
-        let params = reports::PerEmployeeReport::generate(&::db::connect(), self.metadata.id);
-
-        handlebars
-            .render("latex", &params)
-            .expect("Failed to render template")
+    /// Hash of the data the PDF depends on.
+    ///
+    /// Unlike the revision of the report, this also changes with hourly rates, absences or the
+    /// template, so it tells whether an archived PDF is still up to date.
+    pub fn content_hash(&self) -> Result<String> {
+        let content = serde_json::to_vec(&(&self.report, &self.template))?;
+        Ok(archive::sha256_hex(&content))
     }
 
-    fn write_latex(&self) -> Option<(TempDir, PathBuf)> {
-        let latex = self.generate_latex();
+    /// Write the LaTeX source to a new temporary directory.
+    ///
+    /// The file is always called `report.tex`, since XeLaTeX cannot cope with characters like `%`
+    /// or `#` in file names, which might well appear in the title.
+    fn write_latex(&self) -> Result<(TempDir, PathBuf)> {
+        let latex = templates::render_template(&self.template, &self.report)?;
 
-        let dir = TempDir::new("generate-pdf").expect("Failed to create temporary directory");
+        let dir =
+            TempDir::new("generate-pdf").chain_err(|| "Failed to create temporary directory")?;
 
-        let path = dir.path().join(self.sanitized_path().add(".tex"));
+        let path = dir.path().join("report.tex");
 
-        let mut file = File::create(&path).expect("Failed to create LaTeX file");
+        let mut file = File::create(&path).chain_err(|| "Failed to create LaTeX file")?;
         file.write_all(latex.as_bytes())
-            .expect("Failed to write LaTeX to file");
+            .chain_err(|| "Failed to write LaTeX to file")?;
 
-        Some((dir, path))
+        Ok((dir, path))
     }
 }
 
-fn render_latex<P: AsRef<Path>>(temp_dir: TempDir, file_path: P) -> Result<PathBuf> {
-    // Limit scope of tempdir_path_string so we can close temp_dir later
-    {
-        let tempdir_path_string = temp_dir.path().to_str().unwrap();
-        let file_path_string = file_path.as_ref().to_str().unwrap();
-
-        process::Command::new("xelatex")
-            .arg("-output-directory")
-            .arg(tempdir_path_string)
-            .arg(file_path_string)
-            .output()
-            .chain_err(|| "Executing XeLaTeX failed")?;
-        // TODO handle LaTeX errors
+/// Find the first error in a TeX log file.
+///
+/// Errors start with a line beginning with "! ", and are followed by the number and content of
+/// the offending input line, like `l.42 \foo`.
+fn parse_latex_log(log: &str) -> Option<ErrorKind> {
+    let mut lines = log.lines().skip_while(|line| !line.starts_with("! "));
+    let message = lines.next()?[2..].trim().to_string();
+
+    for line in lines.take(20) {
+        if !line.starts_with("l.") {
+            continue;
+        }
+        let digits: String = line[2..].chars().take_while(|c| c.is_digit(10)).collect();
+        if let Ok(number) = digits.parse() {
+            let context = line[2 + digits.len()..].trim().to_string();
+            return Some(ErrorKind::LatexError(message, Some(number), context));
+        }
     }
 
-    let pdf = file_path.as_ref().with_extension("pdf");
+    Some(ErrorKind::LatexError(message, None, "".to_string()))
+}
 
-    let output_path = Path::new(env!("CARGO_MANIFEST_DIR"))
-        .join("pdf")
-        .join(pdf.file_name().unwrap());
+/// Run XeLaTeX on the file at `file_path` and return the resulting PDF.
+fn render_latex<P: AsRef<Path>>(temp_dir: TempDir, file_path: P) -> Result<Vec<u8>> {
+    let file_path = file_path.as_ref();
+
+    let output = process::Command::new("xelatex")
+        .arg("-interaction=nonstopmode")
+        .arg("-halt-on-error")
+        .arg("-output-directory")
+        .arg(temp_dir.path())
+        .arg(file_path)
+        .output()
+        .chain_err(|| "Executing XeLaTeX failed")?;
+
+    let pdf = file_path.with_extension("pdf");
+    if !output.status.success() || !pdf.exists() {
+        let log = fs::read_to_string(file_path.with_extension("log")).unwrap_or_default();
+        let error = parse_latex_log(&log).unwrap_or_else(|| {
+            ErrorKind::LatexError(
+                format!("XeLaTeX failed ({})", output.status),
+                None,
+                "".to_string(),
+            )
+        });
+        error!("Failed to compile {:?}: {:?}", file_path, error);
+        return Err(error.into());
+    }
 
-    fs::copy(&pdf, &output_path).expect("Failed to copy PDF file");
+    let mut data = vec![];
+    File::open(&pdf)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .chain_err(|| format!("Failed to read {:?}", pdf))?;
 
     temp_dir
         .close()
         .chain_err(|| "Failed to close temporary directory")?;
 
-    Ok(output_path)
+    Ok(data)
+}
+
+#[cfg(feature = "native-pdf")]
+fn render_native(snapshot: &ReportSnapshot) -> Result<Vec<u8>> {
+    ::native_pdf::render(&snapshot.report)
 }
 
-pub fn generate(conn: &SqliteConnection, id: i32) -> Result<PathBuf> {
-    let full_report = RawReportData::from_id(conn, id)?;
+#[cfg(not(feature = "native-pdf"))]
+fn render_native(_snapshot: &ReportSnapshot) -> Result<Vec<u8>> {
+    bail!("The native PDF backend is not available, build with --features native-pdf")
+}
 
-    full_report.write_csv()?;
+/// Generate the PDF for a report and add it to the archive.
+///
+/// Nothing is archived if generating the PDF fails. This takes several seconds with the LaTeX
+/// backend, so requests should go through `jobs` instead of calling this directly.
+pub fn generate(conn: &SqliteConnection, id: i32) -> Result<ArchivedPdf> {
+    let snapshot = ReportSnapshot::from_id(conn, id)?;
+
+    let backend: String = settings::get(conn, PDF_BACKEND, DEFAULT_BACKEND.to_string())?;
+    let pdf = match backend.as_str() {
+        "latex" => {
+            let (temp_dir, tex_path) = snapshot.write_latex()?;
+            render_latex(temp_dir, tex_path)?
+        }
+        "native" => render_native(&snapshot)?,
+        _ => bail!("Unknown PDF backend: {}", backend),
+    };
 
-    let (temp_dir, tex_path) = full_report
-        .write_latex()
-        .expect("Failed to write LaTeX file");
+    let archived = archive::store(conn, &snapshot, &pdf)?;
+    reports::set_pdf_generated(conn, id)?;
 
-    let pdf_path = render_latex(temp_dir, tex_path)?;
+    Ok(archived)
+}
 
-    reports::set_pdf_generated(conn, id);
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_latex_log() {
+        let log = "This is XeTeX, Version 3.14159265\n\
+                   (./report.tex\n\
+                   ! Undefined control sequence.\n\
+                   l.42 & 30.08.17 \\foo\n\
+                   \x20                    bar\n\
+                   ! Emergency stop.\n";
+        match parse_latex_log(log) {
+            Some(ErrorKind::LatexError(message, line, context)) => {
+                assert_eq!(message, "Undefined control sequence.");
+                assert_eq!(line, Some(42));
+                assert_eq!(context, "& 30.08.17 \\foo");
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
 
-    Ok(pdf_path)
+        assert!(parse_latex_log("Output written on report.pdf (1 page).").is_none());
+    }
 }
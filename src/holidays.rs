@@ -221,6 +221,11 @@ fn get_holidays(conn: &SqliteConnection) -> HashMap<NaiveDate, String> {
     )
 }
 
+/// Read the holidays from the database again, e.g. after importing a dump.
+pub fn reload(conn: &SqliteConnection) {
+    *HOLIDAYS.lock().unwrap() = get_holidays(conn);
+}
+
 /// Check whether the given day is either a school or general holiday.
 pub fn is_holiday(date: NaiveDate) -> bool {
     HOLIDAYS.lock().unwrap().contains_key(&date)
@@ -0,0 +1,54 @@
+use handlebars::Handlebars;
+
+use errors::*;
+use reports::PerEmployeeReport;
+
+/// Render a report as a web page, so it can be checked on screen or printed without generating a
+/// PDF.
+///
+/// This uses the same data as the PDF, only the template differs. Handlebars' default escaping
+/// takes care of HTML special characters.
+pub fn render(report: &PerEmployeeReport) -> Result<String> {
+    let mut handlebars = Handlebars::new();
+    handlebars.set_strict_mode(true);
+    handlebars
+        .register_template_string("html", include_str!("report.html.hbs"))
+        .chain_err(|| "Invalid HTML template")?;
+    handlebars
+        .render("html", report)
+        .chain_err(|| "Failed to render HTML report")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let html = render(&PerEmployeeReport::example()).unwrap();
+        assert!(html.contains("Abrechnung BetreuerInnen Beispiel"));
+        assert!(html.contains("Erika Mustermann"));
+        assert!(html.contains("Vertretung für Max Mustermann"));
+        assert!(html.contains("Gesamtbetrag: 25,00 €"));
+        assert!(!html.contains("Stundensatz"));
+    }
+
+    #[test]
+    fn test_missing_rate() {
+        let mut report = PerEmployeeReport::example();
+        report.employees[0].items_without_rate = 2;
+        report.items_without_rate = 2;
+        let html = render(&report).unwrap();
+        assert!(html.contains("nicht im Betrag enthalten: 2 Einträge"));
+        assert!(html.contains("Unvollständig: 2 Einträge ohne Stundensatz"));
+    }
+
+    #[test]
+    fn test_escaping() {
+        let mut report = PerEmployeeReport::example();
+        report.title = "<script>alert(1)</script> & Co".to_string();
+        let html = render(&report).unwrap();
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt; &amp; Co"));
+    }
+}
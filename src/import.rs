@@ -0,0 +1,612 @@
+use std::io::Read;
+
+use chrono::{Datelike, Duration, NaiveDate, NaiveTime};
+use csv;
+use diesel::prelude::*;
+use diesel::result::Error::RollbackTransaction;
+use diesel::SqliteConnection;
+
+use employees;
+use errors::*;
+use export;
+use items::{self, NewRow};
+use reports::{self, NewReport, Report};
+use schema::items as items_table;
+use snapshots;
+use weeks::{self, TYPE_OF_WEEK_NAME};
+use {DATE_FORMAT, TIME_FORMAT};
+
+/// Largest CSV file accepted for import, in bytes.
+pub const MAX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Delimiters used by the export or by common spreadsheet programs.
+const DELIMITERS: [u8; 3] = [b',', b';', b'\t'];
+
+/// Used in the titles of reports created for a month.
+const MONTH_NAME: [&str; 12] = [
+    "Januar",
+    "Februar",
+    "März",
+    "April",
+    "Mai",
+    "Juni",
+    "Juli",
+    "August",
+    "September",
+    "Oktober",
+    "November",
+    "Dezember",
+];
+
+/// One row of the file, in the layout written by `export::csv`.
+#[derive(Debug, PartialEq)]
+struct CsvRow {
+    name: String,
+    day: NaiveDate,
+    /// The type of week as given in the file, if any.
+    type_of_week: Option<i32>,
+    start: NaiveTime,
+    end: NaiveTime,
+    remark: String,
+}
+
+/// What happened to one row of the file.
+#[derive(Debug, Serialize)]
+pub struct RowReport {
+    /// Number of the row in the file, starting at 1 with the header.
+    pub line: usize,
+    pub name: String,
+    pub day: Option<NaiveDate>,
+    /// The report the item was assigned to.
+    pub report_id: Option<i32>,
+    /// Title of the report created for the month of this row, if there was none.
+    pub new_report: Option<String>,
+    /// Whether a new employee was created for this row.
+    pub new_employee: bool,
+    /// Existing employees with a similar name, if the name is not known.
+    pub suggestions: Vec<String>,
+    pub warnings: Vec<String>,
+    /// Why the row cannot be imported.
+    pub error: Option<String>,
+}
+
+impl RowReport {
+    fn new(line: usize, name: &str) -> Self {
+        RowReport {
+            line,
+            name: name.to_string(),
+            day: None,
+            report_id: None,
+            new_report: None,
+            new_employee: false,
+            suggestions: vec![],
+            warnings: vec![],
+            error: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    /// Whether the items were saved. Nothing is saved if any row has an error.
+    pub imported: bool,
+    /// Whether the first row was skipped as a header.
+    pub header: bool,
+    pub rows: Vec<RowReport>,
+    /// Names of the employees that were created.
+    pub new_employees: Vec<String>,
+    /// Titles of the reports that were created.
+    pub new_reports: Vec<String>,
+}
+
+/// Read a CSV file, refusing anything larger than `MAX_SIZE`.
+pub fn read<R: Read>(reader: R) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    reader
+        .take(MAX_SIZE + 1)
+        .read_to_end(&mut data)
+        .chain_err(|| "Failed to read CSV file")?;
+    if data.len() as u64 > MAX_SIZE {
+        bail!("The CSV file is larger than {} bytes", MAX_SIZE);
+    }
+    Ok(data)
+}
+
+/// Guess the delimiter from the first line, as it depends on the spreadsheet program and its
+/// language. Ties go to the delimiter listed first.
+fn detect_delimiter(text: &str) -> u8 {
+    let first_line = text.lines().next().unwrap_or("");
+    DELIMITERS
+        .iter()
+        .rev()
+        .cloned()
+        .max_by_key(|&delimiter| first_line.bytes().filter(|&b| b == delimiter).count())
+        .unwrap()
+}
+
+/// Dates are written as `dd.mm.yy`, but spreadsheets often turn them into `dd.mm.yyyy`.
+fn parse_day(day: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(day, "%d.%m.%y")
+        .or_else(|_| NaiveDate::parse_from_str(day, "%d.%m.%Y"))
+        .chain_err(|| format!("Invalid date, expected dd.mm.yy: {}", day))
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, TIME_FORMAT)
+        .chain_err(|| format!("Invalid time, expected hh:mm: {}", time))
+}
+
+/// The header written by the export, or anything else without a date in the date column.
+fn is_header(fields: &[String]) -> bool {
+    fields
+        .get(1)
+        .map_or(true, |day| parse_day(day.trim()).is_err())
+}
+
+fn parse_row(fields: &[String]) -> Result<CsvRow> {
+    if fields.len() < 5 || fields.len() > 6 {
+        bail!(
+            "Expected 6 columns (name, date, week, start, end, remark), found {}",
+            fields.len()
+        );
+    }
+    let field = |i: usize| fields.get(i).map_or("", |field| field.trim());
+
+    let name = field(0);
+    if name.is_empty() {
+        bail!("The name is missing");
+    }
+    let type_of_week = match field(2) {
+        "" => None,
+        week => {
+            let position = TYPE_OF_WEEK_NAME
+                .iter()
+                .position(|&name| name.eq_ignore_ascii_case(week))
+                .ok_or_else(|| format!("Invalid type of week: {}", week))?;
+            Some(position as i32)
+        }
+    };
+
+    Ok(CsvRow {
+        name: name.to_string(),
+        day: parse_day(field(1))?,
+        type_of_week,
+        start: parse_time(field(3))?,
+        end: parse_time(field(4))?,
+        remark: field(5).to_string(),
+    })
+}
+
+/// The message of an error and all its causes.
+fn describe(error: &Error) -> String {
+    error
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+/// The report covering `day`.
+///
+/// If there is none, a report for the whole month is created, unless that would overlap other
+/// reports.
+fn find_report(
+    conn: &SqliteConnection,
+    reports: &mut Vec<Report>,
+    day: NaiveDate,
+    result: &mut RowReport,
+) -> Result<i32> {
+    let date = day.format(DATE_FORMAT).to_string();
+    if let Some(report) = reports
+        .iter()
+        .find(|report| report.start_date <= date && date <= report.end_date)
+    {
+        return Ok(report.id);
+    }
+
+    let first = NaiveDate::from_ymd(day.year(), day.month(), 1);
+    let next_month = if day.month() == 12 {
+        NaiveDate::from_ymd(day.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd(day.year(), day.month() + 1, 1)
+    };
+    let start_date = first.format(DATE_FORMAT).to_string();
+    let end_date = (next_month - Duration::days(1))
+        .format(DATE_FORMAT)
+        .to_string();
+    if let Some(other) = reports
+        .iter()
+        .find(|report| report.start_date <= end_date && start_date <= report.end_date)
+    {
+        bail!(
+            "There is no report for {}, and a report for the whole month would overlap {}",
+            date,
+            other.title
+        );
+    }
+
+    let report = NewReport {
+        title: format!("{} {}", MONTH_NAME[day.month0() as usize], day.year()),
+        start_date,
+        end_date,
+        show_absences: false,
+        template: None,
+    };
+    let id = reports::add(conn, &report)?;
+    result.new_report = Some(report.title);
+    reports.push(reports::get(conn, id)?);
+    Ok(id)
+}
+
+/// Find the employee a row belongs to.
+///
+/// Names similar to existing ones are rejected with suggestions, as they are most likely typos.
+/// Other unknown names are handled like in the frontend: a new employee is created if
+/// `items::STRICT_EMPLOYEES` is turned off.
+fn find_employee(conn: &SqliteConnection, new_row: &NewRow, result: &mut RowReport) -> Result<i32> {
+    if let Some(id) = employees::find_by_name(conn, &new_row.name)? {
+        return Ok(id);
+    }
+
+    let suggestions = employees::suggest(conn, &new_row.name)?;
+    if !suggestions.is_empty() {
+        return Err(ErrorKind::UnknownEmployee(new_row.name.clone(), suggestions).into());
+    }
+    let id = items::resolve_employee(conn, new_row)?;
+    result.new_employee = true;
+    Ok(id)
+}
+
+/// Refuse items that already exist, e.g. when importing the same file twice.
+fn check_duplicate(conn: &SqliteConnection, employee_id: i32, new_row: &NewRow) -> Result<()> {
+    let start = format!("{} {}:00", new_row.day, new_row.start_time);
+    let end = format!("{} {}:00", new_row.day, new_row.end_time);
+    let existing = items_table::table
+        .select(items_table::id)
+        .filter(items_table::employee_id.eq(employee_id))
+        .filter(items_table::start_datetime.eq(&start))
+        .filter(items_table::end_datetime.eq(&end))
+        .first::<i32>(conn)
+        .optional()
+        .chain_err(|| "Failed to look for duplicate items")?;
+    if let Some(id) = existing {
+        bail!(
+            "{} already has an item from {} to {} (#{})",
+            new_row.name,
+            start,
+            end,
+            id
+        );
+    }
+    Ok(())
+}
+
+/// Save one row as an item.
+fn import_row(
+    conn: &SqliteConnection,
+    reports: &mut Vec<Report>,
+    row: &CsvRow,
+    result: &mut RowReport,
+) -> Result<()> {
+    let day = row.day.format(DATE_FORMAT).to_string();
+
+    let type_of_week = weeks::find_type_of_week(row.day)
+        .ok_or_else(|| format!("The type of week of {} is not known", day))?;
+    match row.type_of_week {
+        Some(given) if given != type_of_week => result.warnings.push(format!(
+            "The file says {} is in a {} week, but it is in a {} week",
+            day, TYPE_OF_WEEK_NAME[given as usize], TYPE_OF_WEEK_NAME[type_of_week as usize]
+        )),
+        _ => {}
+    }
+
+    let report_id = find_report(conn, reports, row.day, result)?;
+    result.report_id = Some(report_id);
+
+    let mut new_row = NewRow {
+        id: None,
+        employee_id: None,
+        name: row.name.clone(),
+        day,
+        type_of_week,
+        start_time: row.start.format(TIME_FORMAT).to_string(),
+        end_time: row.end.format(TIME_FORMAT).to_string(),
+        remark: row.remark.clone(),
+        substitutes_employee_id: None,
+    };
+    let employee_id = find_employee(conn, &new_row, result)?;
+    new_row.employee_id = Some(employee_id);
+    check_duplicate(conn, employee_id, &new_row)?;
+
+    let updated = items::update(conn, report_id, 0, &new_row)?;
+    result.warnings.extend(updated.warnings);
+    Ok(())
+}
+
+fn import_rows(conn: &SqliteConnection, text: &str, dry_run: bool) -> Result<ImportReport> {
+    let mut reports = reports::get_all(conn)?;
+    let mut reader = csv::Reader::from_string(text)
+        .delimiter(detect_delimiter(text))
+        .has_headers(false)
+        .flexible(true);
+
+    let mut report = ImportReport {
+        dry_run,
+        imported: false,
+        header: false,
+        rows: vec![],
+        new_employees: vec![],
+        new_reports: vec![],
+    };
+
+    for (i, record) in reader.records().enumerate() {
+        let fields = record.chain_err(|| format!("Invalid CSV in row {}", i + 1))?;
+        if fields.iter().all(|field| field.trim().is_empty()) {
+            continue;
+        }
+        if i == 0 && is_header(&fields) {
+            report.header = true;
+            continue;
+        }
+
+        let mut result = RowReport::new(i + 1, fields.get(0).map_or("", |name| name.trim()));
+        let imported = parse_row(&fields).and_then(|row| {
+            result.day = Some(row.day);
+            import_row(conn, &mut reports, &row, &mut result)
+        });
+        if let Err(e) = imported {
+            if let ErrorKind::UnknownEmployee(_, ref suggestions) = *e.kind() {
+                result.suggestions = suggestions.clone();
+            }
+            result.error = Some(describe(&e));
+        }
+        if result.new_employee {
+            report.new_employees.push(result.name.clone());
+        }
+        if let Some(ref title) = result.new_report {
+            report.new_reports.push(title.clone());
+        }
+        report.rows.push(result);
+    }
+
+    Ok(report)
+}
+
+/// Import items from a CSV file in the layout written by `export::csv`.
+///
+/// The delimiter is detected automatically, and a header is skipped. Each item is assigned to the
+/// report covering its day, or a new report for its month. Items that already exist are refused,
+/// and so are names similar to existing employees, which come with suggestions instead. Other
+/// unknown employees are created if `items::STRICT_EMPLOYEES` is turned off. Either all rows are
+/// imported or, if any of them has an error, none. With `dry_run`, nothing is saved either way,
+/// but the report shows what would happen. Before a successful import, a snapshot is taken.
+pub fn csv(conn: &SqliteConnection, data: &[u8], dry_run: bool) -> Result<ImportReport> {
+    let text = export::decode(data);
+
+    // Check all rows first, so a file with errors does not cost a snapshot.
+    let mut report = import_text(conn, &text, true)?;
+    if dry_run || report.rows.iter().any(|row| row.error.is_some()) {
+        report.dry_run = dry_run;
+        return Ok(report);
+    }
+
+    snapshots::take(conn, "csv-import")?;
+    let report = import_text(conn, &text, false)?;
+    if report.imported {
+        info!("Imported {} items from CSV", report.rows.len());
+    }
+    Ok(report)
+}
+
+/// Import the rows in a transaction, which is rolled back with `dry_run` or if any row fails.
+fn import_text(conn: &SqliteConnection, text: &str, dry_run: bool) -> Result<ImportReport> {
+    let mut report = None;
+    let result = conn.transaction::<_, Error, _>(|| {
+        let rows = import_rows(conn, text, dry_run)?;
+        let failed = rows.rows.iter().any(|row| row.error.is_some());
+        report = Some(rows);
+        if dry_run || failed {
+            Err(RollbackTransaction.into())
+        } else {
+            Ok(())
+        }
+    });
+    let imported = match result {
+        Ok(()) => true,
+        Err(Error(ErrorKind::Diesel(RollbackTransaction), _)) => false,
+        Err(e) => return Err(e).chain_err(|| "Failed to import CSV file"),
+    };
+
+    let mut report = report.unwrap();
+    report.imported = imported;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use settings;
+    use test_data;
+
+    fn fields(row: &[&str]) -> Vec<String> {
+        row.iter().map(|field| field.to_string()).collect()
+    }
+
+    #[test]
+    fn test_detect_delimiter() {
+        assert_eq!(
+            detect_delimiter("Name,Datum,Woche,Beginn,Ende,Bemerkung\n"),
+            b','
+        );
+        assert_eq!(
+            detect_delimiter("Name;Datum;Woche;Beginn;Ende;Bemerkung\nA, B;01.03.18;A"),
+            b';'
+        );
+        assert_eq!(detect_delimiter("Name\tDatum\tWoche"), b'\t');
+    }
+
+    #[test]
+    fn test_header() {
+        assert!(is_header(&fields(&[
+            "Name",
+            "Datum",
+            "Woche",
+            "Beginn",
+            "Ende",
+            "Bemerkung",
+        ])));
+        let row = fields(&["Anna", "05.03.18", "A", "12:00", "14:00", ""]);
+        assert!(!is_header(&row));
+    }
+
+    #[test]
+    fn test_parse_row() {
+        assert_eq!(
+            parse_row(&fields(&[
+                " Anna Schmidt ",
+                "05.03.2018",
+                "b",
+                "12:00",
+                "14:30",
+                "Ausflug",
+            ]))
+            .unwrap(),
+            CsvRow {
+                name: "Anna Schmidt".to_string(),
+                day: NaiveDate::from_ymd(2018, 3, 5),
+                type_of_week: Some(1),
+                start: NaiveTime::from_hms(12, 0, 0),
+                end: NaiveTime::from_hms(14, 30, 0),
+                remark: "Ausflug".to_string(),
+            }
+        );
+
+        let row = parse_row(&fields(&["Anna Schmidt", "05.03.18", "", "12:00", "14:30"])).unwrap();
+        assert_eq!(row.type_of_week, None);
+        assert_eq!(row.remark, "");
+    }
+
+    #[test]
+    fn test_invalid_rows() {
+        assert!(parse_row(&fields(&["Anna Schmidt", "05.03.18", "A", "12:00"])).is_err());
+        assert!(parse_row(&fields(&["", "05.03.18", "A", "12:00", "14:00", ""])).is_err());
+        assert!(parse_row(&fields(&["Anna", "2018-03-05", "A", "12:00", "14:00", ""])).is_err());
+        assert!(parse_row(&fields(&["Anna", "05.03.18", "E", "12:00", "14:00", ""])).is_err());
+        assert!(parse_row(&fields(&["Anna", "05.03.18", "A", "12 Uhr", "14:00", ""])).is_err());
+    }
+
+    fn row_report() -> RowReport {
+        RowReport::new(1, "")
+    }
+
+    #[test]
+    fn test_find_report() {
+        let conn = ::db::test_connection();
+        let march = test_data::report(&conn, "2018-03-01", "2018-03-15");
+        let mut reports = reports::get_all(&conn).unwrap();
+
+        let mut result = row_report();
+        let day = NaiveDate::from_ymd(2018, 3, 10);
+        assert_eq!(
+            find_report(&conn, &mut reports, day, &mut result).unwrap(),
+            march
+        );
+        assert_eq!(result.new_report, None);
+
+        let day = NaiveDate::from_ymd(2018, 12, 10);
+        let december = find_report(&conn, &mut reports, day, &mut result).unwrap();
+        assert_eq!(result.new_report, Some("Dezember 2018".to_string()));
+        let report = reports::get(&conn, december).unwrap();
+        assert_eq!(
+            (report.start_date.as_str(), report.end_date.as_str()),
+            ("2018-12-01", "2018-12-31")
+        );
+
+        let mut result = row_report();
+        let day = NaiveDate::from_ymd(2018, 12, 24);
+        assert_eq!(
+            find_report(&conn, &mut reports, day, &mut result).unwrap(),
+            december
+        );
+        assert_eq!(result.new_report, None);
+
+        // A report for all of March would overlap the existing one.
+        let day = NaiveDate::from_ymd(2018, 3, 20);
+        assert!(find_report(&conn, &mut reports, day, &mut result).is_err());
+        assert_eq!(reports::get_all(&conn).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_import_new_months() {
+        let conn = ::db::test_connection();
+        employees::insert(&conn, "Anna Schmidt").unwrap();
+        let weeks = vec![
+            weeks::NewWeek::new(NaiveDate::from_ymd(2018, 2, 5), 0),
+            weeks::NewWeek::new(NaiveDate::from_ymd(2018, 3, 5), 1),
+        ];
+        diesel::insert_into(::schema::weeks::table)
+            .values(&weeks)
+            .execute(&conn)
+            .unwrap();
+        weeks::reload(&conn);
+
+        let text = "Name;Datum;Woche;Beginn;Ende;Bemerkung\n\
+                    Anna Schmidt;05.02.18;A;12:00;14:00;\n\
+                    Anna Schmidt;06.02.18;A;12:00;14:00;\n\
+                    Anna Schmidt;05.03.18;B;12:00;14:00;\n";
+        let report = import_text(&conn, text, false).unwrap();
+        assert!(report.rows.iter().all(|row| row.error.is_none()));
+        assert!(report.imported);
+        assert_eq!(report.new_reports, vec!["Februar 2018", "März 2018"]);
+
+        let reports = reports::get_all(&conn).unwrap();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(report.rows[0].report_id, report.rows[1].report_id);
+        assert_ne!(report.rows[1].report_id, report.rows[2].report_id);
+    }
+
+    #[test]
+    fn test_find_employee() {
+        let conn = ::db::test_connection();
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+
+        let mut result = row_report();
+        let row = test_data::new_row("Anna Schmidt", "12:00", "14:00");
+        assert_eq!(find_employee(&conn, &row, &mut result).unwrap(), anna);
+        assert!(!result.new_employee);
+
+        let row = test_data::new_row("Anna Schmitt", "12:00", "14:00");
+        match find_employee(&conn, &row, &mut result) {
+            Err(Error(ErrorKind::UnknownEmployee(_, suggestions), _)) => {
+                assert_eq!(suggestions, vec!["Anna Schmidt"]);
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(!result.new_employee);
+
+        let row = test_data::new_row("Paul Peters", "12:00", "14:00");
+        assert!(find_employee(&conn, &row, &mut result).is_err());
+        assert!(!result.new_employee);
+
+        settings::set(&conn, items::STRICT_EMPLOYEES, "false").unwrap();
+        let row = test_data::new_row("Jan Berg", "12:00", "14:00");
+        assert_ne!(find_employee(&conn, &row, &mut result).unwrap(), anna);
+        assert!(result.new_employee);
+    }
+
+    #[test]
+    fn test_check_duplicate() {
+        let conn = ::db::test_connection();
+        let report = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+        let jan = employees::insert(&conn, "Jan Berg").unwrap();
+        test_data::item(&conn, report, anna, "2018-03-05", (12, 14), None);
+
+        let row = test_data::new_row("Anna Schmidt", "12:00", "14:00");
+        assert!(check_duplicate(&conn, anna, &row).is_err());
+        assert!(check_duplicate(&conn, jan, &row).is_ok());
+        let row = test_data::new_row("Anna Schmidt", "12:00", "13:00");
+        assert!(check_duplicate(&conn, anna, &row).is_ok());
+    }
+}
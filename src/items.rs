@@ -2,13 +2,22 @@ use chrono::{NaiveDate, NaiveTime};
 use diesel::prelude::*;
 use diesel::{self, SqliteConnection};
 
+use absences;
 use employees;
 use errors::*;
 use schema::items;
+use settings;
 
 use DATE_FORMAT;
 use TIME_FORMAT;
 
+/// Setting for whether items have to refer to existing employees. If this is false, an employee
+/// is created for every name that is not known yet.
+///
+/// On by default. The frontend shows the suggestions of `UnknownEmployee` and lets the user create
+/// the employee explicitly instead.
+pub const STRICT_EMPLOYEES: &str = "strict_employees";
+
 lazy_static! {
     static ref START_DEFAULT: NaiveTime = NaiveTime::from_hms(13, 0, 0);
     static ref END_DEFAULT: NaiveTime = NaiveTime::from_hms(15, 30, 0);
@@ -18,12 +27,21 @@ lazy_static! {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NewRow {
     pub id: Option<i32>,
+
+    /// The employee who worked. If this is not given, the employee is looked up by `name`.
+    #[serde(default)]
+    pub employee_id: Option<i32>,
+
     pub name: String,
     pub day: String,
     pub type_of_week: i32,
     pub start_time: String,
     pub end_time: String,
     pub remark: String,
+
+    /// The employee who was supposed to work, if this is a substitution.
+    #[serde(default)]
+    pub substitutes_employee_id: Option<i32>,
 }
 
 /// Data needed to create a new row in the database.
@@ -51,6 +69,17 @@ pub struct InvoiceItem {
     pub start: NaiveTime,
     pub end: NaiveTime,
     pub remark: String,
+    pub substitutes_employee_id: Option<i32>,
+    pub substitutes_name: Option<String>,
+}
+
+/// The result of saving an item: the item as stored in the database, plus anything that looks
+/// suspicious about it but is not reason enough to reject it.
+#[derive(Serialize)]
+pub struct UpdatedItem {
+    #[serde(flatten)]
+    pub item: InvoiceItem,
+    pub warnings: Vec<String>,
 }
 
 impl InvoiceItem {
@@ -66,6 +95,8 @@ impl InvoiceItem {
             start: *START_DEFAULT,
             end: *END_DEFAULT,
             remark: "".into(),
+            substitutes_employee_id: None,
+            substitutes_name: None,
         }
     }
 }
@@ -103,6 +134,8 @@ pub fn template(conn: &SqliteConnection, report_id: i32) -> InvoiceItem {
         Ok(mut last) => {
             last.id = 0;
             last.name = "".into();
+            last.substitutes_employee_id = None;
+            last.substitutes_name = None;
             last
         }
         Err(e) => {
@@ -113,20 +146,89 @@ pub fn template(conn: &SqliteConnection, report_id: i32) -> InvoiceItem {
     }
 }
 
+/// Make sure the employee being substituted exists and is not the one doing the substituting.
+fn check_substitute(
+    conn: &SqliteConnection,
+    employee_id: i32,
+    substitutes_employee_id: Option<i32>,
+) -> Result<()> {
+    use schema::employees;
+
+    let substitute_id = match substitutes_employee_id {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+
+    if substitute_id == employee_id {
+        bail!("An employee cannot substitute for themselves");
+    }
+
+    employees::table
+        .select(employees::id)
+        .filter(employees::id.eq(substitute_id))
+        .first::<i32>(conn)
+        .map(|_| ())
+        .chain_err(|| format!("Unknown employee #{} in substitution", substitute_id))
+}
+
+/// Collect warnings about an item that is about to be saved.
+fn check_warnings(
+    conn: &SqliteConnection,
+    employee_id: i32,
+    date: NaiveDate,
+) -> Result<Vec<String>> {
+    let mut warnings = vec![];
+
+    if let Some(absence) = absences::on_day(conn, employee_id, date)? {
+        warnings.push(format!(
+            "Employee is absent from {} to {} ({})",
+            absence.start_date,
+            absence.end_date,
+            absence.reason_name()
+        ));
+    }
+
+    Ok(warnings)
+}
+
+/// Find the id of the employee an item belongs to.
+///
+/// Unless strict mode is turned off, unknown names are rejected with a list of similar names
+/// instead of creating a new employee, so typos do not result in additional employees.
+pub fn resolve_employee(conn: &SqliteConnection, new_row: &NewRow) -> Result<i32> {
+    if let Some(id) = new_row.employee_id {
+        return employees::get_by_id(conn, id).map(|employee| employee.id);
+    }
+
+    if !settings::get(conn, STRICT_EMPLOYEES, true)? {
+        return employees::insert(conn, &new_row.name)
+            .chain_err(|| format!("Failed to insert employee: {}", new_row.name));
+    }
+
+    match employees::find_by_name(conn, &new_row.name)? {
+        Some(id) => Ok(id),
+        None => {
+            let suggestions = employees::suggest(conn, &new_row.name)?;
+            Err(ErrorKind::UnknownEmployee(new_row.name.clone(), suggestions).into())
+        }
+    }
+}
+
 /// Update an item with a specific id, or create a new item if `id == 0`.
 pub fn update(
     conn: &SqliteConnection,
     report_id: i32,
     id: i32,
     new_row: &NewRow,
-) -> Result<InvoiceItem> {
+) -> Result<UpdatedItem> {
     use schema::{items, items_view};
 
     assert!(report_id >= 0);
     assert!(id >= 0);
 
-    let employee_id = employees::insert(conn, &new_row.name)
-        .chain_err(|| format!("Failed to insert employee: {}", new_row.name))?;
+    let employee_id = resolve_employee(conn, new_row)?;
+
+    check_substitute(conn, employee_id, new_row.substitutes_employee_id)?;
 
     let date = NaiveDate::parse_from_str(&new_row.day, DATE_FORMAT).chain_err(|| "Invalid date")?;
     let start_time =
@@ -136,15 +238,26 @@ pub fn update(
     let start_datetime = date.and_time(start_time);
     let end_datetime = date.and_time(end_time);
 
+    let employee = employees::get_by_id(conn, employee_id)?;
+    if !employee.is_employed_on(date)? {
+        bail!("{} was not employed on {}", employee.name, new_row.day);
+    }
+
+    let warnings = check_warnings(conn, employee_id, date)?;
+    for warning in &warnings {
+        warn!("Item {:?}: {}", new_row, warning);
+    }
+
     let new_item = (
         items::employee_id.eq(employee_id),
         items::report_id.eq(report_id),
         items::start_datetime.eq(format!("{}", start_datetime)),
         items::end_datetime.eq(format!("{}", end_datetime)),
         items::remark.eq(&new_row.remark),
+        items::substitutes_employee_id.eq(new_row.substitutes_employee_id),
     );
 
-    if id == 0 {
+    let item = if id == 0 {
         // Insert new item
         info!("Creating new item: {:?}", new_row);
         diesel::insert_into(items::table)
@@ -154,7 +267,7 @@ pub fn update(
         items_view::table
             .order(items_view::id.desc())
             .first::<InvoiceItem>(conn)
-            .chain_err(|| "Query failed")
+            .chain_err(|| "Query failed")?
     } else {
         // Update existing item
         info!("Updating item #{}: {:?}", id, new_row);
@@ -165,6 +278,50 @@ pub fn update(
         items_view::table
             .filter(items_view::id.eq(id))
             .first(conn)
-            .chain_err(|| "Query failed")
+            .chain_err(|| "Query failed")?
+    };
+
+    Ok(UpdatedItem { item, warnings })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_data;
+
+    #[test]
+    fn test_check_substitute() {
+        let conn = ::db::test_connection();
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+        let jan = employees::insert(&conn, "Jan Berg").unwrap();
+
+        assert!(check_substitute(&conn, anna, None).is_ok());
+        assert!(check_substitute(&conn, anna, Some(jan)).is_ok());
+        assert!(check_substitute(&conn, anna, Some(anna)).is_err());
+        assert!(check_substitute(&conn, anna, Some(jan + anna + 1)).is_err());
+    }
+
+    #[test]
+    fn test_resolve_employee() {
+        let conn = ::db::test_connection();
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+        let row = test_data::new_row("Anna Schmidt", "12:00", "14:00");
+        assert_eq!(resolve_employee(&conn, &row).unwrap(), anna);
+
+        let row = test_data::new_row("Anna Schmitt", "12:00", "14:00");
+        match resolve_employee(&conn, &row) {
+            Err(Error(ErrorKind::UnknownEmployee(name, suggestions), _)) => {
+                assert_eq!(name, "Anna Schmitt");
+                assert_eq!(suggestions, vec!["Anna Schmidt"]);
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        // New names only create employees if strict mode is turned off.
+        let row = test_data::new_row("Jan Berg", "12:00", "14:00");
+        assert!(resolve_employee(&conn, &row).is_err());
+        settings::set(&conn, STRICT_EMPLOYEES, "false").unwrap();
+        let jan = resolve_employee(&conn, &row).unwrap();
+        assert_ne!(jan, anna);
     }
 }
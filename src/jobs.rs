@@ -0,0 +1,320 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::{self, SqliteConnection};
+use error_chain::ChainedError;
+
+use db::Pool;
+use errors::*;
+use generate_pdf;
+use reports;
+use schema::pdf_jobs;
+
+pub const QUEUED: &str = "queued";
+pub const RUNNING: &str = "running";
+pub const FAILED: &str = "failed";
+pub const DONE: &str = "done";
+
+/// How often the worker looks for new jobs when nobody tells it about them.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A request to generate the PDF of a report.
+#[derive(Debug, Serialize, Queryable)]
+pub struct PdfJob {
+    pub id: i32,
+    pub report_id: i32,
+    /// Revision of the report when the job was created.
+    pub revision: i32,
+    pub status: String,
+    /// Why the job failed.
+    pub log: String,
+    /// The generated PDF, once the job is done.
+    pub archive_id: Option<i32>,
+    pub created_at: String,
+    pub finished_at: Option<String>,
+}
+
+/// Handle to the worker thread generating PDFs one after another.
+pub struct JobQueue {
+    wake: Mutex<Sender<()>>,
+}
+
+impl JobQueue {
+    /// Start the worker. Jobs left over from a previous run are picked up again.
+    pub fn start(pool: Pool) -> Result<Self> {
+        let conn = pool
+            .get()
+            .chain_err(|| "Failed to get database connection")?;
+        requeue_interrupted(&conn)?;
+
+        let (wake, wakeups) = mpsc::channel();
+        thread::Builder::new()
+            .name("pdf-jobs".to_string())
+            .spawn(move || work(&pool, &wakeups))
+            .chain_err(|| "Failed to start PDF job worker")?;
+
+        // Process leftover jobs right away.
+        wake.send(()).ok();
+        Ok(JobQueue {
+            wake: Mutex::new(wake),
+        })
+    }
+
+    /// Tell the worker that there is a new job.
+    pub fn notify(&self) {
+        if let Ok(wake) = self.wake.lock() {
+            // The worker only stops when the queue is dropped, so this cannot fail.
+            wake.send(()).ok();
+        }
+    }
+}
+
+/// Jobs that were running when the server stopped are started again from scratch.
+fn requeue_interrupted(conn: &SqliteConnection) -> Result<()> {
+    diesel::update(pdf_jobs::table.filter(pdf_jobs::status.eq(RUNNING)))
+        .set(pdf_jobs::status.eq(QUEUED))
+        .execute(conn)
+        .chain_err(|| "Failed to requeue interrupted PDF jobs")?;
+    Ok(())
+}
+
+fn work(pool: &Pool, wakeups: &Receiver<()>) {
+    loop {
+        match pool.get() {
+            Ok(conn) => {
+                while let Some(job) = next(&conn) {
+                    run(&conn, &job);
+                }
+            }
+            Err(e) => error!("PDF job worker cannot connect to the database: {}", e),
+        }
+
+        match wakeups.recv_timeout(POLL_INTERVAL) {
+            Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// The oldest queued job, if any.
+fn next(conn: &SqliteConnection) -> Option<PdfJob> {
+    pdf_jobs::table
+        .filter(pdf_jobs::status.eq(QUEUED))
+        .order(pdf_jobs::id)
+        .first(conn)
+        .optional()
+        .unwrap_or_else(|e| {
+            error!("Failed to get next PDF job: {}", e);
+            None
+        })
+}
+
+/// The current time in the format of SQLite's `CURRENT_TIMESTAMP`.
+fn now() -> String {
+    Utc::now().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+/// The message a thread panicked with, which is a string unless someone went out of their way.
+fn panic_message(panic: &Box<Any + Send>) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => panic
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "unknown cause".to_string()),
+    }
+}
+
+fn run(conn: &SqliteConnection, job: &PdfJob) {
+    info!(
+        "Generating PDF of report #{} (job #{})",
+        job.report_id, job.id
+    );
+    let started = diesel::update(pdf_jobs::table.find(job.id))
+        .set(pdf_jobs::status.eq(RUNNING))
+        .execute(conn);
+    if let Err(e) = started {
+        error!("Failed to start PDF job #{}: {}", job.id, e);
+        return;
+    }
+
+    // A panic must not take down the only worker, which would leave the job running forever.
+    let generated = panic::catch_unwind(AssertUnwindSafe(|| {
+        generate_pdf::generate(conn, job.report_id)
+    }))
+    .unwrap_or_else(|panic| {
+        Err(format!("Generating the PDF panicked: {}", panic_message(&panic)).into())
+    });
+
+    let finished = match generated {
+        Ok(archived) => diesel::update(pdf_jobs::table.find(job.id))
+            .set((
+                pdf_jobs::status.eq(DONE),
+                pdf_jobs::archive_id.eq(archived.id),
+                pdf_jobs::finished_at.eq(now()),
+            ))
+            .execute(conn),
+        Err(e) => {
+            warn!("PDF job #{} failed: {}", job.id, e);
+            diesel::update(pdf_jobs::table.find(job.id))
+                .set((
+                    pdf_jobs::status.eq(FAILED),
+                    pdf_jobs::log.eq(e.display_chain().to_string()),
+                    pdf_jobs::finished_at.eq(now()),
+                ))
+                .execute(conn)
+        }
+    };
+    if let Err(e) = finished {
+        error!("Failed to record result of PDF job #{}: {}", job.id, e);
+    }
+}
+
+/// The job generating the current revision of a report.
+fn pending(conn: &SqliteConnection, report_id: i32, revision: i32) -> QueryResult<Option<PdfJob>> {
+    pdf_jobs::table
+        .filter(pdf_jobs::report_id.eq(report_id))
+        .filter(pdf_jobs::revision.eq(revision))
+        .filter(pdf_jobs::status.eq_any(vec![QUEUED, RUNNING]))
+        .first(conn)
+        .optional()
+}
+
+/// Queue the generation of a report's PDF.
+///
+/// If the current revision of the report is already queued or being generated, that job is
+/// returned instead of creating another one.
+pub fn enqueue(conn: &SqliteConnection, queue: &JobQueue, report_id: i32) -> Result<PdfJob> {
+    let revision = reports::get(conn, report_id)?.revision;
+
+    if let Some(job) = pending(conn, report_id, revision)? {
+        return Ok(job);
+    }
+
+    let inserted = diesel::insert_into(pdf_jobs::table)
+        .values((
+            pdf_jobs::report_id.eq(report_id),
+            pdf_jobs::revision.eq(revision),
+        ))
+        .execute(conn);
+    match inserted {
+        // Someone else queued the same revision in the meantime.
+        Err(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        )) => {}
+        result => {
+            result.chain_err(|| format!("Failed to queue PDF of report #{}", report_id))?;
+            queue.notify();
+        }
+    }
+
+    // The worker may already have finished the job, so it is not necessarily pending anymore.
+    pdf_jobs::table
+        .filter(pdf_jobs::report_id.eq(report_id))
+        .filter(pdf_jobs::revision.eq(revision))
+        .order(pdf_jobs::id.desc())
+        .first(conn)
+        .chain_err(|| "Failed to get PDF job that was just queued")
+}
+
+pub fn get(conn: &SqliteConnection, id: i32) -> Result<PdfJob> {
+    pdf_jobs::table
+        .find(id)
+        .first(conn)
+        .chain_err(|| format!("Failed to get PDF job #{}", id))
+}
+
+/// The PDF generated by a job.
+pub fn pdf(conn: &SqliteConnection, id: i32) -> Result<Vec<u8>> {
+    let job = get(conn, id)?;
+    match job.archive_id {
+        Some(archive_id) if job.status == DONE => ::archive::read(conn, archive_id),
+        _ => bail!("PDF job #{} is {}", id, job.status),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use test_data;
+
+    /// A queue without a worker, so jobs stay queued until a test runs them.
+    fn queue() -> (JobQueue, Receiver<()>) {
+        let (wake, wakeups) = mpsc::channel();
+        let queue = JobQueue {
+            wake: Mutex::new(wake),
+        };
+        (queue, wakeups)
+    }
+
+    #[test]
+    fn test_enqueue_coalesces() {
+        let conn = ::db::test_connection();
+        let (queue, wakeups) = queue();
+        let report_id = test_data::report(&conn, "2018-03-01", "2018-03-31");
+
+        let job = enqueue(&conn, &queue, report_id).unwrap();
+        assert_eq!(job.status, QUEUED);
+        assert!(wakeups.try_recv().is_ok());
+
+        let again = enqueue(&conn, &queue, report_id).unwrap();
+        assert_eq!(again.id, job.id);
+        assert!(wakeups.try_recv().is_err());
+
+        // A new revision needs a new PDF.
+        diesel::update(::schema::reports::table.find(report_id))
+            .set(::schema::reports::title.eq("April 2018"))
+            .execute(&conn)
+            .unwrap();
+        let changed = enqueue(&conn, &queue, report_id).unwrap();
+        assert_ne!(changed.id, job.id);
+        assert_eq!(changed.revision, job.revision + 1);
+    }
+
+    #[test]
+    fn test_failed_job() {
+        let conn = ::db::test_connection();
+        let (queue, _wakeups) = queue();
+        let report_id = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        let job = enqueue(&conn, &queue, report_id).unwrap();
+
+        diesel::delete(::schema::reports::table.find(report_id))
+            .execute(&conn)
+            .unwrap();
+        run(&conn, &next(&conn).unwrap());
+
+        let failed = get(&conn, job.id).unwrap();
+        assert_eq!(failed.status, FAILED);
+        assert!(failed.log.contains(&format!("report #{}", report_id)));
+        assert!(failed.finished_at.is_some());
+        assert!(next(&conn).is_none());
+        assert!(pdf(&conn, job.id).is_err());
+
+        // The failed job does not block another attempt.
+        let report_id = test_data::report(&conn, "2018-04-01", "2018-04-30");
+        let first = enqueue(&conn, &queue, report_id).unwrap();
+        diesel::update(pdf_jobs::table.find(first.id))
+            .set(pdf_jobs::status.eq(FAILED))
+            .execute(&conn)
+            .unwrap();
+        let retry = enqueue(&conn, &queue, report_id).unwrap();
+        assert_ne!(retry.id, first.id);
+        assert_eq!(retry.status, QUEUED);
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let panic = panic::catch_unwind(|| panic!("Could not find report")).unwrap_err();
+        assert_eq!(panic_message(&panic), "Could not find report");
+        let panic = panic::catch_unwind(|| panic!("report #{}", 1)).unwrap_err();
+        assert_eq!(panic_message(&panic), "report #1");
+    }
+}
@@ -0,0 +1,75 @@
+/// Escape a string so that LaTeX prints it literally.
+///
+/// All ten special characters of TeX are replaced by commands printing them, so user-supplied
+/// fields cannot break the document or inject commands like `\input`. Everything else, including
+/// umlauts and other Unicode characters, is passed through, since XeLaTeX handles it natively.
+///
+/// This is used as the escape function of the Handlebars instance rendering `template.tex.hbs`.
+/// Values that already contain LaTeX markup have to be inserted with `{{{triple braces}}}`.
+pub fn escape(data: &str) -> String {
+    let mut result = String::with_capacity(data.len());
+    for c in data.chars() {
+        match c {
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                result.push('\\');
+                result.push(c);
+            }
+            '~' => result.push_str("\\textasciitilde{}"),
+            '^' => result.push_str("\\textasciicircum{}"),
+            '\\' => result.push_str("\\textbackslash{}"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plain_text() {
+        assert_eq!(escape(""), "");
+        assert_eq!(escape("Anna Schmidt"), "Anna Schmidt");
+        assert_eq!(
+            escape("Hausaufgaben (Klasse 3b)."),
+            "Hausaufgaben (Klasse 3b)."
+        );
+    }
+
+    #[test]
+    fn test_special_characters() {
+        assert_eq!(escape("&"), "\\&");
+        assert_eq!(escape("%"), "\\%");
+        assert_eq!(escape("$"), "\\$");
+        assert_eq!(escape("#"), "\\#");
+        assert_eq!(escape("_"), "\\_");
+        assert_eq!(escape("{"), "\\{");
+        assert_eq!(escape("}"), "\\}");
+        assert_eq!(escape("~"), "\\textasciitilde{}");
+        assert_eq!(escape("^"), "\\textasciicircum{}");
+        assert_eq!(escape("\\"), "\\textbackslash{}");
+        assert_eq!(
+            escape("Vertretung 50% & Hausaufgaben"),
+            "Vertretung 50\\% \\& Hausaufgaben"
+        );
+    }
+
+    #[test]
+    fn test_commands_are_not_executed() {
+        assert_eq!(
+            escape("\\input{/etc/passwd}"),
+            "\\textbackslash{}input\\{/etc/passwd\\}"
+        );
+        assert_eq!(escape("\\\\"), "\\textbackslash{}\\textbackslash{}");
+    }
+
+    #[test]
+    fn test_unicode() {
+        assert_eq!(escape("Jürgen Weiß"), "Jürgen Weiß");
+        assert_eq!(escape("Zoë Ørsted-Łukasiewicz"), "Zoë Ørsted-Łukasiewicz");
+        assert_eq!(escape("Ἀριστοτέλης"), "Ἀριστοτέλης");
+        assert_eq!(escape("Müller & Söhne"), "Müller \\& Söhne");
+        assert_eq!(escape("5 €"), "5 €");
+    }
+}
@@ -1,4 +1,4 @@
-#![feature(plugin)]
+#![feature(plugin, custom_derive)]
 #![plugin(rocket_codegen)]
 #![recursion_limit = "128"]
 
@@ -21,49 +21,100 @@ extern crate dotenv_codegen;
 #[macro_use]
 extern crate error_chain;
 
+// Lock the database while the server is running.
+extern crate fs2;
+
 extern crate handlebars;
 
 #[macro_use]
 extern crate lazy_static;
 
+// SQLite's online backup API, which Diesel does not expose.
+extern crate libsqlite3_sys;
+
+#[cfg(feature = "native-pdf")]
+extern crate lopdf;
+
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "native-pdf")]
+extern crate printpdf;
+
 extern crate r2d2;
 extern crate r2d2_diesel;
+extern crate rand;
 
 extern crate rocket;
-#[macro_use]
 extern crate rocket_contrib;
 extern crate rocket_cors;
 
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
+extern crate sha2;
 
 // Create a secure temporary directory to handle the LaTeX side of things.
 extern crate tempdir;
 
+extern crate zip;
+
+use std::env;
 use std::path::{Path, PathBuf};
 
 use rocket::http::Method;
 use rocket::response::NamedFile;
+use rocket::Rocket;
 use rocket_cors::{AllowedHeaders, AllowedOrigins};
 
+pub mod absences;
+pub mod aggregations;
 pub mod api;
+pub mod archive;
+pub mod batch;
+pub mod calendar;
+pub mod cli;
+pub mod compliance;
+pub mod coverage;
 pub mod db;
+pub mod download;
+pub mod dump;
 pub mod employees;
+pub mod export;
 pub mod generate_pdf;
 pub mod holidays;
+pub mod html;
+pub mod import;
 pub mod items;
+pub mod jobs;
+pub mod latex;
+pub mod merges;
+pub mod money;
+pub mod names;
+#[cfg(feature = "native-pdf")]
+pub mod native_pdf;
+pub mod ods;
+pub mod rates;
 pub mod reports;
 pub mod schema;
+pub mod settings;
+pub mod snapshots;
+pub mod statistics;
+pub mod substitutions;
+pub mod templates;
+#[cfg(test)]
+mod test_data;
 pub mod weeks;
 
 pub const DATE_FORMAT: &str = "%Y-%m-%d";
 pub const TIME_FORMAT: &str = "%H:%M";
 
+/// The window during which supervision is provided on school days.
+pub const MIN_TIME: &str = "12:00";
+pub const MAX_TIME: &str = "16:00";
+
 // From https://jamesmunns.com/update/2017/07/22/rocket-plus-error-chain.html
 mod errors {
     use std::io::Cursor;
@@ -79,6 +130,25 @@ mod errors {
             Json(::serde_json::Error);
             Diesel(::diesel::result::Error);
         }
+
+        errors {
+            UnknownEmployee(name: String, suggestions: Vec<String>) {
+                description("unknown employee")
+                display("Unknown employee: {}{}", name, if suggestions.is_empty() {
+                    "".to_string()
+                } else {
+                    format!(" (did you mean {}?)", suggestions.join(", "))
+                })
+            }
+
+            LatexError(message: String, line: Option<usize>, context: String) {
+                description("LaTeX error")
+                display("LaTeX error: {}{}", message, match *line {
+                    Some(line) => format!(" (line {}: {})", line, context),
+                    None => "".to_string(),
+                })
+            }
+        }
     }
 
     impl<'r> Responder<'r> for Error {
@@ -89,9 +159,19 @@ mod errors {
                 msg.push_str(&format!(", caused by: {}", err));
             }
 
+            // Lets the frontend offer the suggestions, or to create the employee.
+            let (unknown_employee, suggestions) = match *self.kind() {
+                ErrorKind::UnknownEmployee(ref name, ref suggestions) => {
+                    (Some(name.clone()), suggestions.clone())
+                }
+                _ => (None, vec![]),
+            };
+
             let resp = json!({
                 "status": "failure",
                 "message": msg,
+                "unknown_employee": unknown_employee,
+                "suggestions": suggestions,
             }).to_string();
 
             // Respond. The `Ok` here is a bit of a misnomer. It means we
@@ -125,7 +205,21 @@ fn files(file: PathBuf) -> Result<NamedFile> {
         .chain_err(|| format!("File not found: {:?}", file))
 }
 
+/// Mount all routes. The calendar feeds are outside of the API, so that they can be made public
+/// on their own.
+fn mount(rocket: Rocket) -> Rocket {
+    rocket
+        .mount("/", routes![index, files])
+        .mount("/api/", api::routes())
+        .mount(calendar::MOUNT_POINT, api::calendar_routes())
+}
+
 fn run() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if cli::run(&args)? {
+        return Ok(());
+    }
+
     let (allowed_origins, _failed_origins) = AllowedOrigins::some(&["http://localhost:8080"]);
     let options = rocket_cors::Cors {
         allowed_origins,
@@ -138,15 +232,20 @@ fn run() -> Result<()> {
         ..Default::default()
     };
 
-    let rocket = rocket::ignite()
-        .manage(db::init_pool())
-        .mount("/", routes![index, files])
-        .mount("/api/", api::routes())
+    let _lock = snapshots::lock_database()?;
+    let pool = db::init_pool();
+    let jobs = jobs::JobQueue::start(pool.clone())?;
+    snapshots::schedule(pool.clone())?;
+
+    let rocket = mount(rocket::ignite())
+        .manage(pool)
+        .manage(jobs)
         .attach(options);
 
     let conn = db::connect();
     holidays::populate_table(&conn);
     weeks::populate_table(&conn)?;
+    employees::fix_legacy_sort_keys(&conn)?;
 
     rocket.launch();
 
@@ -166,3 +265,13 @@ fn main() {
         ::std::process::exit(1);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use rocket::local::Client;
+
+    #[test]
+    fn test_routes() {
+        assert!(Client::new(super::mount(::rocket::ignite())).is_ok());
+    }
+}
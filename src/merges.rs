@@ -0,0 +1,492 @@
+use std::collections::HashMap;
+
+use diesel::prelude::*;
+use diesel::{self, SqliteConnection};
+
+use employees::{self, Employee};
+use errors::*;
+use rates::HourlyRate;
+use schema::{
+    absences, calendar_tokens, employee_merges, employees as employees_table, hourly_rates, items,
+};
+use snapshots;
+
+/// Request to merge one or more duplicate employees into another one.
+#[derive(Debug, Deserialize)]
+pub struct MergeRequest {
+    /// The employees to be merged into the target. They are removed afterwards.
+    pub sources: Vec<i32>,
+
+    /// Only check for collisions, without changing anything.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// An item that cannot be moved to the target employee.
+#[derive(Debug, Serialize)]
+pub struct Collision {
+    pub item_id: i32,
+
+    /// The item starting or ending at the same time, if that is the problem.
+    pub conflicting_item_id: Option<i32>,
+    pub start_datetime: String,
+    pub end_datetime: String,
+
+    /// Why the item cannot be moved.
+    pub message: String,
+}
+
+/// What a merge did, or would do in a dry run.
+///
+/// If there are collisions, nothing was changed.
+#[derive(Debug, Serialize)]
+pub struct MergeResult {
+    /// Id of the audit record, which can be used to undo the merge.
+    pub merge_id: Option<i32>,
+    pub moved_items: usize,
+    pub collisions: Vec<Collision>,
+}
+
+/// Everything needed to undo a merge, stored as JSON in `employee_merges.undo_data`.
+#[derive(Debug, Serialize, Deserialize)]
+struct UndoData {
+    /// The employees that were removed.
+    sources: Vec<Employee>,
+
+    /// (item id, original employee id) for every item that was moved.
+    items: Vec<(i32, i32)>,
+
+    /// (item id, original employee id) for every item where one of the sources was substituted.
+    substitutions: Vec<(i32, i32)>,
+
+    /// (absence id, original employee id) for every absence that was moved.
+    absences: Vec<(i32, i32)>,
+
+    /// Hourly rates of the sources, which were dropped in favour of the target's.
+    rates: Vec<HourlyRate>,
+}
+
+/// Summary of a merge in the audit log.
+#[derive(Debug, Serialize)]
+pub struct MergeRecord {
+    pub id: i32,
+    pub target_id: i32,
+    pub merged_at: String,
+    pub undone: bool,
+
+    /// Names of the employees that were merged into the target.
+    pub sources: Vec<String>,
+    pub moved_items: usize,
+}
+
+/// Find items that would violate the constraints of the `items` table if they all belonged to
+/// the same employee: `UNIQUE (employee_id, start_datetime)`, `UNIQUE (employee_id, end_datetime)`
+/// and `CHECK (substitutes_employee_id <> employee_id)`.
+fn find_collisions(conn: &SqliteConnection, employee_ids: &[i32]) -> Result<Vec<Collision>> {
+    let rows = items::table
+        .select((
+            items::id,
+            items::start_datetime,
+            items::end_datetime,
+            items::substitutes_employee_id,
+        ))
+        .filter(items::employee_id.eq_any(employee_ids))
+        .order(items::id.asc())
+        .load::<(i32, String, String, Option<i32>)>(conn)
+        .chain_err(|| "Failed to query items")?;
+
+    let mut starts = HashMap::new();
+    let mut ends = HashMap::new();
+    let mut collisions = vec![];
+
+    for (id, start, end, substitute) in rows {
+        if substitute.map_or(false, |substitute| employee_ids.contains(&substitute)) {
+            collisions.push(Collision {
+                item_id: id,
+                conflicting_item_id: None,
+                start_datetime: start.clone(),
+                end_datetime: end.clone(),
+                message: format!(
+                    "Item #{} is a substitution between two of the merged employees",
+                    id
+                ),
+            });
+        }
+
+        let conflict = starts.get(&start).or_else(|| ends.get(&end)).cloned();
+        match conflict {
+            Some(conflicting_item_id) => collisions.push(Collision {
+                item_id: id,
+                conflicting_item_id: Some(conflicting_item_id),
+                start_datetime: start,
+                end_datetime: end,
+                message: format!(
+                    "Items #{} and #{} start or end at the same time",
+                    conflicting_item_id, id
+                ),
+            }),
+            None => {
+                starts.insert(start, id);
+                ends.insert(end, id);
+            }
+        }
+    }
+
+    Ok(collisions)
+}
+
+/// Move all items of the `sources` to the `target` employee and remove the sources.
+///
+/// Absences and substitutions of the sources are moved as well, their hourly rates and calendar
+/// links are dropped. Undoing the merge does not bring the links back.
+/// Everything happens in a single transaction. If any items would collide, nothing is changed and
+/// the collisions are returned instead. The merge is recorded in the `employee_merges` table so
+/// that it can be undone, and a snapshot of the database is taken right before anything changes.
+pub fn merge(conn: &SqliteConnection, target: i32, request: &MergeRequest) -> Result<MergeResult> {
+    let mut source_ids = request.sources.clone();
+    source_ids.sort();
+    source_ids.dedup();
+
+    if source_ids.is_empty() {
+        bail!("Nothing to merge into employee #{}", target);
+    }
+    if source_ids.contains(&target) {
+        bail!("Cannot merge employee #{} into itself", target);
+    }
+
+    conn.transaction::<_, Error, _>(|| {
+        employees::get_by_id(conn, target)?;
+        let sources = source_ids
+            .iter()
+            .map(|&id| employees::get_by_id(conn, id))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut all_ids = source_ids.clone();
+        all_ids.push(target);
+        let collisions = find_collisions(conn, &all_ids)?;
+
+        let moved = items::table
+            .select((items::id, items::employee_id))
+            .filter(items::employee_id.eq_any(&source_ids))
+            .load::<(i32, i32)>(conn)?;
+
+        if !collisions.is_empty() || request.dry_run {
+            return Ok(MergeResult {
+                merge_id: None,
+                moved_items: moved.len(),
+                collisions,
+            });
+        }
+
+        // Nothing has been written yet, so the snapshot shows the state before the merge.
+        snapshots::take(conn, "merge")?;
+
+        let undo = UndoData {
+            sources,
+            substitutions: items::table
+                .select((items::id, items::substitutes_employee_id))
+                .filter(items::substitutes_employee_id.eq_any(&source_ids))
+                .load::<(i32, Option<i32>)>(conn)?
+                .into_iter()
+                .filter_map(|(id, substitute)| substitute.map(|substitute| (id, substitute)))
+                .collect(),
+            absences: absences::table
+                .select((absences::id, absences::employee_id))
+                .filter(absences::employee_id.eq_any(&source_ids))
+                .load(conn)?,
+            rates: hourly_rates::table
+                .filter(hourly_rates::employee_id.eq_any(&source_ids))
+                .load(conn)?,
+            items: moved,
+        };
+
+        diesel::update(items::table.filter(items::employee_id.eq_any(&source_ids)))
+            .set(items::employee_id.eq(target))
+            .execute(conn)?;
+        diesel::update(items::table.filter(items::substitutes_employee_id.eq_any(&source_ids)))
+            .set(items::substitutes_employee_id.eq(target))
+            .execute(conn)?;
+        diesel::update(absences::table.filter(absences::employee_id.eq_any(&source_ids)))
+            .set(absences::employee_id.eq(target))
+            .execute(conn)?;
+        diesel::delete(hourly_rates::table.filter(hourly_rates::employee_id.eq_any(&source_ids)))
+            .execute(conn)?;
+        diesel::delete(
+            calendar_tokens::table.filter(calendar_tokens::employee_id.eq_any(&source_ids)),
+        )
+        .execute(conn)?;
+        diesel::delete(employees_table::table.filter(employees_table::id.eq_any(&source_ids)))
+            .execute(conn)?;
+
+        diesel::insert_into(employee_merges::table)
+            .values((
+                employee_merges::target_id.eq(target),
+                employee_merges::undo_data.eq(::serde_json::to_string(&undo)?),
+            ))
+            .execute(conn)?;
+        let merge_id = employee_merges::table
+            .select(employee_merges::id)
+            .order(employee_merges::id.desc())
+            .first(conn)?;
+
+        info!(
+            "Merged employees {:?} into #{} (merge #{})",
+            source_ids, target, merge_id
+        );
+
+        Ok(MergeResult {
+            merge_id: Some(merge_id),
+            moved_items: undo.items.len(),
+            collisions: vec![],
+        })
+    })
+}
+
+/// Restore the state from before a merge: recreate the source employees and give them back their
+/// items, substitutions, absences and hourly rates.
+pub fn undo(conn: &SqliteConnection, merge_id: i32) -> Result<()> {
+    conn.transaction::<_, Error, _>(|| {
+        let (undo_data, undone) = employee_merges::table
+            .select((employee_merges::undo_data, employee_merges::undone))
+            .filter(employee_merges::id.eq(merge_id))
+            .first::<(String, bool)>(conn)
+            .chain_err(|| format!("Failed to get merge #{}", merge_id))?;
+        if undone {
+            bail!("Merge #{} has already been undone", merge_id);
+        }
+        let undo: UndoData = ::serde_json::from_str(&undo_data)?;
+        snapshots::take(conn, "undo-merge")?;
+
+        for employee in &undo.sources {
+            diesel::insert_into(employees_table::table)
+                .values((
+                    employees_table::id.eq(employee.id),
+                    employees_table::name.eq(&employee.name),
+                    employees_table::name_sort.eq(&employee.name_sort),
+                    employees_table::contract_type.eq(&employee.contract_type),
+                    employees_table::employment_start.eq(&employee.employment_start),
+                    employees_table::employment_end.eq(&employee.employment_end),
+                    employees_table::active.eq(employee.active),
+                ))
+                .execute(conn)
+                .chain_err(|| format!("Failed to restore employee {}", employee.name))?;
+        }
+
+        for &(item_id, employee_id) in &undo.items {
+            diesel::update(items::table.filter(items::id.eq(item_id)))
+                .set(items::employee_id.eq(employee_id))
+                .execute(conn)?;
+        }
+        for &(item_id, employee_id) in &undo.substitutions {
+            diesel::update(items::table.filter(items::id.eq(item_id)))
+                .set(items::substitutes_employee_id.eq(employee_id))
+                .execute(conn)?;
+        }
+        for &(absence_id, employee_id) in &undo.absences {
+            diesel::update(absences::table.filter(absences::id.eq(absence_id)))
+                .set(absences::employee_id.eq(employee_id))
+                .execute(conn)?;
+        }
+        for rate in &undo.rates {
+            diesel::insert_into(hourly_rates::table)
+                .values((
+                    hourly_rates::id.eq(rate.id),
+                    hourly_rates::employee_id.eq(rate.employee_id),
+                    hourly_rates::effective_from.eq(&rate.effective_from),
+                    hourly_rates::rate_cents.eq(rate.rate_cents),
+                ))
+                .execute(conn)?;
+        }
+
+        diesel::update(employee_merges::table.filter(employee_merges::id.eq(merge_id)))
+            .set(employee_merges::undone.eq(true))
+            .execute(conn)?;
+
+        info!("Undid merge #{}", merge_id);
+        Ok(())
+    })
+}
+
+/// Get the audit log of all merges, most recent first.
+pub fn get_all(conn: &SqliteConnection) -> Result<Vec<MergeRecord>> {
+    employee_merges::table
+        .select((
+            employee_merges::id,
+            employee_merges::target_id,
+            employee_merges::merged_at,
+            employee_merges::undo_data,
+            employee_merges::undone,
+        ))
+        .order(employee_merges::id.desc())
+        .load::<(i32, i32, String, String, bool)>(conn)
+        .chain_err(|| "Failed to read table employee_merges")?
+        .into_iter()
+        .map(|(id, target_id, merged_at, undo_data, undone)| {
+            let undo: UndoData = ::serde_json::from_str(&undo_data)?;
+            Ok(MergeRecord {
+                id,
+                target_id,
+                merged_at,
+                undone,
+                sources: undo.sources.into_iter().map(|e| e.name).collect(),
+                moved_items: undo.items.len(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tempdir::TempDir;
+
+    use absences::{self, Absence};
+    use archive;
+    use calendar;
+    use settings;
+    use test_data;
+
+    /// A database in a file, as merges take snapshots, which end up in the same directory.
+    fn database() -> (TempDir, SqliteConnection) {
+        let dir = TempDir::new("merges").unwrap();
+        let conn = ::db::test_database(dir.path().join("db.sqlite").to_str().unwrap());
+        settings::set(&conn, archive::DATA_DIR, dir.path().to_str().unwrap()).unwrap();
+        (dir, conn)
+    }
+
+    fn request(sources: Vec<i32>, dry_run: bool) -> MergeRequest {
+        MergeRequest { sources, dry_run }
+    }
+
+    fn employee_of(conn: &SqliteConnection, item_id: i32) -> (i32, Option<i32>) {
+        items::table
+            .select((items::employee_id, items::substitutes_employee_id))
+            .find(item_id)
+            .first(conn)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_merge_and_undo() {
+        let (_dir, conn) = database();
+        let report = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+        let typo = employees::insert(&conn, "Anna Schmitt").unwrap();
+        let jan = employees::insert(&conn, "Jan Berg").unwrap();
+
+        let kept = test_data::item(&conn, report, anna, "2018-03-05", (12, 14), None);
+        let moved = test_data::item(&conn, report, typo, "2018-03-06", (12, 14), None);
+        let substitution = test_data::item(&conn, report, jan, "2018-03-07", (12, 14), Some(typo));
+        let absence = Absence {
+            id: 0,
+            employee_id: typo,
+            start_date: "2018-03-12".to_string(),
+            end_date: "2018-03-16".to_string(),
+            reason: "sick".to_string(),
+            remark: String::new(),
+        };
+        let absence_id = absences::insert(&conn, &absence).unwrap();
+        calendar::link(&conn, typo).unwrap();
+
+        // Listing a source twice does not matter.
+        let result = merge(&conn, anna, &request(vec![typo, typo], false)).unwrap();
+        assert!(result.collisions.is_empty());
+        assert_eq!(result.moved_items, 1);
+        assert_eq!(employee_of(&conn, kept), (anna, None));
+        assert_eq!(employee_of(&conn, moved), (anna, None));
+        assert_eq!(employee_of(&conn, substitution), (jan, Some(anna)));
+        assert_eq!(absences::get_for_employee(&conn, anna).unwrap().len(), 1);
+        assert!(employees::get_by_id(&conn, typo).is_err());
+        assert_eq!(calendar_tokens::table.count().get_result(&conn), Ok(0));
+        assert_eq!(snapshots::list(&conn).unwrap()[0].reason, "merge");
+
+        let records = get_all(&conn).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].sources, vec!["Anna Schmitt"]);
+        assert!(!records[0].undone);
+
+        let merge_id = result.merge_id.unwrap();
+        undo(&conn, merge_id).unwrap();
+        assert_eq!(
+            employees::get_by_id(&conn, typo).unwrap().name,
+            "Anna Schmitt"
+        );
+        assert_eq!(employee_of(&conn, kept), (anna, None));
+        assert_eq!(employee_of(&conn, moved), (typo, None));
+        assert_eq!(employee_of(&conn, substitution), (jan, Some(typo)));
+        let restored = absences::get_for_employee(&conn, typo).unwrap();
+        assert_eq!(restored[0].id, absence_id);
+        assert!(get_all(&conn).unwrap()[0].undone);
+        assert!(undo(&conn, merge_id).is_err());
+
+        let reasons: Vec<_> = snapshots::list(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|snapshot| snapshot.reason)
+            .collect();
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons.contains(&"undo-merge".to_string()));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let (_dir, conn) = database();
+        let report = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+        let typo = employees::insert(&conn, "Anna Schmitt").unwrap();
+        let item = test_data::item(&conn, report, typo, "2018-03-06", (12, 14), None);
+
+        let result = merge(&conn, anna, &request(vec![typo], true)).unwrap();
+        assert_eq!(result.merge_id, None);
+        assert_eq!(result.moved_items, 1);
+        assert!(result.collisions.is_empty());
+        assert_eq!(employee_of(&conn, item), (typo, None));
+        assert!(employees::get_by_id(&conn, typo).is_ok());
+        assert!(get_all(&conn).unwrap().is_empty());
+        assert!(snapshots::list(&conn).unwrap().is_empty());
+
+        assert!(merge(&conn, anna, &request(vec![], false)).is_err());
+        assert!(merge(&conn, anna, &request(vec![typo, anna], false)).is_err());
+        assert!(merge(&conn, anna, &request(vec![typo + anna + 1], false)).is_err());
+    }
+
+    #[test]
+    fn test_collisions() {
+        let (_dir, conn) = database();
+        let report = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+        let typo = employees::insert(&conn, "Anna Schmitt").unwrap();
+
+        let first = test_data::item(&conn, report, anna, "2018-03-05", (12, 14), None);
+        let second = test_data::item(&conn, report, typo, "2018-03-05", (12, 15), None);
+
+        let result = merge(&conn, anna, &request(vec![typo], false)).unwrap();
+        assert_eq!(result.merge_id, None);
+        assert_eq!(result.collisions.len(), 1);
+        assert_eq!(result.collisions[0].item_id, second);
+        assert_eq!(result.collisions[0].conflicting_item_id, Some(first));
+        assert_eq!(employee_of(&conn, second), (typo, None));
+    }
+
+    #[test]
+    fn test_substitution_collisions() {
+        let (_dir, conn) = database();
+        let report = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+        let typo = employees::insert(&conn, "Anna Schmitt").unwrap();
+
+        // Both would become substitutions of Anna for herself.
+        let of_target = test_data::item(&conn, report, typo, "2018-03-05", (12, 14), Some(anna));
+        let of_source = test_data::item(&conn, report, anna, "2018-03-06", (12, 14), Some(typo));
+
+        let result = merge(&conn, anna, &request(vec![typo], false)).unwrap();
+        assert_eq!(result.merge_id, None);
+        let items: Vec<_> = result.collisions.iter().map(|c| c.item_id).collect();
+        assert_eq!(items, vec![of_target, of_source]);
+        for collision in &result.collisions {
+            assert_eq!(collision.conflicting_item_id, None);
+        }
+        assert!(employees::get_by_id(&conn, typo).is_ok());
+    }
+}
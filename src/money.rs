@@ -0,0 +1,56 @@
+/// What working for `minutes` minutes at an hourly rate of `rate_cents` earns, in cents.
+///
+/// The result is rounded to the nearest cent, with half a cent being rounded up. All amounts are
+/// kept as whole cents, so this is the only place where rounding happens.
+pub fn amount_cents(rate_cents: i32, minutes: i64) -> i64 {
+    let exact_times_60 = i64::from(rate_cents) * minutes;
+    if exact_times_60 >= 0 {
+        (exact_times_60 + 30) / 60
+    } else {
+        (exact_times_60 - 30) / 60
+    }
+}
+
+/// Format an amount of money the way it is written in Germany, e.g. "1.234,50 €".
+pub fn format_euro(cents: i64) -> String {
+    let sign = if cents < 0 { "-" } else { "" };
+    let cents = cents.abs();
+
+    let euros = format!("{}", cents / 100);
+    let mut grouped = String::new();
+    for (i, digit) in euros.chars().enumerate() {
+        if i > 0 && (euros.len() - i) % 3 == 0 {
+            grouped.push('.');
+        }
+        grouped.push(digit);
+    }
+
+    format!("{}{},{:02} €", sign, grouped, cents % 100)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_amount_cents() {
+        assert_eq!(amount_cents(1000, 60), 1000);
+        assert_eq!(amount_cents(1000, 150), 2500);
+        assert_eq!(amount_cents(0, 150), 0);
+        // 9,50 € for 20 minutes are 3,1666… €
+        assert_eq!(amount_cents(950, 20), 317);
+        // 8,85 € for 10 minutes are 1,475 €, which has to be rounded up
+        assert_eq!(amount_cents(885, 10), 148);
+    }
+
+    #[test]
+    fn test_format_euro() {
+        assert_eq!(format_euro(0), "0,00 €");
+        assert_eq!(format_euro(5), "0,05 €");
+        assert_eq!(format_euro(1250), "12,50 €");
+        assert_eq!(format_euro(45000), "450,00 €");
+        assert_eq!(format_euro(123456), "1.234,56 €");
+        assert_eq!(format_euro(123456789), "1.234.567,89 €");
+        assert_eq!(format_euro(-1250), "-12,50 €");
+    }
+}
@@ -0,0 +1,209 @@
+use std::cmp::Ordering;
+
+/// Particles of German and Dutch last names that are ignored when sorting by last name.
+///
+/// "Hans von Müller" is sorted under M, "Jan van der Berg" under B.
+pub const DEFAULT_PARTICLES: [&str; 13] = [
+    "von", "vom", "zu", "zum", "zur", "und", "van", "de", "der", "den", "ter", "ten", "'t",
+];
+
+/// A person's name split into its components.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PersonName {
+    /// All given names, separated by spaces.
+    pub first_names: String,
+
+    /// Particles like "von" or "van der" that belong to the last name but are not used for
+    /// sorting.
+    pub particles: String,
+
+    /// The last name without particles.
+    pub last_name: String,
+}
+
+/// Is `word` one of the `particles`? The comparison ignores case, so "Van" and "van" both count.
+fn is_particle<S: AsRef<str>>(word: &str, particles: &[S]) -> bool {
+    let word = word.to_lowercase();
+    particles.iter().any(|particle| particle.as_ref() == word)
+}
+
+impl PersonName {
+    /// Split a full name into first names, particles and last name.
+    ///
+    /// The last word is taken to be the last name, preceded by any number of particles. Everything
+    /// before that is a first name. A name consisting of a single word is a last name.
+    pub fn parse<S: AsRef<str>>(full_name: &str, particles: &[S]) -> Self {
+        let words: Vec<_> = full_name.split_whitespace().collect();
+        if words.is_empty() {
+            return PersonName::from_parts("", "", particles);
+        }
+
+        let last = words.len() - 1;
+        let mut first_particle = last;
+        while first_particle > 1 && is_particle(words[first_particle - 1], particles) {
+            first_particle -= 1;
+        }
+
+        PersonName {
+            first_names: words[..first_particle].join(" "),
+            particles: words[first_particle..last].join(" "),
+            last_name: words[last].to_string(),
+        }
+    }
+
+    /// Combine explicitly given first and last names.
+    ///
+    /// Leading particles are split off the last name, so "van der Berg" is sorted under B, but a
+    /// last name consisting of several words, like "Müller Lüdenscheidt", is kept as it is.
+    pub fn from_parts<S: AsRef<str>>(first_names: &str, last_name: &str, particles: &[S]) -> Self {
+        let words: Vec<_> = last_name.split_whitespace().collect();
+        let num_particles = words
+            .iter()
+            .take(words.len().saturating_sub(1))
+            .take_while(|word| is_particle(word, particles))
+            .count();
+
+        PersonName {
+            first_names: first_names.split_whitespace().collect::<Vec<_>>().join(" "),
+            particles: words[..num_particles].join(" "),
+            last_name: words[num_particles..].join(" "),
+        }
+    }
+
+    /// The name as it appears on the report, e.g. "Jan van der Berg".
+    pub fn full_name(&self) -> String {
+        [&self.first_names, &self.particles, &self.last_name]
+            .iter()
+            .filter(|part| !part.is_empty())
+            .map(|part| part.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// The value of `employees::name_sort`, e.g. "Berg, Jan van der".
+    ///
+    /// For names without first names, this is "<last name>, ".
+    pub fn sort_key(&self) -> String {
+        let mut key = self.last_name.clone();
+        key.push_str(", ");
+        key.push_str(&self.first_names);
+        if !self.particles.is_empty() {
+            key.push(' ');
+            key.push_str(&self.particles);
+        }
+        key
+    }
+}
+
+/// Map a string to a key which sorts the way a German dictionary does (DIN 5007, variant 1).
+///
+/// Case is ignored, umlauts are sorted like the corresponding vowel (Ä with A), ß like "ss", and
+/// accented letters like the letter without the accent.
+pub fn collation_key(s: &str) -> String {
+    let mut key = String::with_capacity(s.len());
+    for c in s.chars().flat_map(char::to_lowercase) {
+        match c {
+            'ä' | 'á' | 'à' | 'â' | 'å' | 'ã' => key.push('a'),
+            'ö' | 'ó' | 'ò' | 'ô' | 'ø' | 'õ' => key.push('o'),
+            'ü' | 'ú' | 'ù' | 'û' => key.push('u'),
+            'é' | 'è' | 'ê' | 'ë' => key.push('e'),
+            'í' | 'ì' | 'î' | 'ï' => key.push('i'),
+            'ç' => key.push('c'),
+            'ñ' => key.push('n'),
+            'ß' => key.push_str("ss"),
+            c => key.push(c),
+        }
+    }
+    key
+}
+
+/// Compare two strings by their collation key, falling back to the strings themselves to get a
+/// total order.
+pub fn compare(a: &str, b: &str) -> Ordering {
+    collation_key(a)
+        .cmp(&collation_key(b))
+        .then_with(|| a.cmp(b))
+}
+
+/// Levenshtein distance between two strings, counted in characters.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        ::std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sort_key(name: &str) -> String {
+        PersonName::parse(name, &DEFAULT_PARTICLES).sort_key()
+    }
+
+    #[test]
+    fn test_multiple_first_names() {
+        assert_eq!(sort_key("Anna Maria Schmidt"), "Schmidt, Anna Maria");
+        assert_eq!(sort_key("Anna  Maria   Schmidt "), "Schmidt, Anna Maria");
+    }
+
+    #[test]
+    fn test_single_word() {
+        assert_eq!(sort_key("Alice"), "Alice, ");
+    }
+
+    #[test]
+    fn test_particles() {
+        assert_eq!(sort_key("Hans von Müller"), "Müller, Hans von");
+        assert_eq!(sort_key("Jan van der Berg"), "Berg, Jan van der");
+        assert_eq!(sort_key("Jan Van Der Berg"), "Berg, Jan Van Der");
+        // The first word is always a first name, even if it looks like a particle.
+        assert_eq!(sort_key("De Vries"), "Vries, De");
+    }
+
+    #[test]
+    fn test_from_parts() {
+        let name = PersonName::from_parts("Anna", "van der Berg", &DEFAULT_PARTICLES);
+        assert_eq!(name.full_name(), "Anna van der Berg");
+        assert_eq!(name.sort_key(), "Berg, Anna van der");
+
+        let name = PersonName::from_parts("Anna", "Müller Lüdenscheidt", &DEFAULT_PARTICLES);
+        assert_eq!(name.full_name(), "Anna Müller Lüdenscheidt");
+        assert_eq!(name.sort_key(), "Müller Lüdenscheidt, Anna");
+
+        let name = PersonName::from_parts("", "Cher", &DEFAULT_PARTICLES);
+        assert_eq!(name.full_name(), "Cher");
+        assert_eq!(name.sort_key(), "Cher, ");
+    }
+
+    #[test]
+    fn test_collation() {
+        let mut names = vec!["Zimmermann", "Özdemir", "Otto", "Ärger", "Bauer", "Abel"];
+        names.sort_by(|a, b| compare(a, b));
+        assert_eq!(
+            names,
+            vec!["Abel", "Ärger", "Bauer", "Otto", "Özdemir", "Zimmermann"]
+        );
+        assert_eq!(collation_key("Weiß"), collation_key("weiss"));
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("Anna Schmidt", "Anna Schmidt"), 0);
+        assert_eq!(edit_distance("Ana Schmidt", "Anna Schmidt"), 1);
+        assert_eq!(edit_distance("Jörg Meier", "Joerg Meier"), 2);
+        assert_eq!(edit_distance("Meier", "Maier"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("abc", ""), 3);
+    }
+}
@@ -0,0 +1,385 @@
+use std::io::BufWriter;
+
+use lopdf::{Document, Object};
+use printpdf::{
+    BuiltinFont, IndirectFontRef, Line, Mm, PdfDocument, PdfDocumentReference, PdfLayerReference,
+    Point,
+};
+
+use errors::*;
+use reports::{PerEmployeeData, PerEmployeeReport};
+
+const PAGE_WIDTH: f64 = 210.0;
+const PAGE_HEIGHT: f64 = 297.0;
+const MARGIN: f64 = 20.0;
+
+const TITLE_SIZE: i64 = 16;
+const FONT_SIZE: i64 = 10;
+const LINE_HEIGHT: f64 = 5.0;
+
+/// Left edges of the table columns in mm: name, date, hours, minutes, amount and week/remark.
+/// The last entry is the right edge of the table.
+const COLUMNS: [f64; 7] = [MARGIN, 58.0, 80.0, 98.0, 116.0, 142.0, PAGE_WIDTH - MARGIN];
+
+/// Rough average width of a character in Helvetica, relative to the font size.
+const AVERAGE_CHAR_WIDTH: f64 = 0.5;
+const MM_PER_PT: f64 = 25.4 / 72.0;
+
+/// The encoding the font dictionaries of the standard fonts declare.
+const ENCODING: &str = "WinAnsiEncoding";
+
+/// Remove the thin spaces `PerEmployeeData::compile` puts into dates, which do not exist in the
+/// standard PDF fonts.
+fn plain_text(text: &str) -> String {
+    text.replace('\u{2009}', "")
+}
+
+/// Split `text` into lines of at most `max_chars` characters, breaking at spaces where possible.
+fn wrap(text: &str, max_chars: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = String::new();
+
+    for word in text.split_whitespace() {
+        let mut word = word.to_string();
+        while word.chars().count() > max_chars {
+            if !line.is_empty() {
+                lines.push(line);
+                line = String::new();
+            }
+            let rest = word.chars().skip(max_chars).collect();
+            lines.push(word.chars().take(max_chars).collect());
+            word = rest;
+        }
+
+        if line.is_empty() {
+            line = word;
+        } else if line.chars().count() + 1 + word.chars().count() <= max_chars {
+            line.push(' ');
+            line.push_str(&word);
+        } else {
+            lines.push(line);
+            line = word;
+        }
+    }
+
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// How many characters fit into column `i` of the table.
+fn column_chars(i: usize) -> usize {
+    let width = COLUMNS[i + 1] - COLUMNS[i] - 2.0;
+    (width / (FONT_SIZE as f64 * AVERAGE_CHAR_WIDTH * MM_PER_PT)) as usize
+}
+
+/// Writes text from the top of the page downwards, starting a new page when necessary.
+struct Writer {
+    doc: PdfDocumentReference,
+    layer: PdfLayerReference,
+    regular: IndirectFontRef,
+    bold: IndirectFontRef,
+
+    /// Distance of the next line from the bottom of the page, in mm.
+    y: f64,
+}
+
+impl Writer {
+    fn new(title: &str) -> Result<Self> {
+        let (doc, page, layer) = PdfDocument::new(title, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Text");
+        let layer = doc.get_page(page).get_layer(layer);
+        let regular = doc
+            .add_builtin_font(BuiltinFont::Helvetica)
+            .map_err(|e| format!("Failed to add font Helvetica: {:?}", e))?;
+        let bold = doc
+            .add_builtin_font(BuiltinFont::HelveticaBold)
+            .map_err(|e| format!("Failed to add font Helvetica-Bold: {:?}", e))?;
+
+        Ok(Writer {
+            doc,
+            layer,
+            regular,
+            bold,
+            y: PAGE_HEIGHT - MARGIN,
+        })
+    }
+
+    /// Start a new page unless there is enough room for `lines` more lines on the current one.
+    fn reserve(&mut self, lines: usize) {
+        if self.y - lines as f64 * LINE_HEIGHT < MARGIN {
+            let (page, layer) = self.doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Text");
+            self.layer = self.doc.get_page(page).get_layer(layer);
+            self.y = PAGE_HEIGHT - MARGIN;
+        }
+    }
+
+    fn skip(&mut self, mm: f64) {
+        self.y -= mm;
+    }
+
+    fn text(&self, text: &str, size: i64, x: f64, bold: bool) {
+        let font = if bold { &self.bold } else { &self.regular };
+        self.layer.use_text(text, size, Mm(x), Mm(self.y), font);
+    }
+
+    /// Write a heading spanning the whole page.
+    fn heading(&mut self, text: &str) {
+        self.reserve(2);
+        self.text(text, TITLE_SIZE, MARGIN, true);
+        self.skip(2.0 * LINE_HEIGHT);
+    }
+
+    /// Write a paragraph spanning the whole page.
+    fn paragraph(&mut self, text: &str, bold: bool) {
+        let max_chars = column_chars(0) * (COLUMNS.len() - 1);
+        for line in wrap(text, max_chars) {
+            self.reserve(1);
+            self.text(&line, FONT_SIZE, MARGIN, bold);
+            self.skip(LINE_HEIGHT);
+        }
+    }
+
+    /// Write a row of the table. Each cell starts at the corresponding column and is wrapped to
+    /// fit into it.
+    fn row(&mut self, cells: &[&str], bold: bool) {
+        let cells: Vec<_> = cells
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| wrap(&plain_text(cell), column_chars(i)))
+            .collect();
+        let height = cells.iter().map(Vec::len).max().unwrap_or(1);
+
+        self.reserve(height);
+        for (i, lines) in cells.iter().enumerate() {
+            for (j, line) in lines.iter().enumerate() {
+                let font = if bold { &self.bold } else { &self.regular };
+                let y = self.y - j as f64 * LINE_HEIGHT;
+                self.layer
+                    .use_text(line.as_str(), FONT_SIZE, Mm(COLUMNS[i]), Mm(y), font);
+            }
+        }
+        self.skip(height as f64 * LINE_HEIGHT);
+    }
+
+    /// Draw a horizontal line across the table, between the previous and the next row.
+    fn rule(&mut self) {
+        let y = self.y + LINE_HEIGHT - 1.0;
+        let line = Line {
+            points: vec![
+                (Point::new(Mm(COLUMNS[0]), Mm(y)), false),
+                (Point::new(Mm(COLUMNS[COLUMNS.len() - 1]), Mm(y)), false),
+            ],
+            has_stroke: true,
+            ..Default::default()
+        };
+        self.layer.set_outline_thickness(0.5);
+        self.layer.add_shape(line);
+        self.skip(1.5);
+    }
+
+    fn finish(self) -> Result<Vec<u8>> {
+        let mut writer = BufWriter::new(vec![]);
+        self.doc
+            .save(&mut writer)
+            .map_err(|e| format!("Failed to write PDF: {:?}", e))?;
+        let pdf = writer.into_inner().chain_err(|| "Failed to write PDF")?;
+        encode_text(&pdf)
+    }
+}
+
+/// printpdf writes text in the standard fonts as UTF-8, although their font dictionaries declare
+/// Windows-1252. Re-encode the strings of all text operators, so that umlauts come out right.
+fn encode_text(pdf: &[u8]) -> Result<Vec<u8>> {
+    let mut doc = Document::load_from(pdf).chain_err(|| "Failed to read PDF")?;
+
+    for page in doc.get_pages().values() {
+        for id in doc.get_page_contents(*page) {
+            if let Some(&mut Object::Stream(ref mut stream)) = doc.get_object_mut(id) {
+                stream.decompress();
+                let mut content = stream
+                    .decode_content()
+                    .chain_err(|| "Failed to parse page content")?;
+                for operation in &mut content.operations {
+                    if operation.operator != "Tj" {
+                        continue;
+                    }
+                    for operand in &mut operation.operands {
+                        if let Object::String(ref mut bytes, _) = *operand {
+                            let text = String::from_utf8_lossy(bytes).into_owned();
+                            *bytes = Document::encode_text(Some(ENCODING), &text);
+                        }
+                    }
+                }
+                stream.set_content(content.encode()?);
+            }
+        }
+    }
+
+    let mut pdf = vec![];
+    doc.save_to(&mut pdf)?;
+    Ok(pdf)
+}
+
+/// The table with all items and the total of one employee, like the `person` environment in the
+/// LaTeX template.
+fn write_employee(writer: &mut Writer, employee: &PerEmployeeData) {
+    // Keep the header together with at least one item.
+    writer.reserve(4);
+    writer.row(
+        &[
+            &employee.name,
+            "Datum",
+            "Stunden",
+            "Minuten",
+            "Betrag",
+            "Woche/Bemerkung",
+        ],
+        true,
+    );
+    writer.rule();
+
+    for item in &employee.items {
+        let remark = if item.substitutes.is_empty() {
+            format!("{} {}", item.type_of_week, item.remark)
+        } else {
+            format!(
+                "{} Vertretung für {} {}",
+                item.type_of_week, item.substitutes, item.remark
+            )
+        };
+        writer.row(
+            &[
+                "",
+                &item.date,
+                &item.hours,
+                &item.minutes,
+                &item.amount,
+                &remark,
+            ],
+            false,
+        );
+    }
+
+    writer.rule();
+    writer.row(
+        &[
+            "Summe",
+            "",
+            &employee.hours.to_string(),
+            &employee.minutes.to_string(),
+            &employee.amount,
+        ],
+        true,
+    );
+    if employee.items_without_rate > 0 {
+        let missing = format!(
+            "Ohne Stundensatz, nicht im Betrag enthalten: {} Einträge",
+            employee.items_without_rate
+        );
+        writer.row(&["", &missing], false);
+    }
+
+    if !employee.absences.is_empty() {
+        writer.rule();
+        writer.row(&["Abwesenheiten"], true);
+        for absence in &employee.absences {
+            let period = format!("{} – {}", absence.from, absence.to);
+            let reason = format!("{} {}", absence.reason, absence.remark);
+            writer.row(&["", &period, "", "", "", &reason], false);
+        }
+    }
+
+    writer.skip(2.0 * LINE_HEIGHT);
+}
+
+/// Render a report to PDF, using the same layout as the LaTeX template.
+///
+/// Only the standard PDF fonts are used, so text is encoded as Windows-1252 and characters that
+/// are not in it are dropped.
+pub fn render(report: &PerEmployeeReport) -> Result<Vec<u8>> {
+    let title = format!("Abrechnung BetreuerInnen {}", report.title);
+    let mut writer = Writer::new(&title)?;
+
+    writer.heading(&title);
+    for employee in &report.employees {
+        write_employee(&mut writer, employee);
+    }
+
+    writer.reserve(1);
+    writer.text(
+        &format!("Gesamtbetrag: {}", report.amount),
+        FONT_SIZE,
+        COLUMNS[4],
+        true,
+    );
+    if report.items_without_rate > 0 {
+        writer.skip(LINE_HEIGHT);
+        writer.paragraph(
+            &format!(
+                "Unvollständig: {} Einträge ohne Stundensatz sind nicht enthalten.",
+                report.items_without_rate
+            ),
+            false,
+        );
+    }
+    writer.skip(2.0 * LINE_HEIGHT);
+
+    if !report.limit_warnings.is_empty() {
+        writer.heading("Hinweise");
+        for warning in &report.limit_warnings {
+            writer.paragraph(&format!("• {}", warning), false);
+        }
+    }
+
+    writer.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_plain_text() {
+        assert_eq!(plain_text("30.\u{2009}08.\u{2009}17"), "30.08.17");
+        assert_eq!(plain_text("Anna Schmidt"), "Anna Schmidt");
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("", 10), vec![""]);
+        assert_eq!(wrap("kurz", 10), vec!["kurz"]);
+        assert_eq!(
+            wrap("Vertretung für Anna Schmidt", 15),
+            vec!["Vertretung für", "Anna Schmidt"]
+        );
+        assert_eq!(wrap("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+    }
+
+    #[test]
+    fn test_umlauts() {
+        let mut report = PerEmployeeReport::example();
+        report.employees[0].name = "Jörg Özdemir".to_string();
+        let pdf = render(&report).unwrap();
+
+        let doc = Document::load_from(pdf.as_slice()).unwrap();
+        let mut texts = vec![];
+        for page in doc.get_pages().values() {
+            for operation in doc.get_and_decode_page_content(*page).operations {
+                if operation.operator == "Tj" {
+                    if let Object::String(ref bytes, _) = operation.operands[0] {
+                        texts.push(bytes.clone());
+                    }
+                }
+            }
+        }
+
+        assert!(texts.contains(&b"J\xF6rg \xD6zdemir".to_vec()));
+        let texts: Vec<_> = texts
+            .iter()
+            .map(|bytes| Document::decode_text(Some(ENCODING), bytes))
+            .collect();
+        assert!(texts.contains(&"Jörg Özdemir".to_string()));
+        assert!(texts.iter().any(|text| text.contains("für")));
+    }
+}
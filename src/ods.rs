@@ -0,0 +1,499 @@
+use std::collections::BTreeMap;
+use std::io::{Cursor, Write};
+
+use diesel::SqliteConnection;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use errors::*;
+use generate_pdf::RawReportData;
+use items::InvoiceItem;
+use money::amount_cents;
+use rates;
+use weeks::TYPE_OF_WEEK_NAME;
+use TIME_FORMAT;
+
+const MIMETYPE: &str = "application/vnd.oasis.opendocument.spreadsheet";
+
+const MANIFEST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest:manifest xmlns:manifest="urn:oasis:names:tc:opendocument:xmlns:manifest:1.0" manifest:version="1.2">
+ <manifest:file-entry manifest:full-path="/" manifest:media-type="application/vnd.oasis.opendocument.spreadsheet"/>
+ <manifest:file-entry manifest:full-path="content.xml" manifest:media-type="text/xml"/>
+</manifest:manifest>
+"#;
+
+const CONTENT_START: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<office:document-content
+ xmlns:office="urn:oasis:names:tc:opendocument:xmlns:office:1.0"
+ xmlns:style="urn:oasis:names:tc:opendocument:xmlns:style:1.0"
+ xmlns:text="urn:oasis:names:tc:opendocument:xmlns:text:1.0"
+ xmlns:table="urn:oasis:names:tc:opendocument:xmlns:table:1.0"
+ xmlns:number="urn:oasis:names:tc:opendocument:xmlns:datastyle:1.0"
+ xmlns:fo="urn:oasis:names:tc:opendocument:xmlns:xsl-fo-compatible:1.0"
+ xmlns:of="urn:oasis:names:tc:opendocument:xmlns:of:1.2"
+ office:version="1.2">
+ <office:automatic-styles>
+  <number:currency-style style:name="N_EUR">
+   <number:number number:decimal-places="2" number:min-integer-digits="1" number:grouping="true"/>
+   <number:text> </number:text>
+   <number:currency-symbol number:language="de" number:country="DE">€</number:currency-symbol>
+  </number:currency-style>
+  <number:date-style style:name="N_DATE">
+   <number:day number:style="long"/>
+   <number:text>.</number:text>
+   <number:month number:style="long"/>
+   <number:text>.</number:text>
+   <number:year number:style="long"/>
+  </number:date-style>
+  <style:style style:name="euro" style:family="table-cell" style:data-style-name="N_EUR"/>
+  <style:style style:name="date" style:family="table-cell" style:data-style-name="N_DATE"/>
+  <style:style style:name="bold" style:family="table-cell">
+   <style:text-properties fo:font-weight="bold"/>
+  </style:style>
+ </office:automatic-styles>
+ <office:body>
+  <office:spreadsheet>
+"#;
+
+const CONTENT_END: &str = r#"  </office:spreadsheet>
+ </office:body>
+</office:document-content>
+"#;
+
+/// Name of the sheet listing the totals of all employees.
+const SUMMARY_SHEET: &str = "Übersicht";
+const EMPLOYEES_SHEET: &str = "Personen";
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Turn an employee's name into a sheet name that is valid in both LibreOffice and Excel and
+/// different from all names in `taken`.
+fn sheet_name(name: &str, taken: &[String]) -> String {
+    let base: String = name
+        .chars()
+        .map(|c| match c {
+            '[' | ']' | '*' | '?' | ':' | '/' | '\\' => '_',
+            c => c,
+        })
+        .take(25)
+        .collect();
+
+    let mut candidate = base.clone();
+    let mut i = 2;
+    while candidate == SUMMARY_SHEET || taken.contains(&candidate) {
+        candidate = format!("{} ({})", base, i);
+        i += 1;
+    }
+    candidate
+}
+
+/// Reference to a cell on another sheet in OpenFormula syntax, e.g. `['Anna Schmidt'.E7]`.
+fn reference(sheet: &str, cell: &str) -> String {
+    format!("['{}'.{}]", sheet.replace('\'', "''"), cell)
+}
+
+/// One cell of a table.
+enum Cell {
+    Empty,
+    Text(String),
+    Bold(String),
+    Date(String),
+    Hours(f64),
+    Euro(i64),
+    /// A formula and its current value in cents, for programs that do not recalculate on load.
+    EuroFormula(String, i64),
+    HoursFormula(String, f64),
+}
+
+impl Cell {
+    fn to_xml(&self) -> String {
+        match *self {
+            Cell::Empty => "<table:table-cell/>".to_string(),
+            Cell::Text(ref text) => format!(
+                r#"<table:table-cell office:value-type="string"><text:p>{}</text:p></table:table-cell>"#,
+                escape_xml(text)
+            ),
+            Cell::Bold(ref text) => format!(
+                r#"<table:table-cell table:style-name="bold" office:value-type="string"><text:p>{}</text:p></table:table-cell>"#,
+                escape_xml(text)
+            ),
+            Cell::Date(ref date) => format!(
+                r#"<table:table-cell table:style-name="date" office:value-type="date" office:date-value="{}"/>"#,
+                date
+            ),
+            Cell::Hours(hours) => format!(
+                r#"<table:table-cell office:value-type="float" office:value="{}"/>"#,
+                hours
+            ),
+            Cell::Euro(cents) => format!(
+                r#"<table:table-cell table:style-name="euro" office:value-type="currency" office:currency="EUR" office:value="{}"/>"#,
+                cents as f64 / 100.0
+            ),
+            Cell::EuroFormula(ref formula, cents) => format!(
+                r#"<table:table-cell table:style-name="euro" table:formula="of:={}" office:value-type="currency" office:currency="EUR" office:value="{}"/>"#,
+                escape_xml(formula),
+                cents as f64 / 100.0
+            ),
+            Cell::HoursFormula(ref formula, hours) => format!(
+                r#"<table:table-cell table:formula="of:={}" office:value-type="float" office:value="{}"/>"#,
+                escape_xml(formula),
+                hours
+            ),
+        }
+    }
+}
+
+/// A sheet under construction.
+struct Sheet {
+    name: String,
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Sheet {
+    fn new(name: String, header: &[&str]) -> Self {
+        Sheet {
+            name,
+            rows: vec![header.iter().map(|h| Cell::Bold(h.to_string())).collect()],
+        }
+    }
+
+    /// Number of the next row, as used in formulas (starting at 1).
+    fn next_row(&self) -> usize {
+        self.rows.len() + 1
+    }
+
+    fn to_xml(&self) -> String {
+        let mut xml = format!(
+            "   <table:table table:name=\"{}\">\n",
+            escape_xml(&self.name)
+        );
+        for row in &self.rows {
+            xml.push_str("    <table:table-row>");
+            for cell in row {
+                xml.push_str(&cell.to_xml());
+            }
+            xml.push_str("</table:table-row>\n");
+        }
+        xml.push_str("   </table:table>\n");
+        xml
+    }
+}
+
+/// The sheet of one employee, listing all their items.
+///
+/// Returns the sheet, the number of the row with the totals, and the total hours and amount.
+fn employee_sheet(
+    conn: &SqliteConnection,
+    name: String,
+    employee_id: i32,
+    items: &[&InvoiceItem],
+) -> Result<(Sheet, usize, f64, i64)> {
+    let rates = rates::get_for_employee(conn, employee_id)?;
+    let mut sheet = Sheet::new(
+        name,
+        &[
+            "Datum",
+            "Woche",
+            "Beginn",
+            "Ende",
+            "Stunden",
+            "Stundensatz",
+            "Betrag",
+            "Vertretung für",
+            "Bemerkung",
+        ],
+    );
+
+    let mut total_hours = 0.0;
+    let mut total_cents = 0;
+    for item in items {
+        let row = sheet.next_row();
+        let minutes = item.end.signed_duration_since(item.start).num_minutes();
+        let hours = minutes as f64 / 60.0;
+        total_hours += hours;
+
+        let (rate, amount) = match rates::rate_on(&rates, item.day) {
+            Some(rate) => {
+                let cents = amount_cents(rate.rate_cents, minutes);
+                total_cents += cents;
+                (
+                    Cell::Euro(i64::from(rate.rate_cents)),
+                    Cell::EuroFormula(format!("ROUND([.E{0}]*[.F{0}];2)", row), cents),
+                )
+            }
+            None => (Cell::Empty, Cell::Empty),
+        };
+
+        sheet.rows.push(vec![
+            Cell::Date(format!("{}", item.day.format("%Y-%m-%d"))),
+            Cell::Text(TYPE_OF_WEEK_NAME[item.type_of_week as usize].to_string()),
+            Cell::Text(format!("{}", item.start.format(TIME_FORMAT))),
+            Cell::Text(format!("{}", item.end.format(TIME_FORMAT))),
+            Cell::Hours(hours),
+            rate,
+            amount,
+            Cell::Text(item.substitutes_name.clone().unwrap_or_default()),
+            Cell::Text(item.remark.clone()),
+        ]);
+    }
+
+    let last = sheet.next_row() - 1;
+    let total_row = sheet.next_row();
+    sheet.rows.push(vec![
+        Cell::Bold("Summe".to_string()),
+        Cell::Empty,
+        Cell::Empty,
+        Cell::Empty,
+        Cell::HoursFormula(format!("SUM([.E2:.E{}])", last), total_hours),
+        Cell::Empty,
+        Cell::EuroFormula(format!("SUM([.G2:.G{}])", last), total_cents),
+    ]);
+
+    Ok((sheet, total_row, total_hours, total_cents))
+}
+
+/// Create an OpenDocument spreadsheet with a summary sheet and one sheet per employee.
+///
+/// Hours, hourly rates and amounts are numbers, the amounts and all totals are formulas, so the
+/// accounting office can check and extend them.
+pub fn ods(conn: &SqliteConnection, report: &RawReportData) -> Result<Vec<u8>> {
+    let mut by_employee: BTreeMap<i32, Vec<&InvoiceItem>> = BTreeMap::new();
+    for item in &report.items {
+        by_employee
+            .entry(item.employee_id)
+            .or_insert_with(Vec::new)
+            .push(item);
+    }
+    let mut employees: Vec<_> = by_employee.into_iter().map(|(_, items)| items).collect();
+    employees.sort_by(|a, b| ::names::compare(&a[0].name_sort, &b[0].name_sort));
+
+    let mut summary = Sheet::new(SUMMARY_SHEET.to_string(), &["Name", "Stunden", "Betrag"]);
+    let mut sheets = vec![];
+    let mut taken = vec![];
+    let mut total_hours = 0.0;
+    let mut total_cents = 0;
+
+    for mut items in employees {
+        items.sort_by_key(|item| (item.day, item.start));
+        let name = sheet_name(&items[0].name, &taken);
+        taken.push(name.clone());
+
+        let (sheet, total_row, hours, cents) =
+            employee_sheet(conn, name.clone(), items[0].employee_id, &items)?;
+        total_hours += hours;
+        total_cents += cents;
+        summary.rows.push(vec![
+            Cell::Text(items[0].name.clone()),
+            Cell::HoursFormula(reference(&name, &format!("E{}", total_row)), hours),
+            Cell::EuroFormula(reference(&name, &format!("G{}", total_row)), cents),
+        ]);
+        sheets.push(sheet);
+    }
+
+    let last = summary.next_row() - 1;
+    summary.rows.push(vec![
+        Cell::Bold("Summe".to_string()),
+        Cell::HoursFormula(format!("SUM([.B2:.B{}])", last), total_hours),
+        Cell::EuroFormula(format!("SUM([.C2:.C{}])", last), total_cents),
+    ]);
+
+    let mut content = CONTENT_START.to_string();
+    content.push_str(&summary.to_xml());
+    for sheet in &sheets {
+        content.push_str(&sheet.to_xml());
+    }
+    content.push_str(CONTENT_END);
+
+    write_zip(&content).chain_err(|| {
+        format!(
+            "Failed to create spreadsheet for report #{}",
+            report.metadata.id
+        )
+    })
+}
+
+/// Create an OpenDocument spreadsheet summarizing several reports, e.g. a whole school year.
+///
+/// The first sheet lists the totals of each report, the second one the totals of each employee
+/// across all reports. Hours without an hourly rate are not part of any amount, so they are
+/// listed in a column of their own instead of silently counting as nothing.
+pub fn year_summary(conn: &SqliteConnection, reports: &[RawReportData]) -> Result<Vec<u8>> {
+    let content = year_summary_content(conn, reports)?;
+    write_zip(&content).chain_err(|| "Failed to create year summary")
+}
+
+fn year_summary_content(conn: &SqliteConnection, reports: &[RawReportData]) -> Result<String> {
+    let mut employee_rates = BTreeMap::new();
+    let mut employees: BTreeMap<i32, (&InvoiceItem, f64, i64, f64)> = BTreeMap::new();
+    let mut summary = Sheet::new(
+        SUMMARY_SHEET.to_string(),
+        &[
+            "Abrechnung",
+            "Beginn",
+            "Ende",
+            "Stunden",
+            "Betrag",
+            "Stunden ohne Stundensatz",
+        ],
+    );
+    let mut total_hours = 0.0;
+    let mut total_cents = 0;
+    let mut total_without_rate = 0.0;
+
+    for report in reports {
+        let mut report_hours = 0.0;
+        let mut report_cents = 0;
+        let mut report_without_rate = 0.0;
+        for item in &report.items {
+            if !employee_rates.contains_key(&item.employee_id) {
+                let rates = rates::get_for_employee(conn, item.employee_id)?;
+                employee_rates.insert(item.employee_id, rates);
+            }
+            let minutes = item.end.signed_duration_since(item.start).num_minutes();
+            let hours = minutes as f64 / 60.0;
+            let (cents, without_rate) =
+                match rates::rate_on(&employee_rates[&item.employee_id], item.day) {
+                    Some(rate) => (amount_cents(rate.rate_cents, minutes), 0.0),
+                    None => (0, hours),
+                };
+
+            report_hours += hours;
+            report_cents += cents;
+            report_without_rate += without_rate;
+            let totals = employees
+                .entry(item.employee_id)
+                .or_insert((item, 0.0, 0, 0.0));
+            totals.1 += hours;
+            totals.2 += cents;
+            totals.3 += without_rate;
+        }
+
+        total_hours += report_hours;
+        total_cents += report_cents;
+        total_without_rate += report_without_rate;
+        summary.rows.push(vec![
+            Cell::Text(report.metadata.title.clone()),
+            Cell::Date(report.metadata.start_date.clone()),
+            Cell::Date(report.metadata.end_date.clone()),
+            Cell::Hours(report_hours),
+            Cell::Euro(report_cents),
+            Cell::Hours(report_without_rate),
+        ]);
+    }
+
+    let last = summary.next_row() - 1;
+    summary.rows.push(vec![
+        Cell::Bold("Summe".to_string()),
+        Cell::Empty,
+        Cell::Empty,
+        Cell::HoursFormula(format!("SUM([.D2:.D{}])", last), total_hours),
+        Cell::EuroFormula(format!("SUM([.E2:.E{}])", last), total_cents),
+        Cell::HoursFormula(format!("SUM([.F2:.F{}])", last), total_without_rate),
+    ]);
+
+    let mut employees: Vec<_> = employees.into_iter().map(|(_, totals)| totals).collect();
+    employees.sort_by(|a, b| ::names::compare(&a.0.name_sort, &b.0.name_sort));
+    let mut by_employee = Sheet::new(
+        EMPLOYEES_SHEET.to_string(),
+        &["Name", "Stunden", "Betrag", "Stunden ohne Stundensatz"],
+    );
+    for (item, hours, cents, without_rate) in employees {
+        by_employee.rows.push(vec![
+            Cell::Text(item.name.clone()),
+            Cell::Hours(hours),
+            Cell::Euro(cents),
+            Cell::Hours(without_rate),
+        ]);
+    }
+    let last = by_employee.next_row() - 1;
+    by_employee.rows.push(vec![
+        Cell::Bold("Summe".to_string()),
+        Cell::HoursFormula(format!("SUM([.B2:.B{}])", last), total_hours),
+        Cell::EuroFormula(format!("SUM([.C2:.C{}])", last), total_cents),
+        Cell::HoursFormula(format!("SUM([.D2:.D{}])", last), total_without_rate),
+    ]);
+
+    let mut content = CONTENT_START.to_string();
+    content.push_str(&summary.to_xml());
+    content.push_str(&by_employee.to_xml());
+    content.push_str(CONTENT_END);
+    Ok(content)
+}
+
+/// Pack the content into an ODS file. The mimetype has to be the first file, uncompressed.
+fn write_zip(content: &str) -> ::zip::result::ZipResult<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+
+    zip.start_file(
+        "mimetype",
+        FileOptions::default().compression_method(CompressionMethod::Stored),
+    )?;
+    zip.write_all(MIMETYPE.as_bytes())?;
+    zip.start_file("META-INF/manifest.xml", FileOptions::default())?;
+    zip.write_all(MANIFEST.as_bytes())?;
+    zip.start_file("content.xml", FileOptions::default())?;
+    zip.write_all(content.as_bytes())?;
+
+    Ok(zip.finish()?.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sheet_name() {
+        assert_eq!(sheet_name("Anna Schmidt", &[]), "Anna Schmidt");
+        assert_eq!(sheet_name("A/B [test]", &[]), "A_B _test_");
+        assert_eq!(
+            sheet_name("Anna Schmidt", &["Anna Schmidt".to_string()]),
+            "Anna Schmidt (2)"
+        );
+        assert_eq!(sheet_name(SUMMARY_SHEET, &[]), "Übersicht (2)");
+    }
+
+    #[test]
+    fn test_reference() {
+        assert_eq!(reference("Anna Schmidt", "E7"), "['Anna Schmidt'.E7]");
+        assert_eq!(reference("Jan 't Hooft", "G3"), "['Jan ''t Hooft'.G3]");
+    }
+
+    #[test]
+    fn test_year_summary() {
+        let conn = ::db::test_connection();
+        let anna = ::employees::insert(&conn, "Anna Schmidt").unwrap();
+        let jan = ::employees::insert(&conn, "Jan Berg").unwrap();
+        let rate = rates::HourlyRate {
+            id: 0,
+            employee_id: anna,
+            effective_from: "2018-01-01".to_string(),
+            rate_cents: 1000,
+        };
+        rates::insert(&conn, anna, &rate).unwrap();
+
+        let report_id = ::test_data::report(&conn, "2018-03-01", "2018-03-31");
+        ::test_data::item(&conn, report_id, anna, "2018-03-05", (12, 14), None);
+        ::test_data::item(&conn, report_id, jan, "2018-03-06", (12, 15), None);
+        let report = RawReportData::from_id(&conn, report_id).unwrap();
+
+        let content = year_summary_content(&conn, &[report]).unwrap();
+        assert!(content.contains("Stunden ohne Stundensatz"));
+        // Jan's three hours are left out of the amount, but still listed.
+        let summary_row = Cell::Hours(3.0).to_xml();
+        assert!(content.contains(&format!("{}{}", Cell::Euro(2000).to_xml(), summary_row)));
+        assert!(content.contains(&format!("{}{}", Cell::Euro(0).to_xml(), summary_row)));
+    }
+
+    #[test]
+    fn test_escape_xml() {
+        assert_eq!(
+            escape_xml("Müller & Söhne <\"GmbH\">"),
+            "Müller &amp; Söhne &lt;&quot;GmbH&quot;&gt;"
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use chrono::NaiveDate;
+use diesel::prelude::*;
+use diesel::{self, SqliteConnection};
+
+use employees;
+use errors::*;
+use schema::hourly_rates;
+
+use DATE_FORMAT;
+
+/// The hourly rate of an employee starting on a given day.
+///
+/// A rate is valid from `effective_from` until the day before the next rate of the same employee
+/// becomes effective.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Queryable)]
+pub struct HourlyRate {
+    #[serde(default)]
+    pub id: i32,
+    #[serde(default)]
+    pub employee_id: i32,
+
+    /// The first day on which this rate applies.
+    pub effective_from: String,
+
+    /// Hourly rate in euro cents.
+    pub rate_cents: i32,
+}
+
+/// Find the rate that applies on a given day.
+///
+/// `rates` can be in any order. If none of the rates is effective yet, there is no rate.
+pub fn rate_on(rates: &[HourlyRate], day: NaiveDate) -> Option<&HourlyRate> {
+    let day = format!("{}", day.format(DATE_FORMAT));
+    rates
+        .iter()
+        .filter(|rate| rate.effective_from <= day)
+        .max_by(|a, b| a.effective_from.cmp(&b.effective_from))
+}
+
+/// Get the history of hourly rates of an employee, most recent first.
+pub fn get_for_employee(conn: &SqliteConnection, employee_id: i32) -> Result<Vec<HourlyRate>> {
+    assert!(employee_id >= 0);
+
+    hourly_rates::table
+        .filter(hourly_rates::employee_id.eq(employee_id))
+        .order(hourly_rates::effective_from.desc())
+        .load::<HourlyRate>(conn)
+        .chain_err(|| format!("Failed to get hourly rates of employee #{}", employee_id))
+}
+
+/// Add a new rate for an employee, returning its id.
+///
+/// Adding a rate with the same `effective_from` as an existing one replaces the existing one.
+pub fn insert(conn: &SqliteConnection, employee_id: i32, rate: &HourlyRate) -> Result<i32> {
+    assert!(employee_id >= 0);
+
+    employees::get_by_id(conn, employee_id)?;
+    NaiveDate::parse_from_str(&rate.effective_from, DATE_FORMAT)
+        .chain_err(|| format!("Invalid date: {}", rate.effective_from))?;
+    if rate.rate_cents < 0 {
+        bail!("Hourly rate must not be negative: {}", rate.rate_cents);
+    }
+
+    diesel::replace_into(hourly_rates::table)
+        .values((
+            hourly_rates::employee_id.eq(employee_id),
+            hourly_rates::effective_from.eq(&rate.effective_from),
+            hourly_rates::rate_cents.eq(rate.rate_cents),
+        ))
+        .execute(conn)
+        .chain_err(|| format!("Failed to insert hourly rate: {:?}", rate))?;
+
+    hourly_rates::table
+        .select(hourly_rates::id)
+        .filter(hourly_rates::employee_id.eq(employee_id))
+        .filter(hourly_rates::effective_from.eq(&rate.effective_from))
+        .first::<i32>(conn)
+        .chain_err(|| "Failed to get hourly rate that was just inserted")
+}
+
+/// Remove an hourly rate from the database.
+pub fn delete(conn: &SqliteConnection, id: i32) -> Result<()> {
+    diesel::delete(hourly_rates::table.filter(hourly_rates::id.eq(id)))
+        .execute(conn)
+        .map(|_| ())
+        .chain_err(|| format!("Failed to delete hourly rate #{}", id))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rate(effective_from: &str, rate_cents: i32) -> HourlyRate {
+        HourlyRate {
+            id: 0,
+            employee_id: 1,
+            effective_from: effective_from.into(),
+            rate_cents,
+        }
+    }
+
+    #[test]
+    fn test_no_rate_before_first_one() {
+        let rates = vec![rate("2017-09-01", 1000)];
+        assert_eq!(rate_on(&rates, NaiveDate::from_ymd(2017, 8, 31)), None);
+        assert_eq!(rate_on(&[], NaiveDate::from_ymd(2017, 8, 31)), None);
+    }
+
+    #[test]
+    fn test_rate_change_in_the_middle_of_a_report() {
+        // A report from 2017-12-11 to 2018-01-26 with a raise at the start of the year.
+        let rates = vec![rate("2018-01-01", 1200), rate("2017-08-01", 1000)];
+
+        let rate_cents = |y, m, d| {
+            rate_on(&rates, NaiveDate::from_ymd(y, m, d))
+                .unwrap()
+                .rate_cents
+        };
+        assert_eq!(rate_cents(2017, 12, 11), 1000);
+        assert_eq!(rate_cents(2017, 12, 31), 1000);
+        assert_eq!(rate_cents(2018, 1, 1), 1200);
+        assert_eq!(rate_cents(2018, 1, 26), 1200);
+    }
+
+    #[test]
+    fn test_order_of_rates_does_not_matter() {
+        let mut rates = vec![
+            rate("2017-08-01", 1000),
+            rate("2018-02-01", 1300),
+            rate("2018-01-01", 1200),
+        ];
+        let day = NaiveDate::from_ymd(2018, 1, 15);
+        assert_eq!(rate_on(&rates, day).unwrap().rate_cents, 1200);
+        rates.reverse();
+        assert_eq!(rate_on(&rates, day).unwrap().rate_cents, 1200);
+    }
+
+    #[test]
+    fn test_insert() {
+        let conn = ::db::test_connection();
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+
+        let id = insert(&conn, anna, &rate("2018-01-01", 1200)).unwrap();
+        assert_eq!(get_for_employee(&conn, anna).unwrap()[0].id, id);
+        assert!(insert(&conn, anna + 1, &rate("2018-01-01", 1200)).is_err());
+        assert!(get_for_employee(&conn, anna + 1).unwrap().is_empty());
+    }
+}
@@ -9,6 +9,11 @@ use errors::*;
 use schema::reports;
 use weeks::TYPE_OF_WEEK_NAME;
 
+/// How dates appear in the report, with thin spaces, e.g. "30. 08. 17".
+///
+/// The thin spaces are plain Unicode, so the same data works for LaTeX, HTML and native PDFs.
+pub const REPORT_DATE_FORMAT: &str = "%d.\u{2009}%m.\u{2009}%y";
+
 /// Represent a row in the `reports` table.
 #[derive(Debug, Serialize, Deserialize, Insertable, Queryable)]
 #[table_name = "reports"]
@@ -18,6 +23,36 @@ pub struct Report {
     pub start_date: String,
     pub end_date: String,
     pub was_pdf_generated: bool,
+
+    /// Whether to list the employees' absences in the PDF.
+    #[serde(default)]
+    pub show_absences: bool,
+
+    /// Name of the template used for the PDF, or `None` for the default template.
+    #[serde(default)]
+    pub template: Option<String>,
+
+    /// Incremented by the database whenever the report or one of its items changes.
+    #[serde(default)]
+    pub revision: i32,
+}
+
+/// A report as sent by the frontend to create it.
+///
+/// The id, the revision and whether a PDF was generated are up to the database, so a client
+/// cannot forge the revision that archived PDFs are checked against.
+#[derive(Debug, Deserialize, Insertable)]
+#[table_name = "reports"]
+pub struct NewReport {
+    pub title: String,
+    pub start_date: String,
+    pub end_date: String,
+
+    #[serde(default)]
+    pub show_absences: bool,
+
+    #[serde(default)]
+    pub template: Option<String>,
 }
 
 /// Get data for the report with the given id.
@@ -38,10 +73,12 @@ pub fn get_all(conn: &SqliteConnection) -> Result<Vec<Report>> {
 }
 
 /// Insert a new report into the database.
-pub fn add(conn: &SqliteConnection, report: &Report) -> Result<i32> {
+pub fn add(conn: &SqliteConnection, report: &NewReport) -> Result<i32> {
     use schema::reports::dsl::*;
 
-    assert!(report.id >= 0);
+    if let Some(ref name) = report.template {
+        ::templates::check_exists(conn, name)?;
+    }
 
     diesel::insert_into(reports)
         .values(report)
@@ -60,6 +97,9 @@ pub fn add(conn: &SqliteConnection, report: &Report) -> Result<i32> {
 /// Replace a report in the database.
 pub fn update(conn: &SqliteConnection, report: &Report) -> Result<()> {
     assert!(report.id >= 0);
+    if let Some(ref name) = report.template {
+        ::templates::check_exists(conn, name)?;
+    }
 
     diesel::update(reports::table)
         .filter(reports::id.eq(report.id))
@@ -67,6 +107,8 @@ pub fn update(conn: &SqliteConnection, report: &Report) -> Result<()> {
             reports::title.eq(&report.title),
             reports::start_date.eq(&report.start_date),
             reports::end_date.eq(&report.end_date),
+            reports::show_absences.eq(report.show_absences),
+            reports::template.eq(&report.template),
         ))
         .execute(conn)
         .map(|_| ())
@@ -78,35 +120,77 @@ pub fn update(conn: &SqliteConnection, report: &Report) -> Result<()> {
 /// It belongs to a specific employee. It represents one row in the final PDF report, which is why
 /// it only contains strings, and is only used for writing the formatted data to a file.
 #[derive(Serialize)]
-struct EmployeeItem {
+pub struct EmployeeItem {
     /// When the employee worked
-    date: String,
+    pub date: String,
 
     /// What type of week was this day in?
-    type_of_week: String,
+    pub type_of_week: String,
 
     /// How many full hours did they work?
-    hours: String,
+    pub hours: String,
 
     /// How many minutes did they work? (< 60)
-    minutes: String,
+    pub minutes: String,
+
+    /// How much they earned, or "?" if no hourly rate is known for that day.
+    pub amount: String,
 
-    /// Whatever remark, e.g. stand-in for so-and-so.
-    remark: String,
+    /// Name of the employee this one stood in for, if any.
+    pub substitutes: String,
+
+    /// Whatever other remark there is.
+    pub remark: String,
+}
+
+/// An absence of an employee during the period covered by the report.
+#[derive(Serialize)]
+pub struct EmployeeAbsence {
+    /// First day of the absence
+    pub from: String,
+
+    /// Last day of the absence
+    pub to: String,
+
+    /// Why the employee was absent
+    pub reason: String,
+
+    pub remark: String,
 }
 
 /// Section in the report with all the data for one employee.
 #[derive(Serialize)]
-struct PerEmployeeData {
-    name: String,
-    hours: i32,
-    minutes: i32,
-    items: Vec<EmployeeItem>,
+pub struct PerEmployeeData {
+    pub employee_id: i32,
+    pub name: String,
+    pub hours: i32,
+    pub minutes: i32,
+    pub items: Vec<EmployeeItem>,
+
+    /// Total amount earned in cents.
+    pub amount_cents: i64,
+
+    /// `amount_cents`, formatted for the report.
+    pub amount: String,
+
+    /// Number of items for which there was no hourly rate, and which are therefore not included in
+    /// `amount`.
+    pub items_without_rate: i32,
+
+    /// Absences during the report's period. Only filled in if the report is supposed to show them.
+    pub absences: Vec<EmployeeAbsence>,
 }
 
 impl PerEmployeeData {
     /// Get all data for a specific repord and a specific employee.
-    fn compile(conn: &SqliteConnection, report_id: i32, id: i32) -> Result<Self> {
+    ///
+    /// If `absence_period` is given, also list all absences of the employee overlapping it.
+    fn compile(
+        conn: &SqliteConnection,
+        report_id: i32,
+        id: i32,
+        absence_period: Option<(NaiveDate, NaiveDate)>,
+    ) -> Result<Self> {
         use chrono::Duration;
 
         use schema::employees;
@@ -121,7 +205,11 @@ impl PerEmployeeData {
             .first(conn)
             .chain_err(|| "Failed to find employee's name")?;
 
+        let rates = ::rates::get_for_employee(conn, id)?;
+
         let mut total_time = Duration::zero();
+        let mut amount_cents = 0;
+        let mut items_without_rate = 0;
 
         let items = items_view::table
             .filter(items_view::report_id.eq(report_id))
@@ -131,7 +219,7 @@ impl PerEmployeeData {
             .chain_err(|| "Failed to query items_view")?
             .into_iter()
             .map(|item| {
-                let date = format!("{}", item.day.format("%d.\\,%m.\\,%y"));
+                let date = format!("{}", item.day.format(REPORT_DATE_FORMAT));
 
                 let duration = item.end.signed_duration_since(item.start);
                 total_time = total_time + duration;
@@ -139,11 +227,25 @@ impl PerEmployeeData {
                 let hours = format!("{}", duration.num_hours());
                 let minutes = format!("{}", duration.num_minutes() % 60);
 
+                let amount = match ::rates::rate_on(&rates, item.day) {
+                    Some(rate) => {
+                        let cents = ::money::amount_cents(rate.rate_cents, duration.num_minutes());
+                        amount_cents += cents;
+                        ::money::format_euro(cents)
+                    }
+                    None => {
+                        items_without_rate += 1;
+                        "?".to_string()
+                    }
+                };
+
                 EmployeeItem {
                     date,
                     type_of_week: TYPE_OF_WEEK_NAME[item.type_of_week as usize].to_string(),
                     hours,
                     minutes,
+                    amount,
+                    substitutes: item.substitutes_name.unwrap_or_default(),
                     remark: item.remark,
                 }
             })
@@ -152,68 +254,241 @@ impl PerEmployeeData {
         let hours = total_time.num_hours() as i32;
         let minutes = (total_time.num_minutes() % 60) as i32;
 
+        let absences = match absence_period {
+            Some((start, end)) => ::absences::get_between(conn, id, start, end)?
+                .into_iter()
+                .map(|absence| {
+                    let format_date = |date: Result<NaiveDate>| {
+                        date.map(|day| format!("{}", day.format(REPORT_DATE_FORMAT)))
+                    };
+                    Ok(EmployeeAbsence {
+                        from: format_date(absence.start())?,
+                        to: format_date(absence.end())?,
+                        reason: absence.reason_name().to_string(),
+                        remark: absence.remark,
+                    })
+                })
+                .collect::<Result<_>>()?,
+            None => vec![],
+        };
+
         Ok(PerEmployeeData {
+            employee_id: id,
             name,
             hours,
             minutes,
             items,
+            amount_cents,
+            amount: ::money::format_euro(amount_cents),
+            items_without_rate,
+            absences,
         })
     }
 }
 
 /// All the data that goes generating the PDF report.
 ///
-/// This is used to supply the data to the template.
+/// This is used to supply the data to the template, or to `native_pdf` when rendering without
+/// LaTeX.
 #[derive(Serialize)]
 pub struct PerEmployeeReport {
-    title: String,
-    employees: Vec<PerEmployeeData>,
+    pub title: String,
+    pub employees: Vec<PerEmployeeData>,
+
+    /// Grand total of the amounts of all employees in cents.
+    pub amount_cents: i64,
+
+    /// `amount_cents`, formatted for the report.
+    pub amount: String,
+
+    /// Number of items of all employees which are missing from `amount` for lack of an hourly
+    /// rate. Templates have to point this out, so an incomplete total is not paid as it is.
+    pub items_without_rate: i32,
+
+    /// Employees in this report getting close to or exceeding their earnings limits.
+    pub limit_warnings: Vec<String>,
 }
 
 impl PerEmployeeReport {
     /// Read all data for a report from the database.
-    // TODO error handling
-    pub fn generate(conn: &SqliteConnection, report_id: i32) -> Self {
-        use schema::reports;
+    pub fn generate(conn: &SqliteConnection, report_id: i32) -> Result<Self> {
         use schema::items_view;
 
-        assert!(report_id >= 0);
+        let report = get(conn, report_id)?;
 
-        let report_title = reports::table
-            .select(reports::title)
-            .filter(reports::id.eq(report_id))
-            .first(conn)
-            .expect("Could not find report");
+        let absence_period = if report.show_absences {
+            let parse = |date: &str| {
+                NaiveDate::parse_from_str(date, DATE_FORMAT)
+                    .chain_err(|| format!("Invalid date in report #{}: {}", report_id, date))
+            };
+            Some((parse(&report.start_date)?, parse(&report.end_date)?))
+        } else {
+            None
+        };
 
-        let employee_ids = items_view::table
+        let mut employee_ids = items_view::table
             .filter(items_view::report_id.eq(report_id))
-            .select(items_view::employee_id)
+            .select((items_view::employee_id, items_view::name_sort))
             .group_by(items_view::employee_id)
-            .order(items_view::name_sort.asc())
-            .load::<i32>(conn)
-            .unwrap();
+            .load::<(i32, String)>(conn)
+            .chain_err(|| format!("Failed to get employees of report #{}", report_id))?;
+
+        // Employees who were only absent get a section as well, so their absences are listed.
+        if let Some((start, end)) = absence_period {
+            for id in ::absences::employees_between(conn, start, end)? {
+                if !employee_ids.iter().any(|&(known, _)| known == id) {
+                    employee_ids.push((id, ::employees::get_by_id(conn, id)?.name_sort));
+                }
+            }
+        }
+        employee_ids.sort_by(|a, b| ::names::compare(&a.1, &b.1));
 
         let employees = employee_ids
             .into_iter()
-            .map(|id| PerEmployeeData::compile(conn, report_id, id).unwrap())
-            .collect();
+            .map(|(id, _)| PerEmployeeData::compile(conn, report_id, id, absence_period))
+            .collect::<Result<Vec<_>>>()?;
+
+        let amount_cents = employees.iter().map(|employee| employee.amount_cents).sum();
+        let items_without_rate = employees
+            .iter()
+            .map(|employee| employee.items_without_rate)
+            .sum();
+
+        let limit_warnings = Self::limit_warnings(conn, &report, &employees)?;
 
-        Self {
-            title: report_title,
+        Ok(Self {
+            title: report.title,
             employees,
+            amount_cents,
+            amount: ::money::format_euro(amount_cents),
+            items_without_rate,
+            limit_warnings,
+        })
+    }
+
+    /// Check the earnings limits for every calendar year covered by the report.
+    fn limit_warnings(
+        conn: &SqliteConnection,
+        report: &Report,
+        employees: &[PerEmployeeData],
+    ) -> Result<Vec<String>> {
+        use chrono::Datelike;
+
+        let year = |date: &str| -> Result<i32> {
+            NaiveDate::parse_from_str(date, DATE_FORMAT)
+                .map(|day| day.year())
+                .chain_err(|| format!("Invalid date: {}", date))
+        };
+
+        let mut warnings = vec![];
+        for year in year(&report.start_date)?..=year(&report.end_date)? {
+            for warning in ::compliance::check(conn, year)?.warnings {
+                if employees
+                    .iter()
+                    .any(|e| e.employee_id == warning.employee_id)
+                {
+                    warn!("Report #{}: {}", report.id, warning.message);
+                    warnings.push(warning.message);
+                }
+            }
+        }
+        Ok(warnings)
+    }
+
+    /// Made-up data containing every field, used to check templates before they are saved.
+    pub fn example() -> Self {
+        PerEmployeeReport {
+            title: "Beispiel".to_string(),
+            employees: vec![PerEmployeeData {
+                employee_id: 1,
+                name: "Erika Mustermann".to_string(),
+                hours: 2,
+                minutes: 30,
+                items: vec![EmployeeItem {
+                    date: "30.\u{2009}08.\u{2009}17".to_string(),
+                    type_of_week: "A".to_string(),
+                    hours: "2".to_string(),
+                    minutes: "30".to_string(),
+                    amount: ::money::format_euro(2500),
+                    substitutes: "Max Mustermann".to_string(),
+                    remark: "Hausaufgaben".to_string(),
+                }],
+                amount_cents: 2500,
+                amount: ::money::format_euro(2500),
+                items_without_rate: 0,
+                absences: vec![EmployeeAbsence {
+                    from: "31.\u{2009}08.\u{2009}17".to_string(),
+                    to: "01.\u{2009}09.\u{2009}17".to_string(),
+                    reason: "Krankheit".to_string(),
+                    remark: "".to_string(),
+                }],
+            }],
+            amount_cents: 2500,
+            amount: ::money::format_euro(2500),
+            items_without_rate: 0,
+            limit_warnings: vec!["Erika Mustermann hat fast zu viel verdient.".to_string()],
+        }
+    }
+
+    /// Drop the individual items, keeping only the totals per employee and for the report.
+    pub fn summary(&self, report_id: i32) -> ReportSummary {
+        ReportSummary {
+            report_id,
+            title: self.title.clone(),
+            employees: self
+                .employees
+                .iter()
+                .map(|employee| EmployeeSummary {
+                    employee_id: employee.employee_id,
+                    name: employee.name.clone(),
+                    hours: employee.hours,
+                    minutes: employee.minutes,
+                    amount_cents: employee.amount_cents,
+                    amount: employee.amount.clone(),
+                    items_without_rate: employee.items_without_rate,
+                })
+                .collect(),
+            amount_cents: self.amount_cents,
+            amount: self.amount.clone(),
+            items_without_rate: self.items_without_rate,
+            limit_warnings: self.limit_warnings.clone(),
         }
     }
 }
 
+/// Totals for one employee in a report.
+#[derive(Serialize)]
+pub struct EmployeeSummary {
+    pub employee_id: i32,
+    pub name: String,
+    pub hours: i32,
+    pub minutes: i32,
+    pub amount_cents: i64,
+    pub amount: String,
+    pub items_without_rate: i32,
+}
+
+/// Totals per employee and for the whole report, without the individual items.
+#[derive(Serialize)]
+pub struct ReportSummary {
+    pub report_id: i32,
+    pub title: String,
+    pub employees: Vec<EmployeeSummary>,
+    pub amount_cents: i64,
+    pub amount: String,
+    pub items_without_rate: i32,
+    pub limit_warnings: Vec<String>,
+}
+
 /// For the report with a given id, set `was_pdf_generated` to true.
-// TODO error handling?
-pub fn set_pdf_generated(conn: &SqliteConnection, id: i32) {
+pub fn set_pdf_generated(conn: &SqliteConnection, id: i32) -> Result<()> {
     assert!(id >= 0);
 
     diesel::update(reports::table.filter(reports::id.eq(id)))
         .set(reports::was_pdf_generated.eq(true))
         .execute(conn)
-        .expect("Failed to update was_pdf_generated");
+        .map(|_| ())
+        .chain_err(|| format!("Failed to mark PDF of report #{} as generated", id))
 }
 
 /// Generate reasonable start and end date for a new report.
@@ -247,5 +522,85 @@ pub fn template(conn: &SqliteConnection) -> Report {
         start_date,
         end_date: format!("{}", Local::today().format(DATE_FORMAT)),
         was_pdf_generated: false,
+        show_absences: false,
+        template: None,
+        revision: 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use absences::{self, Absence};
+    use employees;
+    use test_data;
+
+    #[test]
+    fn test_absences_without_items() {
+        let conn = ::db::test_connection();
+        let id = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        let anna = employees::insert(&conn, "Anna Schmidt").unwrap();
+        let jan = employees::insert(&conn, "Jan Berg").unwrap();
+        test_data::item(&conn, id, anna, "2018-03-05", (12, 14), None);
+        let absence = Absence {
+            id: 0,
+            employee_id: jan,
+            start_date: "2018-02-26".to_string(),
+            end_date: "2018-03-02".to_string(),
+            reason: "vacation".to_string(),
+            remark: String::new(),
+        };
+        absences::insert(&conn, &absence).unwrap();
+
+        let report = PerEmployeeReport::generate(&conn, id).unwrap();
+        assert_eq!(report.employees.len(), 1);
+        assert_eq!(report.employees[0].name, "Anna Schmidt");
+
+        diesel::update(reports::table.find(id))
+            .set(reports::show_absences.eq(true))
+            .execute(&conn)
+            .unwrap();
+        let report = PerEmployeeReport::generate(&conn, id).unwrap();
+        let names: Vec<_> = report.employees.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["Jan Berg", "Anna Schmidt"]);
+        assert!(report.employees[0].items.is_empty());
+        assert_eq!(report.employees[0].absences[0].reason, "Urlaub");
+    }
+
+    #[test]
+    fn test_update_template() {
+        let conn = ::db::test_connection();
+        let id = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        let mut report = get(&conn, id).unwrap();
+
+        report.template = Some("unbekannt".to_string());
+        assert!(update(&conn, &report).is_err());
+        report.template = Some(::templates::BUILTIN.to_string());
+        update(&conn, &report).unwrap();
+        assert_eq!(get(&conn, id).unwrap().template, report.template);
+    }
+
+    #[test]
+    fn test_add_ignores_database_fields() {
+        let conn = ::db::test_connection();
+        let report: NewReport = ::serde_json::from_value(json!({
+            "id": 42,
+            "title": "März 2018",
+            "start_date": "2018-03-01",
+            "end_date": "2018-03-31",
+            "was_pdf_generated": true,
+            "revision": 7,
+        }))
+        .unwrap();
+        let id = add(&conn, &report).unwrap();
+        assert_ne!(id, 42);
+
+        let added = get(&conn, id).unwrap();
+        assert_eq!(added.title, "März 2018");
+        assert!(!added.was_pdf_generated);
+        assert_eq!(added.revision, 0);
+        assert!(!added.show_absences);
+        assert_eq!(added.template, None);
     }
 }
@@ -3,7 +3,7 @@ infer_schema!("dotenv:DATABASE_URL");
 table! {
     /// Representation of the `items_view` view.
     items_view (id, employee_id, report_id, name, name_sort, day, type_of_week,
-                start, end, remark) {
+                start, end, remark, substitutes_employee_id, substitutes_name) {
         id -> Integer,
         employee_id -> Integer,
         report_id -> Integer,
@@ -14,5 +14,7 @@ table! {
         start -> Time,
         end -> Time,
         remark -> Text,
+        substitutes_employee_id -> Nullable<Integer>,
+        substitutes_name -> Nullable<Text>,
     }
 }
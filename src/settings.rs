@@ -0,0 +1,252 @@
+use std::str::FromStr;
+
+use diesel::prelude::*;
+use diesel::{self, SqliteConnection};
+
+use archive;
+use compliance;
+use coverage;
+use employees;
+use errors::*;
+use export;
+use generate_pdf;
+use items;
+use schema::settings;
+use snapshots;
+use templates;
+
+/// A configuration value stored in the `settings` table.
+///
+/// Settings that were never set are not stored; the code using them provides a default instead.
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "settings"]
+pub struct Setting {
+    pub key: String,
+    pub value: String,
+}
+
+/// Get all settings that have been set explicitly.
+pub fn get_all(conn: &SqliteConnection) -> Result<Vec<Setting>> {
+    settings::table
+        .order(settings::key)
+        .load::<Setting>(conn)
+        .chain_err(|| "Failed to read table settings")
+}
+
+/// Get the value of a setting, or `default` if it has not been set.
+pub fn get<T: FromStr>(conn: &SqliteConnection, key: &str, default: T) -> Result<T> {
+    let value = settings::table
+        .select(settings::value)
+        .filter(settings::key.eq(key))
+        .first::<String>(conn)
+        .optional()
+        .chain_err(|| format!("Failed to read setting {}", key))?;
+
+    match value {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("Invalid value for setting {}: {}", key, value).into()),
+        None => Ok(default),
+    }
+}
+
+/// A setting used somewhere in the application, and the values it accepts.
+struct Known {
+    key: &'static str,
+    /// Description of the valid values, for error messages.
+    expected: &'static str,
+    is_valid: fn(&str) -> bool,
+}
+
+fn is_count(value: &str) -> bool {
+    value
+        .parse::<u32>()
+        .ok()
+        .map_or(false, |n| n <= i32::max_value() as u32)
+}
+
+fn is_positive(value: &str) -> bool {
+    is_count(value) && value.parse::<u32>().ok().map_or(false, |n| n > 0)
+}
+
+fn is_percent(value: &str) -> bool {
+    value
+        .parse::<u8>()
+        .ok()
+        .map_or(false, |n| 0 < n && n <= 100)
+}
+
+fn is_bool(value: &str) -> bool {
+    value.parse::<bool>().is_ok()
+}
+
+fn is_ascii_char(value: &str) -> bool {
+    value.parse::<char>().ok().map_or(false, |c| c.is_ascii())
+}
+
+fn is_encoding(value: &str) -> bool {
+    export::ENCODINGS.contains(&value)
+}
+
+fn is_backend(value: &str) -> bool {
+    value == "latex" || value == "native"
+}
+
+fn is_not_empty(value: &str) -> bool {
+    !value.trim().is_empty()
+}
+
+fn is_anything(_value: &str) -> bool {
+    true
+}
+
+const COUNT: &str = "a whole number of at least 0";
+
+const KNOWN: &[Known] = &[
+    Known {
+        key: archive::DATA_DIR,
+        expected: "a directory",
+        is_valid: is_not_empty,
+    },
+    Known {
+        key: compliance::MINIJOB_MONTHLY_LIMIT,
+        expected: COUNT,
+        is_valid: is_count,
+    },
+    Known {
+        key: compliance::MINIJOB_YEARLY_LIMIT,
+        expected: COUNT,
+        is_valid: is_count,
+    },
+    Known {
+        key: compliance::UEBUNGSLEITER_YEARLY_LIMIT,
+        expected: COUNT,
+        is_valid: is_count,
+    },
+    Known {
+        key: compliance::WARNING_THRESHOLD,
+        expected: "a percentage from 1 to 100",
+        is_valid: is_percent,
+    },
+    Known {
+        key: coverage::DEFAULT_MINIMUM,
+        expected: COUNT,
+        is_valid: is_count,
+    },
+    Known {
+        key: employees::NAME_PARTICLES,
+        expected: "a comma-separated list",
+        is_valid: is_anything,
+    },
+    Known {
+        key: export::CSV_DELIMITER,
+        expected: "a single ASCII character",
+        is_valid: is_ascii_char,
+    },
+    Known {
+        key: export::CSV_ENCODING,
+        expected: "utf-8, utf-8-bom or windows-1252",
+        is_valid: is_encoding,
+    },
+    Known {
+        key: generate_pdf::PDF_BACKEND,
+        expected: "latex or native",
+        is_valid: is_backend,
+    },
+    Known {
+        key: items::STRICT_EMPLOYEES,
+        expected: "true or false",
+        is_valid: is_bool,
+    },
+    Known {
+        key: snapshots::INTERVAL_HOURS,
+        expected: COUNT,
+        is_valid: is_count,
+    },
+    Known {
+        key: snapshots::KEEP,
+        expected: "a whole number of at least 1",
+        is_valid: is_positive,
+    },
+    Known {
+        key: templates::DEFAULT_TEMPLATE,
+        expected: "a template name",
+        is_valid: is_not_empty,
+    },
+    Known {
+        key: templates::TEMPLATE_DIR,
+        expected: "a directory, or nothing",
+        is_valid: is_anything,
+    },
+];
+
+/// Make sure a setting is used by the application and the value makes sense for it, so a typo
+/// cannot break everything that reads the setting.
+pub fn validate(key: &str, value: &str) -> Result<()> {
+    let known = KNOWN
+        .iter()
+        .find(|known| known.key == key)
+        .ok_or_else(|| format!("Unknown setting: {}", key))?;
+    if !(known.is_valid)(value) {
+        bail!(
+            "Invalid value for setting {}, expected {}: {}",
+            key,
+            known.expected,
+            value
+        );
+    }
+    Ok(())
+}
+
+/// Set a setting to a new value, after checking it with `validate`.
+pub fn set(conn: &SqliteConnection, key: &str, value: &str) -> Result<()> {
+    validate(key, value)?;
+
+    diesel::replace_into(settings::table)
+        .values(&Setting {
+            key: key.into(),
+            value: value.into(),
+        })
+        .execute(conn)
+        .map(|_| ())
+        .chain_err(|| format!("Failed to set {} to {}", key, value))
+}
+
+/// Remove a setting, so its default value is used again.
+pub fn reset(conn: &SqliteConnection, key: &str) -> Result<()> {
+    diesel::delete(settings::table.filter(settings::key.eq(key)))
+        .execute(conn)
+        .map(|_| ())
+        .chain_err(|| format!("Failed to reset setting {}", key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(compliance::WARNING_THRESHOLD, "80").is_ok());
+        assert!(validate(compliance::WARNING_THRESHOLD, "abc").is_err());
+        assert!(validate(compliance::WARNING_THRESHOLD, "0").is_err());
+        assert!(validate(compliance::MINIJOB_MONTHLY_LIMIT, "-1").is_err());
+        assert!(validate(export::CSV_DELIMITER, ";").is_ok());
+        assert!(validate(export::CSV_DELIMITER, ";;").is_err());
+        assert!(validate(items::STRICT_EMPLOYEES, "yes").is_err());
+        assert!(validate(templates::TEMPLATE_DIR, "").is_ok());
+        assert!(validate(snapshots::KEEP, "1").is_ok());
+        assert!(validate(snapshots::KEEP, "0").is_err());
+        assert!(validate(snapshots::INTERVAL_HOURS, "0").is_ok());
+        assert!(validate("limit_warning_percentage", "80").is_err());
+    }
+
+    #[test]
+    fn test_set() {
+        let conn = ::db::test_connection();
+        set(&conn, compliance::WARNING_THRESHOLD, "80").unwrap();
+        assert!(set(&conn, compliance::WARNING_THRESHOLD, "abc").is_err());
+        assert_eq!(get(&conn, compliance::WARNING_THRESHOLD, 90).unwrap(), 80);
+        assert!(set(&conn, "unknown", "1").is_err());
+        assert!(get_all(&conn).unwrap().iter().all(|s| s.key != "unknown"));
+    }
+}
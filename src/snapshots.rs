@@ -0,0 +1,538 @@
+use std::ffi::{CStr, CString, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use chrono::Utc;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use diesel::{self, SqliteConnection};
+use error_chain::ChainedError;
+use fs2::FileExt;
+use libsqlite3_sys as ffi;
+
+use archive;
+use db::{self, Pool};
+use errors::*;
+use settings;
+
+/// Setting for the number of hours between scheduled snapshots, or 0 to disable them.
+pub const INTERVAL_HOURS: &str = "snapshot_interval_hours";
+/// Setting for the number of snapshots to keep for each reason. Older ones are deleted.
+pub const KEEP: &str = "snapshot_keep";
+
+/// How often the scheduler checks whether a snapshot is due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How long to wait for other connections to finish writing before giving up on a snapshot.
+const BUSY_RETRIES: u32 = 50;
+const BUSY_WAIT: Duration = Duration::from_millis(100);
+
+const EXTENSION: &str = "sqlite";
+
+/// Length of the time at the start of a snapshot's name, e.g. `2018-05-08_12-00-00_`.
+const TIME_PREFIX: usize = 20;
+
+/// A copy of the database in the snapshot directory.
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    /// The file name, which starts with the time the snapshot was taken and ends with the reason,
+    /// e.g. `2018-05-08_12-00-00_merge.sqlite`.
+    pub name: String,
+    /// Why the snapshot was taken, e.g. `merge`.
+    pub reason: String,
+    /// Size in bytes.
+    pub size: u64,
+}
+
+/// Snapshots are kept in the `snapshots` directory of the data directory.
+pub fn dir(conn: &SqliteConnection) -> Result<PathBuf> {
+    Ok(archive::data_dir(conn)?.join("snapshots"))
+}
+
+/// All snapshots, newest first.
+pub fn list(conn: &SqliteConnection) -> Result<Vec<Snapshot>> {
+    list_in(&dir(conn)?)
+}
+
+/// The reason in the name of a snapshot, if it is a valid name.
+fn reason_of(name: &str) -> Option<&str> {
+    let suffix = format!(".{}", EXTENSION);
+    if !name.ends_with(&suffix) {
+        return None;
+    }
+    let reason = name.get(TIME_PREFIX..name.len() - suffix.len())?;
+    check_reason(reason).ok().map(|()| reason)
+}
+
+fn list_in(dir: &Path) -> Result<Vec<Snapshot>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut snapshots = vec![];
+    for entry in fs::read_dir(dir).chain_err(|| format!("Failed to list {}", dir.display()))? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let reason = match reason_of(&name) {
+            Some(reason) => reason.to_string(),
+            None => continue,
+        };
+        snapshots.push(Snapshot {
+            size: fs::metadata(&path)?.len(),
+            name,
+            reason,
+        });
+    }
+
+    snapshots.sort_by(|a, b| b.name.cmp(&a.name));
+    Ok(snapshots)
+}
+
+/// Reasons become part of the file name, so they are restricted to letters, digits and dashes.
+fn check_reason(reason: &str) -> Result<()> {
+    let is_valid = |c: char| c.is_ascii_alphanumeric() || c == '-';
+    if reason.is_empty() || !reason.chars().all(is_valid) {
+        bail!("Invalid reason for a snapshot: {:?}", reason);
+    }
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct DatabaseFile {
+    #[sql_type = "Text"]
+    name: String,
+    #[sql_type = "Text"]
+    file: String,
+}
+
+/// The file the connection's database is stored in.
+fn database_file(conn: &SqliteConnection) -> Result<PathBuf> {
+    let main = diesel::sql_query("PRAGMA database_list")
+        .load::<DatabaseFile>(conn)
+        .chain_err(|| "Failed to find the database file")?
+        .into_iter()
+        .find(|database| database.name == "main");
+    match main {
+        Some(ref database) if !database.file.is_empty() => Ok(PathBuf::from(&database.file)),
+        _ => bail!("Snapshots need a database stored in a file"),
+    }
+}
+
+/// An SQLite connection opened without Diesel, which is closed when it is dropped.
+struct RawConnection(*mut ffi::sqlite3);
+
+impl RawConnection {
+    fn open(path: &Path, flags: i32) -> Result<RawConnection> {
+        let c_path = path
+            .to_str()
+            .and_then(|path| CString::new(path).ok())
+            .ok_or_else(|| format!("Invalid path: {}", path.display()))?;
+        let mut handle = ptr::null_mut();
+        let code =
+            unsafe { ffi::sqlite3_open_v2(c_path.as_ptr(), &mut handle, flags, ptr::null()) };
+        // Even if opening fails, SQLite usually allocates a handle that has to be closed.
+        let conn = RawConnection(handle);
+        if code != ffi::SQLITE_OK {
+            bail!(
+                "Failed to open {}: {}",
+                path.display(),
+                conn.error_message()
+            );
+        }
+        Ok(conn)
+    }
+
+    fn error_message(&self) -> String {
+        if self.0.is_null() {
+            return "out of memory".to_string();
+        }
+        unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(self.0)) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl Drop for RawConnection {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_close(self.0);
+        }
+    }
+}
+
+/// Copy the database at `source` to `dest` with SQLite's online backup API.
+///
+/// This works with any SQLite version and while other connections keep using the database. If
+/// they hold a write lock, the copy is retried for a few seconds.
+fn backup(source: &Path, dest: &Path) -> Result<()> {
+    let from = RawConnection::open(source, ffi::SQLITE_OPEN_READONLY)?;
+    let to = RawConnection::open(dest, ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE)?;
+    let main = CString::new("main").unwrap();
+
+    let code = unsafe {
+        let backup = ffi::sqlite3_backup_init(to.0, main.as_ptr(), from.0, main.as_ptr());
+        if backup.is_null() {
+            bail!(
+                "Failed to copy {}: {}",
+                source.display(),
+                to.error_message()
+            );
+        }
+        let mut retries = 0;
+        let code = loop {
+            match ffi::sqlite3_backup_step(backup, -1) {
+                ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED if retries < BUSY_RETRIES => {
+                    retries += 1;
+                    thread::sleep(BUSY_WAIT);
+                }
+                code => break code,
+            }
+        };
+        ffi::sqlite3_backup_finish(backup);
+        code
+    };
+    if code != ffi::SQLITE_DONE {
+        bail!(
+            "Failed to copy {} (error {}): {}",
+            source.display(),
+            code,
+            to.error_message()
+        );
+    }
+    Ok(())
+}
+
+/// Copy the database into a new snapshot while the server keeps running.
+///
+/// The `reason` becomes part of the file name. Only the newest `KEEP` snapshots with the same
+/// reason are kept, so frequent merges cannot push out the scheduled snapshots.
+pub fn take(conn: &SqliteConnection, reason: &str) -> Result<Snapshot> {
+    check_reason(reason)?;
+    let keep = settings::get(conn, KEEP, 14)?;
+    take_in(&database_file(conn)?, &dir(conn)?, reason, keep)
+}
+
+fn take_in(database: &Path, dir: &Path, reason: &str, keep: usize) -> Result<Snapshot> {
+    fs::create_dir_all(dir).chain_err(|| format!("Failed to create {}", dir.display()))?;
+
+    let name = format!(
+        "{}_{}.{}",
+        Utc::now().format("%Y-%m-%d_%H-%M-%S"),
+        reason,
+        EXTENSION
+    );
+    let path = dir.join(&name);
+
+    // Write to a temporary file first, so an incomplete copy never looks like a snapshot.
+    let partial = path.with_extension("part");
+    backup(database, &partial)
+        .chain_err(|| format!("Failed to write snapshot {}", path.display()))?;
+    fs::rename(&partial, &path)
+        .chain_err(|| format!("Failed to move snapshot to {}", path.display()))?;
+    info!("Saved snapshot {}", path.display());
+
+    let size = fs::metadata(&path)?.len();
+    rotate_in(dir, reason, keep)?;
+    Ok(Snapshot {
+        name,
+        reason: reason.to_string(),
+        size,
+    })
+}
+
+/// Delete all but the newest `keep` snapshots taken for `reason`.
+///
+/// At least one is always kept, so the snapshot that was just taken survives.
+fn rotate_in(dir: &Path, reason: &str, keep: usize) -> Result<()> {
+    let old = list_in(dir)?
+        .into_iter()
+        .filter(|snapshot| snapshot.reason == reason)
+        .skip(keep.max(1));
+    for snapshot in old {
+        let path = dir.join(&snapshot.name);
+        fs::remove_file(&path).chain_err(|| format!("Failed to delete {}", path.display()))?;
+        info!("Deleted old snapshot {}", path.display());
+    }
+    Ok(())
+}
+
+/// Whether the newest snapshot is older than the configured interval.
+fn is_due(conn: &SqliteConnection) -> Result<bool> {
+    let hours: u64 = settings::get(conn, INTERVAL_HOURS, 24)?;
+    if hours == 0 {
+        return Ok(false);
+    }
+
+    let newest = match list(conn)?.into_iter().next() {
+        Some(snapshot) => snapshot,
+        None => return Ok(true),
+    };
+    let taken = fs::metadata(dir(conn)?.join(&newest.name))?.modified()?;
+    let age = SystemTime::now()
+        .duration_since(taken)
+        .unwrap_or_else(|_| Duration::from_secs(0));
+    Ok(age >= Duration::from_secs(hours * 60 * 60))
+}
+
+/// Start a thread taking snapshots according to the `INTERVAL_HOURS` setting.
+///
+/// Snapshots taken for other reasons count as well, so right after a merge there is no need for
+/// another one.
+pub fn schedule(pool: Pool) -> Result<()> {
+    thread::Builder::new()
+        .name("snapshots".to_string())
+        .spawn(move || loop {
+            match pool.get() {
+                Ok(conn) => {
+                    let result = is_due(&conn).and_then(|due| {
+                        if due {
+                            take(&conn, "scheduled")?;
+                        }
+                        Ok(())
+                    });
+                    if let Err(e) = result {
+                        error!("Scheduled snapshot failed: {}", e.display_chain());
+                    }
+                }
+                Err(e) => error!("Snapshot scheduler cannot connect to the database: {}", e),
+            }
+            thread::sleep(CHECK_INTERVAL);
+        })
+        .chain_err(|| "Failed to start snapshot scheduler")?;
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct IntegrityCheck {
+    #[sql_type = "Text"]
+    integrity_check: String,
+}
+
+/// Run SQLite's integrity check on a database file.
+pub fn verify(path: &Path) -> Result<()> {
+    // Connecting would create an empty database if the file did not exist.
+    if !path.is_file() {
+        bail!("No such file: {}", path.display());
+    }
+    let url = path
+        .to_str()
+        .ok_or_else(|| format!("Invalid path: {}", path.display()))?;
+    let conn = SqliteConnection::establish(url)
+        .chain_err(|| format!("Failed to open {}", path.display()))?;
+
+    let problems: Vec<_> = diesel::sql_query("PRAGMA integrity_check")
+        .load::<IntegrityCheck>(&conn)
+        .chain_err(|| format!("Failed to check {}", path.display()))?
+        .into_iter()
+        .map(|row| row.integrity_check)
+        .filter(|row| row != "ok")
+        .collect();
+    if !problems.is_empty() {
+        bail!("{} is damaged: {}", path.display(), problems.join("; "));
+    }
+    Ok(())
+}
+
+/// A file next to the database, e.g. its journal.
+fn next_to(database: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(database.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn lock_file(database: &Path) -> Result<File> {
+    let path = next_to(database, ".lock");
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(&path)
+        .chain_err(|| format!("Failed to open {}", path.display()))
+}
+
+/// Lock the database for as long as the server runs, which is how `restore` tells that it is
+/// running. The lock is released when the returned file is closed, even if the server crashes.
+pub fn lock_database() -> Result<File> {
+    let file = lock_file(db::path())?;
+    file.try_lock_exclusive()
+        .chain_err(|| "The database is in use, is the server already running?")?;
+    Ok(file)
+}
+
+/// Make sure nothing uses the database and it is safe to replace the file.
+///
+/// SQLite would apply a leftover journal or write-ahead log to whatever file it finds under the
+/// database's name, so these have to be dealt with before a restore.
+fn check_unused(database: &Path) -> Result<File> {
+    let lock = lock_file(database)?;
+    lock.try_lock_exclusive()
+        .chain_err(|| "The server is running, stop it before restoring a snapshot")?;
+
+    for suffix in &["-wal", "-journal"] {
+        let path = next_to(database, suffix);
+        if path.exists() {
+            bail!(
+                "{} exists, open the database with sqlite3 once to recover it before restoring a \
+                 snapshot",
+                path.display()
+            );
+        }
+    }
+    Ok(lock)
+}
+
+/// Replace the database with a snapshot, given by its name or path. The server must not be
+/// running.
+///
+/// The snapshot is checked before anything is changed, and the current database is saved as
+/// another snapshot first, so a restore can be undone as well.
+pub fn restore(snapshot: &str) -> Result<PathBuf> {
+    let database = db::path();
+    let _lock = check_unused(database)?;
+
+    let conn = db::connect();
+    let mut path = PathBuf::from(snapshot);
+    if !path.is_file() {
+        path = dir(&conn)?.join(snapshot);
+    }
+    verify(&path)?;
+
+    // Copy the snapshot first, as rotation could delete it.
+    let temp = database.with_extension("restore");
+    fs::copy(&path, &temp).chain_err(|| format!("Failed to copy {}", path.display()))?;
+    take(&conn, "before-restore")?;
+    drop(conn);
+
+    fs::rename(&temp, database)
+        .chain_err(|| format!("Failed to replace {}", database.display()))?;
+    info!("Restored {}", path.display());
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn touch(dir: &Path, name: &str) {
+        File::create(dir.join(name)).unwrap();
+    }
+
+    fn names(dir: &Path) -> Vec<String> {
+        list_in(dir)
+            .unwrap()
+            .into_iter()
+            .map(|snapshot| snapshot.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_check_reason() {
+        assert!(check_reason("merge").is_ok());
+        assert!(check_reason("before-restore").is_ok());
+        assert!(check_reason("").is_err());
+        assert!(check_reason("a_b").is_err());
+        assert!(check_reason("../x").is_err());
+    }
+
+    #[test]
+    fn test_list() {
+        let dir = TempDir::new("snapshots").unwrap();
+        assert!(list_in(&dir.path().join("missing")).unwrap().is_empty());
+
+        touch(dir.path(), "2018-05-08_12-00-00_merge.sqlite");
+        touch(dir.path(), "2018-05-09_12-00-00_scheduled.sqlite");
+        touch(dir.path(), "2018-05-09_13-00-00_merge.part");
+        touch(dir.path(), "notes.sqlite");
+        let snapshots = list_in(dir.path()).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].name, "2018-05-09_12-00-00_scheduled.sqlite");
+        assert_eq!(snapshots[0].reason, "scheduled");
+        assert_eq!(snapshots[1].reason, "merge");
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = TempDir::new("snapshots").unwrap();
+        for day in 1..5 {
+            touch(
+                dir.path(),
+                &format!("2018-05-0{}_12-00-00_merge.sqlite", day),
+            );
+        }
+        touch(dir.path(), "2018-04-01_12-00-00_scheduled.sqlite");
+
+        rotate_in(dir.path(), "merge", 2).unwrap();
+        assert_eq!(
+            names(dir.path()),
+            vec![
+                "2018-05-04_12-00-00_merge.sqlite",
+                "2018-05-03_12-00-00_merge.sqlite",
+                "2018-04-01_12-00-00_scheduled.sqlite",
+            ]
+        );
+
+        // The newest snapshot is never deleted.
+        rotate_in(dir.path(), "merge", 0).unwrap();
+        rotate_in(dir.path(), "scheduled", 0).unwrap();
+        assert_eq!(
+            names(dir.path()),
+            vec![
+                "2018-05-04_12-00-00_merge.sqlite",
+                "2018-04-01_12-00-00_scheduled.sqlite",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_take() {
+        let dir = TempDir::new("snapshots").unwrap();
+        let database = dir.path().join("database.sqlite");
+        let conn = ::db::test_database(database.to_str().unwrap());
+        settings::set(&conn, archive::DATA_DIR, dir.path().to_str().unwrap()).unwrap();
+        ::employees::insert(&conn, "Anna Schmidt").unwrap();
+
+        assert!(take(&conn, "a b").is_err());
+        let snapshot = take(&conn, "manual").unwrap();
+        assert_eq!(snapshot.reason, "manual");
+        assert_eq!(list(&conn).unwrap()[0].name, snapshot.name);
+
+        let path = dir.path().join("snapshots").join(&snapshot.name);
+        verify(&path).unwrap();
+        let copy = SqliteConnection::establish(path.to_str().unwrap()).unwrap();
+        assert_eq!(::employees::get(&copy, false).unwrap().len(), 1);
+
+        assert!(take(&::db::test_connection(), "manual").is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = TempDir::new("snapshots").unwrap();
+        assert!(verify(&dir.path().join("missing.sqlite")).is_err());
+
+        let path = dir.path().join("garbage.sqlite");
+        fs::write(&path, vec![0x42; 4096]).unwrap();
+        assert!(verify(&path).is_err());
+    }
+
+    #[test]
+    fn test_check_unused() {
+        let dir = TempDir::new("snapshots").unwrap();
+        let database = dir.path().join("database.sqlite");
+        touch(dir.path(), "database.sqlite");
+
+        let server = lock_file(&database).unwrap();
+        server.try_lock_exclusive().unwrap();
+        assert!(check_unused(&database).is_err());
+        server.unlock().unwrap();
+        drop(check_unused(&database).unwrap());
+
+        touch(dir.path(), "database.sqlite-journal");
+        assert!(check_unused(&database).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, NaiveDate};
+use diesel::prelude::*;
+use diesel::SqliteConnection;
+use serde_json::{self, Value};
+
+use errors::*;
+use items::InvoiceItem;
+use reports;
+use weeks::{TYPE_OF_WEEK_NAME, WEEKDAY_NAME};
+use DATE_FORMAT;
+
+/// Restricts which items are included in the statistics. Everything is included by default.
+#[derive(Debug, Default, FromForm)]
+pub struct Filter {
+    /// First day to include, as `YYYY-MM-DD`.
+    pub from: Option<String>,
+    /// Last day to include, as `YYYY-MM-DD`.
+    pub to: Option<String>,
+    pub employee: Option<i32>,
+}
+
+impl Filter {
+    fn parse_date(date: &Option<String>) -> Result<Option<NaiveDate>> {
+        match *date {
+            Some(ref date) => NaiveDate::parse_from_str(date, DATE_FORMAT)
+                .map(Some)
+                .chain_err(|| format!("Invalid date, expected YYYY-MM-DD: {}", date)),
+            None => Ok(None),
+        }
+    }
+
+    /// All items matching the filter.
+    fn load(&self, conn: &SqliteConnection) -> Result<Vec<InvoiceItem>> {
+        use schema::items_view;
+
+        let mut query = items_view::table.into_boxed();
+        if let Some(from) = Self::parse_date(&self.from)? {
+            query = query.filter(items_view::day.ge(from));
+        }
+        if let Some(to) = Self::parse_date(&self.to)? {
+            query = query.filter(items_view::day.le(to));
+        }
+        if let Some(employee) = self.employee {
+            query = query.filter(items_view::employee_id.eq(employee));
+        }
+
+        query
+            .order((items_view::day.asc(), items_view::start.asc()))
+            .load::<InvoiceItem>(conn)
+            .chain_err(|| "Failed to query items for statistics")
+    }
+}
+
+/// How much work was done, e.g. by one employee in one month.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Total {
+    /// Number of items.
+    pub shifts: usize,
+    pub minutes: i64,
+    /// `minutes` in hours, for convenience.
+    pub hours: f64,
+    /// Average length of a shift in minutes, rounded down.
+    pub average_minutes: i64,
+}
+
+impl Total {
+    fn add(&mut self, item: &InvoiceItem) {
+        self.shifts += 1;
+        self.minutes += item.end.signed_duration_since(item.start).num_minutes();
+        self.hours = self.minutes as f64 / 60.0;
+        self.average_minutes = self.minutes / self.shifts as i64;
+    }
+
+    fn of(items: &[&InvoiceItem]) -> Self {
+        let mut total = Total::default();
+        for item in items {
+            total.add(item);
+        }
+        total
+    }
+}
+
+/// The total of some period, e.g. `2018-03` for a month or `2017/18` for a school year.
+#[derive(Debug, Serialize)]
+pub struct PeriodTotal {
+    pub period: String,
+    /// The report, when grouping by report.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report_id: Option<i32>,
+    #[serde(flatten)]
+    pub total: Total,
+}
+
+/// Totals of one employee, overall and for each period.
+#[derive(Debug, Serialize)]
+pub struct EmployeeStatistics {
+    pub employee_id: i32,
+    pub name: String,
+    #[serde(flatten)]
+    pub total: Total,
+    pub periods: Vec<PeriodTotal>,
+}
+
+/// The total for one value of some property, e.g. all Mondays or all A weeks.
+#[derive(Debug, Serialize)]
+pub struct Bucket {
+    pub label: String,
+    #[serde(flatten)]
+    pub total: Total,
+}
+
+/// The items of each employee, sorted by name.
+fn by_employee(items: &[InvoiceItem]) -> Vec<Vec<&InvoiceItem>> {
+    let mut by_employee: BTreeMap<i32, Vec<&InvoiceItem>> = BTreeMap::new();
+    for item in items {
+        by_employee
+            .entry(item.employee_id)
+            .or_insert_with(Vec::new)
+            .push(item);
+    }
+
+    let mut employees: Vec<_> = by_employee.into_iter().map(|(_, items)| items).collect();
+    employees.sort_by(|a, b| ::names::compare(&a[0].name_sort, &b[0].name_sort));
+    employees
+}
+
+/// Group the items of each employee by some period. The key determines the order of the periods.
+fn per_period<K, F>(items: &[InvoiceItem], period: F) -> Vec<EmployeeStatistics>
+where
+    K: Ord,
+    F: Fn(&InvoiceItem) -> (K, String, Option<i32>),
+{
+    by_employee(items)
+        .into_iter()
+        .map(|items| {
+            let mut periods: BTreeMap<K, PeriodTotal> = BTreeMap::new();
+            for item in &items {
+                let (key, name, report_id) = period(item);
+                periods
+                    .entry(key)
+                    .or_insert_with(|| PeriodTotal {
+                        period: name,
+                        report_id,
+                        total: Total::default(),
+                    })
+                    .total
+                    .add(item);
+            }
+
+            EmployeeStatistics {
+                employee_id: items[0].employee_id,
+                name: items[0].name.clone(),
+                total: Total::of(&items),
+                periods: periods.into_iter().map(|(_, total)| total).collect(),
+            }
+        })
+        .collect()
+}
+
+/// Hours of each employee per calendar month.
+pub fn per_month(items: &[InvoiceItem]) -> Vec<EmployeeStatistics> {
+    per_period(items, |item| {
+        let month = (item.day.year(), item.day.month());
+        (month, format!("{}-{:02}", month.0, month.1), None)
+    })
+}
+
+/// Hours of each employee per school year.
+pub fn per_school_year(items: &[InvoiceItem]) -> Vec<EmployeeStatistics> {
+    per_period(items, |item| {
+        let year = ::batch::school_year_of(item.day);
+        (year, format!("{}/{:02}", year, (year + 1) % 100), None)
+    })
+}
+
+/// Hours of each employee per report, labelled with the report's title.
+fn per_report(conn: &SqliteConnection, items: &[InvoiceItem]) -> Result<Vec<EmployeeStatistics>> {
+    let titles: BTreeMap<i32, (String, String)> = reports::get_all(conn)?
+        .into_iter()
+        .map(|report| (report.id, (report.start_date, report.title)))
+        .collect();
+
+    Ok(per_period(items, |item| {
+        let (start_date, title) = titles.get(&item.report_id).cloned().unwrap_or_default();
+        ((start_date, item.report_id), title, Some(item.report_id))
+    }))
+}
+
+/// Number and average length of the shifts of each employee.
+pub fn shifts(items: &[InvoiceItem]) -> Vec<EmployeeStatistics> {
+    by_employee(items)
+        .into_iter()
+        .map(|items| EmployeeStatistics {
+            employee_id: items[0].employee_id,
+            name: items[0].name.clone(),
+            total: Total::of(&items),
+            periods: vec![],
+        })
+        .collect()
+}
+
+/// How the work is distributed over the days of the week.
+pub fn per_weekday(items: &[InvoiceItem]) -> Vec<Bucket> {
+    WEEKDAY_NAME
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let items: Vec<_> = items
+                .iter()
+                .filter(|item| item.day.weekday().num_days_from_monday() as usize == i)
+                .collect();
+            Bucket {
+                label: name.to_string(),
+                total: Total::of(&items),
+            }
+        })
+        .collect()
+}
+
+/// How the work is distributed over the types of weeks.
+pub fn per_week_type(items: &[InvoiceItem]) -> Vec<Bucket> {
+    TYPE_OF_WEEK_NAME
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let items: Vec<_> = items
+                .iter()
+                .filter(|item| item.type_of_week as usize == i)
+                .collect();
+            Bucket {
+                label: name.to_string(),
+                total: Total::of(&items),
+            }
+        })
+        .collect()
+}
+
+/// Compute one of the statistics for all items matching the filter.
+///
+/// `kind` is one of `months`, `reports`, `school_years`, `shifts`, `weekdays` and `week_types`.
+pub fn get(conn: &SqliteConnection, kind: &str, filter: &Filter) -> Result<Value> {
+    let items = filter.load(conn)?;
+    let value = match kind {
+        "months" => serde_json::to_value(per_month(&items))?,
+        "reports" => serde_json::to_value(per_report(conn, &items)?)?,
+        "school_years" => serde_json::to_value(per_school_year(&items))?,
+        "shifts" => serde_json::to_value(shifts(&items))?,
+        "weekdays" => serde_json::to_value(per_weekday(&items))?,
+        "week_types" => serde_json::to_value(per_week_type(&items))?,
+        _ => bail!("Unknown statistics: {}", kind),
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test_data::{invoice_item, invoice_items};
+
+    /// Items around the end of a school year.
+    fn summer_items() -> Vec<InvoiceItem> {
+        vec![
+            invoice_item(2, "Jan Berg", "2018-07-30", "12:00", "14:00"),
+            invoice_item(1, "Anna Schmidt", "2018-07-31", "12:00", "13:30"),
+            invoice_item(1, "Anna Schmidt", "2018-08-01", "12:00", "13:00"),
+            invoice_item(1, "Anna Schmidt", "2018-08-02", "12:00", "12:45"),
+        ]
+    }
+
+    #[test]
+    fn test_total() {
+        let items = invoice_items();
+        let total = Total::of(&items.iter().collect::<Vec<_>>());
+        assert_eq!(total.shifts, 4);
+        assert_eq!(total.minutes, 600);
+        assert_eq!(total.hours, 10.0);
+        assert_eq!(total.average_minutes, 150);
+    }
+
+    #[test]
+    fn test_per_period() {
+        let months = per_month(&summer_items());
+        assert_eq!(months.len(), 2);
+        assert_eq!(months[0].name, "Anna Schmidt");
+        assert_eq!(months[0].total.minutes, 195);
+        let periods: Vec<_> = months[0]
+            .periods
+            .iter()
+            .map(|p| (p.period.as_str(), p.total.minutes))
+            .collect();
+        assert_eq!(periods, vec![("2018-07", 90), ("2018-08", 105)]);
+
+        let years = per_school_year(&summer_items());
+        let periods: Vec<_> = years[0]
+            .periods
+            .iter()
+            .map(|p| (p.period.as_str(), p.total.shifts))
+            .collect();
+        assert_eq!(periods, vec![("2017/18", 1), ("2018/19", 2)]);
+        assert_eq!(years[1].periods[0].period, "2017/18");
+    }
+
+    #[test]
+    fn test_shifts() {
+        let shifts = shifts(&invoice_items());
+        assert_eq!(shifts[0].name, "Anna Schmidt");
+        assert_eq!(shifts[0].total.average_minutes, 120);
+        assert_eq!(shifts[1].total.average_minutes, 180);
+        assert!(shifts[0].periods.is_empty());
+    }
+
+    #[test]
+    fn test_distribution() {
+        let weekdays = per_weekday(&invoice_items());
+        assert_eq!(weekdays.len(), 7);
+        // 12 March 2018 was a Monday.
+        assert_eq!(weekdays[0].label, "Montag");
+        assert_eq!(weekdays[0].total.minutes, 210);
+        assert_eq!(weekdays[1].total.minutes, 300);
+        assert_eq!(weekdays[2].total.minutes, 90);
+        assert_eq!(weekdays[5].total, Total::default());
+
+        let week_types = per_week_type(&invoice_items());
+        let minutes: Vec<_> = week_types.iter().map(|b| b.total.minutes).collect();
+        assert_eq!(minutes, vec![390, 210, 0, 0]);
+    }
+}
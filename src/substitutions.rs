@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+
+use diesel::prelude::*;
+use diesel::SqliteConnection;
+
+use errors::*;
+use items::InvoiceItem;
+use names;
+
+/// How often one employee substituted for another one, or the other way round.
+#[derive(Debug, Serialize)]
+pub struct SubstitutionCount {
+    pub employee_id: i32,
+    pub name: String,
+    pub count: i32,
+}
+
+/// All substitutions in a report an employee was involved in.
+#[derive(Debug, Serialize)]
+pub struct EmployeeSubstitutions {
+    pub employee_id: i32,
+    pub name: String,
+
+    /// Whom this employee stood in for.
+    pub substituted_for: Vec<SubstitutionCount>,
+
+    /// Who stood in for this employee.
+    pub substituted_by: Vec<SubstitutionCount>,
+}
+
+impl EmployeeSubstitutions {
+    fn new(employee_id: i32, name: String) -> Self {
+        EmployeeSubstitutions {
+            employee_id,
+            name,
+            substituted_for: vec![],
+            substituted_by: vec![],
+        }
+    }
+}
+
+/// Increment the counter for `employee_id` in `counts`, adding it if necessary.
+fn count(counts: &mut Vec<SubstitutionCount>, employee_id: i32, name: &str) {
+    if let Some(entry) = counts.iter_mut().find(|c| c.employee_id == employee_id) {
+        entry.count += 1;
+        return;
+    }
+    counts.push(SubstitutionCount {
+        employee_id,
+        name: name.to_string(),
+        count: 1,
+    });
+}
+
+/// Summarize who substituted for whom how often in the report with the given id.
+///
+/// Every employee who substituted for someone or was substituted by someone gets one entry, sorted
+/// by name.
+pub fn summary(conn: &SqliteConnection, report_id: i32) -> Result<Vec<EmployeeSubstitutions>> {
+    use schema::{employees, items_view};
+
+    assert!(report_id >= 0);
+
+    let items = items_view::table
+        .filter(items_view::report_id.eq(report_id))
+        .filter(items_view::substitutes_employee_id.is_not_null())
+        .load::<InvoiceItem>(conn)
+        .chain_err(|| format!("Failed to query substitutions in report #{}", report_id))?;
+
+    let mut summary: BTreeMap<i32, EmployeeSubstitutions> = BTreeMap::new();
+
+    for item in items {
+        let (absent_id, absent_name) = match (item.substitutes_employee_id, item.substitutes_name) {
+            (Some(id), Some(name)) => (id, name),
+            _ => continue,
+        };
+        let (present_id, present_name) = (item.employee_id, item.name);
+
+        count(
+            &mut summary
+                .entry(present_id)
+                .or_insert_with(|| EmployeeSubstitutions::new(present_id, present_name.clone()))
+                .substituted_for,
+            absent_id,
+            &absent_name,
+        );
+        count(
+            &mut summary
+                .entry(absent_id)
+                .or_insert_with(|| EmployeeSubstitutions::new(absent_id, absent_name.clone()))
+                .substituted_by,
+            present_id,
+            &present_name,
+        );
+    }
+
+    // `items_view` has no sort key for the substituted employees, so get them all at once.
+    let sort_keys: BTreeMap<i32, String> = employees::table
+        .select((employees::id, employees::name_sort))
+        .filter(employees::id.eq_any(summary.keys().cloned().collect::<Vec<_>>()))
+        .load(conn)
+        .chain_err(|| "Failed to get names of substituting employees")?
+        .into_iter()
+        .collect();
+    let compare = |a: i32, b: i32| names::compare(&sort_keys[&a], &sort_keys[&b]);
+
+    let mut result: Vec<_> = summary.into_iter().map(|(_, entry)| entry).collect();
+    result.sort_by(|a, b| compare(a.employee_id, b.employee_id));
+    for entry in &mut result {
+        entry
+            .substituted_for
+            .sort_by(|a, b| compare(a.employee_id, b.employee_id));
+        entry
+            .substituted_by
+            .sort_by(|a, b| compare(a.employee_id, b.employee_id));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use employees;
+    use test_data;
+
+    fn counts(entries: &[SubstitutionCount]) -> Vec<(&str, i32)> {
+        entries.iter().map(|c| (c.name.as_str(), c.count)).collect()
+    }
+
+    #[test]
+    fn test_summary() {
+        let conn = ::db::test_connection();
+        let report = test_data::report(&conn, "2018-03-01", "2018-03-31");
+        let zoe = employees::insert(&conn, "Zoe Adler").unwrap();
+        let jan = employees::insert(&conn, "Jan Berg").unwrap();
+        let anna = employees::insert(&conn, "Anna Özdemir").unwrap();
+        let paul = employees::insert(&conn, "Paul Peters").unwrap();
+
+        test_data::item(&conn, report, jan, "2018-03-05", (12, 14), Some(anna));
+        test_data::item(&conn, report, jan, "2018-03-06", (12, 14), Some(anna));
+        test_data::item(&conn, report, jan, "2018-03-07", (12, 14), Some(zoe));
+        test_data::item(&conn, report, zoe, "2018-03-08", (12, 14), Some(jan));
+        test_data::item(&conn, report, paul, "2018-03-09", (12, 14), Some(anna));
+        test_data::item(&conn, report, anna, "2018-03-12", (12, 14), None);
+
+        let result = summary(&conn, report).unwrap();
+        let order: Vec<_> = result.iter().map(|e| e.name.as_str()).collect();
+        // Sorted by last name, with "Ö" like "O" rather than after all other letters.
+        assert_eq!(
+            order,
+            vec!["Zoe Adler", "Jan Berg", "Anna Özdemir", "Paul Peters"]
+        );
+
+        assert_eq!(counts(&result[0].substituted_for), vec![("Jan Berg", 1)]);
+        assert_eq!(counts(&result[0].substituted_by), vec![("Jan Berg", 1)]);
+        assert_eq!(
+            counts(&result[1].substituted_for),
+            vec![("Zoe Adler", 1), ("Anna Özdemir", 2)]
+        );
+        assert_eq!(counts(&result[1].substituted_by), vec![("Zoe Adler", 1)]);
+        assert!(result[2].substituted_for.is_empty());
+        assert_eq!(
+            counts(&result[2].substituted_by),
+            vec![("Jan Berg", 2), ("Paul Peters", 1)]
+        );
+        assert_eq!(
+            counts(&result[3].substituted_for),
+            vec![("Anna Özdemir", 1)]
+        );
+
+        let other = test_data::report(&conn, "2018-04-01", "2018-04-30");
+        assert!(summary(&conn, other).unwrap().is_empty());
+    }
+}
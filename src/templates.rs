@@ -0,0 +1,320 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use diesel::prelude::*;
+use diesel::{self, SqliteConnection};
+use handlebars::Handlebars;
+
+use errors::*;
+use reports::PerEmployeeReport;
+use schema::{reports, templates};
+use settings;
+
+/// Setting for a directory containing additional templates, one `<name>.tex.hbs` file each.
+pub const TEMPLATE_DIR: &str = "template_dir";
+/// Setting for the template used by reports that do not choose one themselves.
+pub const DEFAULT_TEMPLATE: &str = "default_template";
+
+/// Name of the template compiled into the binary.
+pub const BUILTIN: &str = "standard";
+const BUILTIN_CONTENT: &str = include_str!("template.tex.hbs");
+
+const EXTENSION: &str = ".tex.hbs";
+
+/// A LaTeX template for the PDF report.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Template {
+    pub name: String,
+    pub content: String,
+}
+
+/// Entry in the list of available templates.
+#[derive(Debug, Serialize)]
+pub struct TemplateInfo {
+    pub name: String,
+
+    /// Where the template comes from: `builtin`, `directory` or `database`. If there are
+    /// templates with the same name in several places, the database wins over the directory,
+    /// which wins over the built-in template.
+    pub source: String,
+}
+
+/// Template names end up in file names and URLs, so only allow a safe set of characters.
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(
+            "Invalid template name {:?}: only letters, digits, '-' and '_' are allowed",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// A Handlebars instance set up for rendering LaTeX.
+///
+/// Strict mode turns misspelled field names into errors instead of silently leaving them empty.
+fn registry() -> Handlebars {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(::latex::escape);
+    handlebars.set_strict_mode(true);
+    handlebars
+}
+
+/// All `<name>.tex.hbs` files in the configured template directory, if any.
+fn directory_templates(conn: &SqliteConnection) -> Result<Vec<(String, PathBuf)>> {
+    let dir: String = settings::get(conn, TEMPLATE_DIR, String::new())?;
+    if dir.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut result = vec![];
+    for entry in fs::read_dir(&dir).chain_err(|| format!("Failed to read directory {}", dir))? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.ends_with(EXTENSION) => {
+                name[..name.len() - EXTENSION.len()].to_string()
+            }
+            _ => continue,
+        };
+        if check_name(&name).is_ok() {
+            result.push((name, path));
+        } else {
+            warn!("Ignoring template with invalid name: {:?}", path);
+        }
+    }
+    Ok(result)
+}
+
+/// List all available templates.
+pub fn list(conn: &SqliteConnection) -> Result<Vec<TemplateInfo>> {
+    let mut sources = BTreeMap::new();
+    sources.insert(BUILTIN.to_string(), "builtin");
+    for (name, _) in directory_templates(conn)? {
+        sources.insert(name, "directory");
+    }
+    let stored = templates::table
+        .select(templates::name)
+        .load::<String>(conn)
+        .chain_err(|| "Failed to read table templates")?;
+    for name in stored {
+        sources.insert(name, "database");
+    }
+
+    Ok(sources
+        .into_iter()
+        .map(|(name, source)| TemplateInfo {
+            name,
+            source: source.to_string(),
+        })
+        .collect())
+}
+
+/// Get the template with the given name from wherever it is stored.
+pub fn get(conn: &SqliteConnection, name: &str) -> Result<Template> {
+    let stored = templates::table
+        .select(templates::content)
+        .filter(templates::name.eq(name))
+        .first::<String>(conn)
+        .optional()
+        .chain_err(|| format!("Failed to read template {}", name))?;
+
+    let content = match stored {
+        Some(content) => content,
+        None => match directory_templates(conn)?
+            .into_iter()
+            .find(|&(ref file_name, _)| file_name == name)
+        {
+            Some((_, path)) => fs::read_to_string(&path)
+                .chain_err(|| format!("Failed to read template {:?}", path))?,
+            None if name == BUILTIN => BUILTIN_CONTENT.to_string(),
+            None => bail!("No template called {}", name),
+        },
+    };
+
+    Ok(Template {
+        name: name.to_string(),
+        content,
+    })
+}
+
+/// Make sure a template compiles and only uses fields that actually exist.
+pub fn validate(content: &str) -> Result<()> {
+    let mut handlebars = registry();
+    handlebars
+        .register_template_string("validate", content)
+        .chain_err(|| "Invalid template")?;
+    handlebars
+        .render("validate", &PerEmployeeReport::example())
+        .map(|_| ())
+        .chain_err(|| "Invalid template")
+}
+
+/// Store a template in the database, after validating it.
+///
+/// A template with the same name as one in the template directory or the built-in one takes
+/// precedence over those.
+pub fn save(conn: &SqliteConnection, template: &Template) -> Result<()> {
+    check_name(&template.name)?;
+    validate(&template.content)?;
+
+    diesel::replace_into(templates::table)
+        .values((
+            templates::name.eq(&template.name),
+            templates::content.eq(&template.content),
+        ))
+        .execute(conn)
+        .map(|_| ())
+        .chain_err(|| format!("Failed to save template {}", template.name))
+}
+
+/// Make sure there is a template called `name`, e.g. before a report refers to it.
+pub fn check_exists(conn: &SqliteConnection, name: &str) -> Result<()> {
+    if !list(conn)?.iter().any(|template| template.name == name) {
+        bail!("No template called {}", name);
+    }
+    Ok(())
+}
+
+/// Remove a template from the database.
+///
+/// This is refused while reports or the `DEFAULT_TEMPLATE` setting use the template, unless a
+/// template of the same name in the template directory or the built-in one takes its place.
+pub fn delete(conn: &SqliteConnection, name: &str) -> Result<()> {
+    let replaced = name == BUILTIN
+        || directory_templates(conn)?
+            .iter()
+            .any(|&(ref file_name, _)| file_name == name);
+    if !replaced {
+        let users = reports::table
+            .select(reports::title)
+            .filter(reports::template.eq(name))
+            .load::<String>(conn)
+            .chain_err(|| format!("Failed to find reports using template {}", name))?;
+        if !users.is_empty() {
+            bail!(
+                "Template {} is still used by these reports: {}",
+                name,
+                users.join(", ")
+            );
+        }
+        let default: String = settings::get(conn, DEFAULT_TEMPLATE, BUILTIN.to_string())?;
+        if default == name {
+            bail!("Template {} is the default template", name);
+        }
+    }
+
+    diesel::delete(templates::table.filter(templates::name.eq(name)))
+        .execute(conn)
+        .map(|_| ())
+        .chain_err(|| format!("Failed to delete template {}", name))
+}
+
+/// The template called `name`, or the default template if `name` is `None`.
+pub fn resolve(conn: &SqliteConnection, name: Option<&str>) -> Result<Template> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => settings::get(conn, DEFAULT_TEMPLATE, BUILTIN.to_string())?,
+    };
+    get(conn, &name)
+}
+
+/// Render a report with a template.
+pub fn render_template(template: &Template, report: &PerEmployeeReport) -> Result<String> {
+    let name = &template.name;
+    let mut handlebars = registry();
+    handlebars
+        .register_template_string(name, &template.content)
+        .chain_err(|| format!("Invalid template {}", name))?;
+    handlebars
+        .render(name, report)
+        .chain_err(|| format!("Failed to render template {}", name))
+}
+
+/// Render a report with the template called `name`, or the default template if `name` is `None`.
+pub fn render(
+    conn: &SqliteConnection,
+    name: Option<&str>,
+    report: &PerEmployeeReport,
+) -> Result<String> {
+    render_template(&resolve(conn, name)?, report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builtin_template_is_valid() {
+        validate(BUILTIN_CONTENT).unwrap();
+    }
+
+    /// The output for `PerEmployeeReport::example()` as rendered by Handlebars 0.32, to notice
+    /// when an upgrade changes how the built-in template is rendered.
+    #[test]
+    fn test_builtin_template_output() {
+        let template = Template {
+            name: BUILTIN.to_string(),
+            content: BUILTIN_CONTENT.to_string(),
+        };
+        assert_eq!(
+            render_template(&template, &PerEmployeeReport::example()).unwrap(),
+            include_str!("template.example.tex")
+        );
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        assert!(validate("{{title}}").is_ok());
+        assert!(validate("{{titel}}").is_err());
+        assert!(validate("{{#each employees}}{{nmae}}{{/each}}").is_err());
+        assert!(validate("{{#each employees}").is_err());
+    }
+
+    fn template(name: &str) -> Template {
+        Template {
+            name: name.to_string(),
+            content: "{{title}}".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_delete() {
+        let conn = ::db::test_connection();
+        save(&conn, &template("schmal")).unwrap();
+        save(&conn, &template("breit")).unwrap();
+        save(&conn, &template(BUILTIN)).unwrap();
+        check_exists(&conn, "schmal").unwrap();
+        assert!(check_exists(&conn, "unbekannt").is_err());
+
+        let id = ::test_data::report(&conn, "2018-03-01", "2018-03-31");
+        diesel::update(reports::table.find(id))
+            .set(reports::template.eq("schmal"))
+            .execute(&conn)
+            .unwrap();
+        settings::set(&conn, DEFAULT_TEMPLATE, "breit").unwrap();
+
+        assert!(delete(&conn, "schmal").is_err());
+        assert!(delete(&conn, "breit").is_err());
+        // Reports using the built-in template fall back to it.
+        delete(&conn, BUILTIN).unwrap();
+        assert_eq!(get(&conn, BUILTIN).unwrap().content, BUILTIN_CONTENT);
+
+        settings::reset(&conn, DEFAULT_TEMPLATE).unwrap();
+        delete(&conn, "breit").unwrap();
+        assert!(check_exists(&conn, "breit").is_err());
+    }
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name("standard").is_ok());
+        assert!(check_name("Land_NRW-2018").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("../etc/passwd").is_err());
+        assert!(check_name("mit Leerzeichen").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! Rows for tests that need data in a database from `db::test_connection`.
+
+use chrono::{Datelike, NaiveDate, NaiveTime};
+use diesel::prelude::*;
+use diesel::{self, SqliteConnection};
+
+use items::{InvoiceItem, NewRow};
+use reports::{self, NewReport};
+use schema::{items, weeks};
+use DATE_FORMAT;
+use TIME_FORMAT;
+
+/// Add a report covering the period from `start` to `end`, both given as `YYYY-MM-DD`.
+pub fn report(conn: &SqliteConnection, start: &str, end: &str) -> i32 {
+    let report = NewReport {
+        title: format!("{} bis {}", start, end),
+        start_date: start.to_string(),
+        end_date: end.to_string(),
+        show_absences: false,
+        template: None,
+    };
+    reports::add(conn, &report).unwrap()
+}
+
+/// Add an item from `start` to `end` o'clock on `day`.
+///
+/// The week of the day is added as well, since `items_view` leaves out items in unknown weeks.
+pub fn item(
+    conn: &SqliteConnection,
+    report_id: i32,
+    employee_id: i32,
+    day: &str,
+    (start, end): (u32, u32),
+    substitutes_employee_id: Option<i32>,
+) -> i32 {
+    let day = NaiveDate::parse_from_str(day, DATE_FORMAT).unwrap();
+
+    // `items_view` uses SQLite's `%W`, which starts counting weeks at the first Monday.
+    let week: i32 = day.format("%W").to_string().parse().unwrap();
+    diesel::replace_into(weeks::table)
+        .values((
+            weeks::year.eq(day.year()),
+            weeks::week_of_year.eq(week),
+            weeks::type_of_week.eq(week % 2),
+        ))
+        .execute(conn)
+        .unwrap();
+
+    diesel::insert_into(items::table)
+        .values((
+            items::employee_id.eq(employee_id),
+            items::report_id.eq(report_id),
+            items::start_datetime.eq(day.and_time(NaiveTime::from_hms(start, 0, 0)).to_string()),
+            items::end_datetime.eq(day.and_time(NaiveTime::from_hms(end, 0, 0)).to_string()),
+            items::remark.eq(""),
+            items::substitutes_employee_id.eq(substitutes_employee_id),
+        ))
+        .execute(conn)
+        .unwrap();
+    items::table
+        .select(items::id)
+        .order(items::id.desc())
+        .first(conn)
+        .unwrap()
+}
+
+/// A row as entered in the frontend for `name` on 2018-03-05, from `start` to `end` as `HH:MM`.
+pub fn new_row(name: &str, start: &str, end: &str) -> NewRow {
+    NewRow {
+        id: None,
+        employee_id: None,
+        name: name.to_string(),
+        day: "2018-03-05".to_string(),
+        type_of_week: 0,
+        start_time: start.to_string(),
+        end_time: end.to_string(),
+        remark: String::new(),
+        substitutes_employee_id: None,
+    }
+}
+
+/// An item as loaded from `items_view`, for tests that do not need a database.
+///
+/// The report is numbered after the month, and A and B weeks alternate as in the real rota.
+pub fn invoice_item(
+    employee_id: i32,
+    name: &str,
+    day: &str,
+    start: &str,
+    end: &str,
+) -> InvoiceItem {
+    let day = NaiveDate::parse_from_str(day, DATE_FORMAT).unwrap();
+    InvoiceItem {
+        id: 0,
+        employee_id,
+        report_id: day.month() as i32,
+        name: name.to_string(),
+        name_sort: name.to_string(),
+        day,
+        type_of_week: (day.iso_week().week() % 2) as i32,
+        start: NaiveTime::parse_from_str(start, TIME_FORMAT).unwrap(),
+        end: NaiveTime::parse_from_str(end, TIME_FORMAT).unwrap(),
+        remark: String::new(),
+        substitutes_employee_id: None,
+        substitutes_name: None,
+    }
+}
+
+/// A few items of two employees in March 2018, spread over an A and a B week.
+pub fn invoice_items() -> Vec<InvoiceItem> {
+    vec![
+        invoice_item(2, "Jan Berg", "2018-03-06", "13:00", "15:30"),
+        invoice_item(1, "Anna Schmidt", "2018-03-06", "12:00", "14:30"),
+        invoice_item(1, "Anna Schmidt", "2018-03-07", "12:00", "13:30"),
+        invoice_item(2, "Jan Berg", "2018-03-12", "12:00", "15:30"),
+    ]
+}
@@ -15,6 +15,17 @@ lazy_static! {
 /// Names for the different types of weeks.
 pub const TYPE_OF_WEEK_NAME: [&str; 4] = ["A", "B", "C", "D"];
 
+/// German names of the days of the week, starting on Monday.
+pub const WEEKDAY_NAME: [&str; 7] = [
+    "Montag",
+    "Dienstag",
+    "Mittwoch",
+    "Donnerstag",
+    "Freitag",
+    "Samstag",
+    "Sonntag",
+];
+
 /// Map (year, week-of-year) to type-of-week.
 struct TypeOfWeek {
     map: HashMap<(i32, i32), i32>,
@@ -35,15 +46,19 @@ impl TypeOfWeek {
 
     /// What type is the week a day is in?
     pub fn get(&self, day: NaiveDate) -> i32 {
-        let year = day.year();
-        let week_of_year = day.iso_week().week() as i32;
-        if self.map.contains_key(&(year, week_of_year)) {
-            self.map[&(year, week_of_year)]
-        } else {
+        match self.lookup(day) {
+            Some(type_of_week) => type_of_week,
             // TODO Populate table
-            unimplemented!()
+            None => unimplemented!(),
         }
     }
+
+    /// The type of the week a day is in, if the week is in the table.
+    fn lookup(&self, day: NaiveDate) -> Option<i32> {
+        let year = day.year();
+        let week_of_year = day.iso_week().week() as i32;
+        self.map.get(&(year, week_of_year)).cloned()
+    }
 }
 
 /// What is the type of the week a given day belongs to?
@@ -51,6 +66,16 @@ pub fn get_type_of_week(day: NaiveDate) -> i32 {
     TYPE_OF_WEEK.lock().unwrap().get(day)
 }
 
+/// Read the `weeks` table again, e.g. after importing a dump.
+pub fn reload(conn: &SqliteConnection) {
+    *TYPE_OF_WEEK.lock().unwrap() = TypeOfWeek::new(conn);
+}
+
+/// The type of the week a day belongs to, or `None` for weeks that have not been computed yet.
+pub fn find_type_of_week(day: NaiveDate) -> Option<i32> {
+    TYPE_OF_WEEK.lock().unwrap().lookup(day)
+}
+
 /// Structure for inserting data into the `weeks` table.
 #[derive(Debug, Serialize, Insertable)]
 #[table_name = "weeks"]